@@ -0,0 +1,78 @@
+//! Cost of a single key derivation for a range of iteration counts.
+//!
+//! Run with `cargo bench`. Use `Pbkdf2::calibrate` to pick a count for a
+//! target time instead of reading it off this table.
+
+use std::time::{Duration, Instant};
+
+use p12::{pkcs12_kdf, Pbkdf2};
+use sha1::Sha1;
+use sha2::Sha256;
+
+const ITERATIONS: [u32; 4] = [1_000, 10_000, 100_000, 600_000];
+const SALT: [u8; 16] = [0x5a; 16];
+// "pw" as a BMPString with the terminating zero
+const BMP_PASSWORD: [u8; 6] = [0, b'p', 0, b'w', 0, 0];
+
+fn pkcs12_sha1(iterations: u32) {
+    pkcs12_kdf::<Sha1>(&BMP_PASSWORD, &SALT, iterations as u64, 1, 24).unwrap();
+}
+
+fn pkcs12_sha256(iterations: u32) {
+    pkcs12_kdf::<Sha256>(&BMP_PASSWORD, &SALT, iterations as u64, 1, 32).unwrap();
+}
+
+fn pbkdf2_sha1(iterations: u32) {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha1>(b"pw", &SALT, iterations, &mut key);
+}
+
+fn pbkdf2_sha256(iterations: u32) {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(b"pw", &SALT, iterations, &mut key);
+}
+
+type Kdf = fn(u32);
+
+const KDFS: [(&str, Kdf); 4] = [
+    ("pkcs12 sha1", pkcs12_sha1),
+    ("pkcs12 sha256", pkcs12_sha256),
+    ("pbkdf2-hmac-sha1", pbkdf2_sha1),
+    ("pbkdf2-hmac-sha256", pbkdf2_sha256),
+];
+
+const SAMPLES: usize = 11;
+
+// fastest and median of a few runs after a warm-up one
+fn time(kdf: Kdf, iterations: u32) -> (Duration, Duration) {
+    kdf(iterations);
+    let mut samples: Vec<Duration> = (0..SAMPLES)
+        .map(|_| {
+            let start = Instant::now();
+            kdf(iterations);
+            start.elapsed()
+        })
+        .collect();
+    samples.sort();
+    (samples[0], samples[SAMPLES / 2])
+}
+
+fn main() {
+    println!(
+        "{:<24}{:>12}{:>14}{:>14}",
+        "kdf", "iterations", "min", "median"
+    );
+    for iterations in ITERATIONS {
+        for (name, kdf) in KDFS {
+            let (min, median) = time(kdf, iterations);
+            let (min, median) = (format!("{:?}", min), format!("{:?}", median));
+            println!("{:<24}{:>12}{:>14}{:>14}", name, iterations, min, median);
+        }
+    }
+    let target = Duration::from_millis(100);
+    println!(
+        "pbkdf2-hmac-sha256 iterations for {:?}: {}",
+        target,
+        Pbkdf2::calibrate(target)
+    );
+}
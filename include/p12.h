@@ -0,0 +1,82 @@
+/*
+ * C API of the p12 crate, built with the `capi` feature.
+ *
+ * Functions return P12_OK or one of the P12_ERR_* codes and hand out results
+ * through pointer arguments. A parsed PFX is an opaque P12Handle released
+ * with p12_free, byte buffers are released with p12_bytes_free. Passwords
+ * are NUL terminated UTF-8 strings.
+ */
+#ifndef P12_H
+#define P12_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define P12_OK 0
+#define P12_ERR_NULL_POINTER 1
+#define P12_ERR_INVALID_UTF8 2
+/* no key, or no certificate at the index */
+#define P12_ERR_NOT_FOUND 3
+#define P12_ERR_ASN1 4
+#define P12_ERR_UNSUPPORTED_AUTH_SAFE 5
+#define P12_ERR_LIMIT_EXCEEDED 6
+#define P12_ERR_TRAILING_DATA 7
+#define P12_ERR_UNSUPPORTED_VERSION 8
+#define P12_ERR_INVALID_MAC 9
+#define P12_ERR_MISSING_IDENTITY 10
+#define P12_ERR_DECRYPTION_FAILED 11
+#define P12_ERR_INVALID_KDF_PARAMS 12
+#define P12_ERR_NOT_IA5_STRING 13
+#define P12_ERR_ENCRYPTION_FAILED 14
+#define P12_ERR_UNSUPPORTED_ALGORITHM 15
+#define P12_ERR_UNSUPPORTED_SALT_SOURCE 16
+#define P12_ERR_NESTED_ENCRYPTION 17
+#define P12_ERR_UNSUPPORTED_MAC_ALGORITHM 18
+/* the file has no MacData to verify */
+#define P12_ERR_NO_MAC 19
+/* a bug in the library, the panic was caught at the C boundary */
+#define P12_ERR_PANIC 20
+
+typedef struct P12Handle P12Handle;
+
+/* Parses len bytes of DER at der into a handle written to out. */
+int p12_parse(const uint8_t *der, size_t len, P12Handle **out);
+
+/* P12_OK if the MAC verifies, P12_ERR_INVALID_MAC if it doesn't and
+ * P12_ERR_NO_MAC if the file has none. */
+int p12_verify_mac(const P12Handle *handle, const char *password);
+
+/* Decrypts the first private key, a PKCS#8 PrivateKeyInfo, into a buffer of
+ * out_len bytes at out. */
+int p12_get_key(const P12Handle *handle, const char *password,
+                uint8_t **out, size_t *out_len);
+
+/* Decrypts the X.509 certificate at index into a buffer of out_len bytes at
+ * out, P12_ERR_NOT_FOUND past the last one. */
+int p12_get_certs(const P12Handle *handle, const char *password, size_t index,
+                  uint8_t **out, size_t *out_len);
+
+/* Builds the DER of a PFX holding a certificate, its PKCS#8 key and an
+ * optional CA certificate (ca may be NULL), encrypted with AES-256-CBC and
+ * PBKDF2. */
+int p12_build(const uint8_t *cert, size_t cert_len,
+              const uint8_t *key, size_t key_len,
+              const uint8_t *ca, size_t ca_len,
+              const char *password, const char *name,
+              uint8_t **out, size_t *out_len);
+
+/* Releases a handle from p12_parse, NULL is ignored. */
+void p12_free(P12Handle *handle);
+
+/* Releases a buffer handed out by this API, NULL is ignored. */
+void p12_bytes_free(uint8_t *data, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* P12_H */
@@ -1,22 +1,46 @@
 //!
 //! pure rust pkcs12 tool
 //!
+//! # WebAssembly
 //!
+//! Only `PFX::from_file` and `write_to_file`, behind the default `std`
+//! feature, touch the file system, `PFX::parse`, `bags` and `verify_mac`
+//! work on byte slices. Building a PFX needs random salts and IVs from
+//! [getrandom](https://docs.rs/getrandom/0.2), which has no source of its
+//! own on `wasm32-unknown-unknown` and fails to compile there unless one is
+//! chosen. Either enable its `js` feature in your Cargo.toml for a browser:
+//!
+//! ```toml
+//! getrandom = { version = "0.2", features = ["js"] }
+//! ```
+//!
+//! or enable this crate's `custom-rng` feature and register your own source
+//! with `getrandom::register_custom_getrandom!`. `Pbkdf2::calibrate` needs a
+//! clock and isn't available on that target.
+//!
+
+#[cfg(any(feature = "std", test))]
+use std::fs;
+use std::io;
+use std::ops::ControlFlow;
+#[cfg(feature = "std")]
+use std::path::Path;
 
 use cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
 use getrandom::getrandom;
 use lazy_static::lazy_static;
 use yasna::{
-    models::ObjectIdentifier, tags::TAG_OCTETSTRING, ASN1Error, ASN1ErrorKind, BERReader,
-    DERWriter, Tag,
+    models::ObjectIdentifier, tags::TAG_INTEGER, tags::TAG_OCTETSTRING, tags::TAG_UTF8STRING,
+    ASN1Error, ASN1ErrorKind, BERReader, DERWriter, Tag,
 };
 
-use hmac::{Hmac, Mac};
+use hmac::{Mac, SimpleHmac};
 use sha1::{Digest, Sha1};
-use sha2::Sha256;
+use sha2::{
+    digest::{core_api::BlockSizeUser, Output},
+    Sha224, Sha256, Sha512, Sha512_256,
+};
 
-type HmacSha1 = Hmac<Sha1>;
-type HmacSha256 = Hmac<Sha256>;
 type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
 type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
 
@@ -26,6 +50,8 @@ fn as_oid(s: &'static [u64]) -> ObjectIdentifier {
 
 lazy_static! {
     static ref OID_DATA_CONTENT_TYPE: ObjectIdentifier = as_oid(&[1, 2, 840, 113_549, 1, 7, 1]);
+    static ref OID_SIGNED_DATA_CONTENT_TYPE: ObjectIdentifier =
+        as_oid(&[1, 2, 840, 113_549, 1, 7, 2]);
     static ref OID_ENCRYPTED_DATA_CONTENT_TYPE: ObjectIdentifier =
         as_oid(&[1, 2, 840, 113_549, 1, 7, 6]);
     static ref OID_FRIENDLY_NAME: ObjectIdentifier = as_oid(&[1, 2, 840, 113_549, 1, 9, 20]);
@@ -36,26 +62,48 @@ lazy_static! {
         as_oid(&[1, 2, 840, 113_549, 1, 9, 22, 2]);
     static ref OID_PBE_WITH_SHA_AND3_KEY_TRIPLE_DESCBC: ObjectIdentifier =
         as_oid(&[1, 2, 840, 113_549, 1, 12, 1, 3]);
+    static ref OID_PBE_WITH_SHA1_AND128_BIT_RC4: ObjectIdentifier =
+        as_oid(&[1, 2, 840, 113_549, 1, 12, 1, 1]);
+    static ref OID_PBE_WITH_SHA1_AND40_BIT_RC4: ObjectIdentifier =
+        as_oid(&[1, 2, 840, 113_549, 1, 12, 1, 2]);
+    static ref OID_PBE_WITH_MD5_AND_DES_CBC: ObjectIdentifier =
+        as_oid(&[1, 2, 840, 113_549, 1, 5, 3]);
+    static ref OID_PBE_WITH_SHA1_AND_DES_CBC: ObjectIdentifier =
+        as_oid(&[1, 2, 840, 113_549, 1, 5, 10]);
     static ref OID_SHA1: ObjectIdentifier = as_oid(&[1, 3, 14, 3, 2, 26]);
     static ref OID_HMAC_WITH_SHA1: ObjectIdentifier = as_oid(&[1, 2, 840, 113549, 2]);
     static ref OID_HMAC_WITH_SHA256: ObjectIdentifier = as_oid(&[1, 2, 840, 113549, 2, 9]);
+    static ref OID_HMAC_WITH_SHA512: ObjectIdentifier = as_oid(&[1, 2, 840, 113549, 2, 11]);
     static ref OID_PBES2: ObjectIdentifier = as_oid(&[1, 2, 840, 113549, 1, 5, 13]);
     static ref OID_PBKDF2: ObjectIdentifier = as_oid(&[1, 2, 840, 113549, 1, 5, 12]);
     static ref OID_SHA2: ObjectIdentifier = as_oid(&[2, 16, 840, 1, 101, 3, 4, 2, 1]);
+    static ref OID_SHA224: ObjectIdentifier = as_oid(&[2, 16, 840, 1, 101, 3, 4, 2, 4]);
+    static ref OID_SHA512_256: ObjectIdentifier = as_oid(&[2, 16, 840, 1, 101, 3, 4, 2, 6]);
     static ref OID_PBE_WITH_SHA1_AND40_BIT_RC2_CBC: ObjectIdentifier =
         as_oid(&[1, 2, 840, 113_549, 1, 12, 1, 6]);
+    static ref OID_RSA_ENCRYPTION: ObjectIdentifier = as_oid(&[1, 2, 840, 113_549, 1, 1, 1]);
     static ref OID_KEY_BAG: ObjectIdentifier = as_oid(&[1, 2, 840, 113_549, 1, 12, 10, 1, 1]);
+    static ref OID_RC2_CBC: ObjectIdentifier = as_oid(&[1, 2, 840, 113_549, 3, 2]);
+    static ref OID_DES_EDE3_CBC: ObjectIdentifier = as_oid(&[1, 2, 840, 113_549, 3, 7]);
     static ref OID_AES_CBC_PAD: ObjectIdentifier = as_oid(&[2, 16, 840, 1, 101, 3, 4, 1, 42]);
+    static ref OID_AES128_CBC_PAD: ObjectIdentifier = as_oid(&[2, 16, 840, 1, 101, 3, 4, 1, 2]);
+    static ref OID_AES192_CBC_PAD: ObjectIdentifier = as_oid(&[2, 16, 840, 1, 101, 3, 4, 1, 22]);
+    static ref OID_AES256_WRAP: ObjectIdentifier = as_oid(&[2, 16, 840, 1, 101, 3, 4, 1, 45]);
     static ref OID_PKCS8_SHROUDED_KEY_BAG: ObjectIdentifier =
         as_oid(&[1, 2, 840, 113_549, 1, 12, 10, 1, 2]);
     static ref OID_CERT_BAG: ObjectIdentifier = as_oid(&[1, 2, 840, 113_549, 1, 12, 10, 1, 3]);
     static ref OID_CRL_BAG: ObjectIdentifier = as_oid(&[1, 2, 840, 113_549, 1, 12, 10, 1, 4]);
+    static ref OID_CRL_TYPE_X509_CRL: ObjectIdentifier = as_oid(&[1, 2, 840, 113_549, 1, 9, 23, 1]);
     static ref OID_SECRET_BAG: ObjectIdentifier = as_oid(&[1, 2, 840, 113_549, 1, 12, 10, 1, 5]);
     static ref OID_SAFE_CONTENTS_BAG: ObjectIdentifier =
         as_oid(&[1, 2, 840, 113_549, 1, 12, 10, 1, 6]);
 }
 
 const ITERATIONS: u64 = 2048;
+// PBKDF2-HMAC-SHA256 iterations OWASP recommends, and the MAC iterations
+// written with them by `PFX::upgrade_to_modern`
+const MODERN_PBKDF2_ITERATIONS: u64 = 600_000;
+const MODERN_MAC_ITERATIONS: u32 = 10_000;
 
 fn sha<D: Digest>(bytes: &[u8]) -> Vec<u8> {
     let mut hasher = D::new();
@@ -63,7 +111,129 @@ fn sha<D: Digest>(bytes: &[u8]) -> Vec<u8> {
     hasher.finalize().to_vec()
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
+pub enum Pkcs12Error {
+    Asn1(ASN1Error),
+    // the auth_safe is neither data nor encrypted data, e.g. signed data
+    UnsupportedAuthSafe(ObjectIdentifier),
+    // the input exceeds the given `Limits`
+    LimitExceeded,
+    // the number of bytes after the PFX
+    TrailingData(usize),
+    // a PFX version other than 0 to 3
+    UnsupportedVersion(u32),
+    // the MAC doesn't match, usually a wrong password
+    InvalidMac,
+    // no private key, or no certificate for it
+    MissingIdentity,
+    // decrypting a content info failed, `truncated` tells that the ciphertext
+    // isn't a multiple of the cipher block size, otherwise the password is
+    // likely wrong
+    DecryptionFailed { truncated: bool },
+    // key derivation parameters that would give a degenerate key, like zero iterations
+    InvalidKdfParams,
+    // an SDSI certificate must be an IA5 (ASCII) string
+    NotIa5String,
+    // encrypting or MACing the new contents failed
+    EncryptionFailed,
+    // a cipher, KDF or PRF this crate doesn't implement, like GOST or SEED
+    UnsupportedAlgorithm(AlgorithmIdentifier),
+    // a PBKDF2 salt given by an otherSource algorithm rather than specified
+    UnsupportedSaltSource(AlgorithmIdentifier),
+    // an encrypted content info that decrypts to encrypted data more than once
+    NestedEncryption,
+    // a MAC digest other than SHA-1 and SHA-2, e.g. GOST R 34.11-2012 (Streebog)
+    UnsupportedMacAlgorithm(ObjectIdentifier),
+}
+
+impl Pkcs12Error {
+    fn decryption_failed(algorithm: &AlgorithmIdentifier, ciphertext: &[u8]) -> Pkcs12Error {
+        if algorithm.kdf_info().map_or(false, |info| {
+            info.iterations == 0 || info.iterations > u32::MAX.into()
+        }) {
+            return Pkcs12Error::InvalidKdfParams;
+        }
+        if let AlgorithmIdentifier::Pbes2(params) = algorithm {
+            if !params.key_length_matches() {
+                return Pkcs12Error::InvalidKdfParams;
+            }
+        }
+        let len = ciphertext.len();
+        let truncated = algorithm
+            .cipher_block_size()
+            .map_or(false, |size| len == 0 || len % size != 0);
+        Pkcs12Error::DecryptionFailed { truncated }
+    }
+}
+
+impl std::fmt::Display for Pkcs12Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pkcs12Error::Asn1(e) => write!(f, "{e}"),
+            Pkcs12Error::UnsupportedAuthSafe(oid) => {
+                write!(f, "unsupported authenticated safe content type {oid}")
+            }
+            Pkcs12Error::LimitExceeded => write!(f, "input exceeds the parsing limits"),
+            Pkcs12Error::TrailingData(n) => write!(f, "{n} bytes of trailing data"),
+            Pkcs12Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported PFX version {version}")
+            }
+            Pkcs12Error::InvalidMac => write!(f, "MAC verification failed"),
+            Pkcs12Error::MissingIdentity => write!(f, "no private key with a certificate found"),
+            Pkcs12Error::DecryptionFailed { truncated: true } => {
+                write!(f, "decryption failed, the ciphertext is truncated")
+            }
+            Pkcs12Error::DecryptionFailed { truncated: false } => {
+                write!(f, "decryption failed, wrong password?")
+            }
+            Pkcs12Error::InvalidKdfParams => write!(f, "invalid key derivation parameters"),
+            Pkcs12Error::NotIa5String => write!(f, "string is not an IA5 string"),
+            Pkcs12Error::EncryptionFailed => write!(f, "encryption failed"),
+            Pkcs12Error::UnsupportedAlgorithm(alg) => write!(f, "unsupported algorithm {alg}"),
+            Pkcs12Error::UnsupportedSaltSource(alg) => {
+                write!(f, "unsupported PBKDF2 salt source {alg}")
+            }
+            Pkcs12Error::NestedEncryption => {
+                write!(f, "encrypted data nested more than two levels deep")
+            }
+            Pkcs12Error::UnsupportedMacAlgorithm(oid) => {
+                write!(f, "unsupported MAC algorithm {oid}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Pkcs12Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Pkcs12Error::Asn1(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ASN1Error> for Pkcs12Error {
+    fn from(e: ASN1Error) -> Self {
+        Pkcs12Error::Asn1(e)
+    }
+}
+
+#[test]
+fn test_pkcs12_error_boxed() {
+    fn keys(der: &[u8], password: &str) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+        let pfx = PFX::parse(der)?;
+        pfx.verify(password)?;
+        Ok(pfx.key_bags(password)?)
+    }
+    let err = keys(&[0x30, 0x00], "changeit").unwrap_err();
+    assert!(err.downcast_ref::<Pkcs12Error>().is_some());
+
+    let pfx = PfxBuilder::new().build("changeit").unwrap();
+    let err = keys(&pfx.to_der(), "wrong").unwrap_err();
+    assert_eq!(err.to_string(), Pkcs12Error::InvalidMac.to_string());
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct EncryptedContentInfo {
     pub content_encryption_algorithm: AlgorithmIdentifier,
     pub encrypted_content: Vec<u8>,
@@ -85,7 +255,7 @@ impl EncryptedContentInfo {
         })
     }
 
-    pub fn data(&self, password: &[u8]) -> Option<Vec<u8>> {
+    pub fn data(&self, password: &[u8]) -> Result<Vec<u8>, Pkcs12Error> {
         self.content_encryption_algorithm
             .decrypt_pbe(&self.encrypted_content, password)
     }
@@ -107,19 +277,25 @@ impl EncryptedContentInfo {
         safe_bags: &[SafeBag],
         password: &[u8],
     ) -> Option<EncryptedContentInfo> {
-        let data = yasna::construct_der(|w| {
-            w.write_sequence_of(|w| {
-                for sb in safe_bags {
-                    sb.write(w.next());
-                }
-            })
-        });
+        let data = safe_contents_der(safe_bags);
         let encryptor = Encryptor::new();
         encryptor.encrypt::<KDF>(&data, password)
     }
+
+    /// Like `from_safe_bags`, but with a caller-built encryptor and key
+    /// deriver, so a fixed IV and salt give reproducible output.
+    pub fn from_safe_bags_with(
+        encryptor: &impl DataEncryptor,
+        key_deriver: &impl KeyDeriver,
+        safe_bags: &[SafeBag],
+        password: &[u8],
+    ) -> Option<EncryptedContentInfo> {
+        let data = safe_contents_der(safe_bags);
+        encryptor.encrypt_key_deriver(&data, password, key_deriver)
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct EncryptedData {
     pub encrypted_content_info: EncryptedContentInfo,
 }
@@ -127,15 +303,18 @@ pub struct EncryptedData {
 impl EncryptedData {
     pub fn parse(r: BERReader) -> Result<Self, ASN1Error> {
         r.read_sequence(|r| {
+            //version 0 of RFC 2315 10.1
             let version = r.next().read_u8()?;
-            debug_assert_eq!(version, 0);
+            if version != 0 {
+                return Err(ASN1Error::new(ASN1ErrorKind::Invalid));
+            }
             let encrypted_content_info = EncryptedContentInfo::parse(r.next())?;
             Ok(EncryptedData {
                 encrypted_content_info,
             })
         })
     }
-    pub fn data(&self, password: &[u8]) -> Option<Vec<u8>> {
+    pub fn data(&self, password: &[u8]) -> Result<Vec<u8>, Pkcs12Error> {
         self.encrypted_content_info.data(password)
     }
     pub fn write(&self, w: DERWriter) {
@@ -154,15 +333,91 @@ impl EncryptedData {
             encrypted_content_info,
         })
     }
+    pub fn from_safe_bags_with(
+        encryptor: &impl DataEncryptor,
+        key_deriver: &impl KeyDeriver,
+        safe_bags: &[SafeBag],
+        password: &[u8],
+    ) -> Option<Self> {
+        let encrypted_content_info =
+            EncryptedContentInfo::from_safe_bags_with(encryptor, key_deriver, safe_bags, password)?;
+        Some(EncryptedData {
+            encrypted_content_info,
+        })
+    }
 }
 
-#[derive(Debug, Clone)]
+#[test]
+fn test_encrypted_data_version() {
+    let encrypted =
+        EncryptedData::from_safe_bags::<AesCbcDataEncryptor, Pbkdf2>(&[], b"changeit").unwrap();
+    let mut der = yasna::construct_der(|w| encrypted.write(w));
+    assert_eq!(
+        yasna::parse_der(&der, EncryptedData::parse).unwrap(),
+        encrypted
+    );
+    //the INTEGER right after the SEQUENCE header
+    let version = der.iter().position(|&b| b == 0x02).unwrap() + 2;
+    assert_eq!(der[version], 0);
+    der[version] = 1;
+    assert!(yasna::parse_der(&der, EncryptedData::parse).is_err());
+}
+
+// decrypted content that is encrypted data again rather than a SafeContents,
+// either bare or in a ContentInfo
+fn nested_encrypted_data(data: &[u8]) -> Option<EncryptedData> {
+    if let Ok(ContentInfo::EncryptedData(encrypted)) = yasna::parse_ber(data, ContentInfo::parse) {
+        return Some(encrypted);
+    }
+    yasna::parse_ber(data, EncryptedData::parse).ok()
+}
+
+#[test]
+fn test_nested_encrypted_data() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let bags = [SafeBag::cert_x509(&cert)];
+    let wrap = |content: ContentInfo| {
+        let encryptor = AesCbcDataEncryptor::new();
+        let encrypted_content_info = encryptor
+            .encrypt::<Pbkdf2>(&content.to_der(), b"changeit")
+            .unwrap();
+        ContentInfo::EncryptedData(EncryptedData {
+            encrypted_content_info,
+        })
+    };
+    let pfx = |content: ContentInfo| {
+        let contents = content_infos_der(&[content]);
+        PFX {
+            version: 3,
+            mac_data: Some(MacData::new(&contents, b"changeit")),
+            auth_safe: ContentInfo::Data(contents),
+        }
+    };
+    let once = ContentInfo::EncryptedData(
+        EncryptedData::from_safe_bags::<AesCbcDataEncryptor, Pbkdf2>(&bags, b"changeit").unwrap(),
+    );
+    let twice = wrap(once.clone());
+    assert_eq!(
+        pfx(twice.clone()).cert_x509_bags("changeit").unwrap(),
+        vec![cert]
+    );
+    assert_eq!(
+        twice.data(b"changeit").unwrap(),
+        once.data(b"changeit").unwrap()
+    );
+    assert!(matches!(
+        pfx(wrap(twice)).bags("changeit"),
+        Err(Pkcs12Error::NestedEncryption)
+    ));
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OtherContext {
     pub content_type: ObjectIdentifier,
     pub content: Vec<u8>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ContentInfo {
     Data(Vec<u8>),
     EncryptedData(EncryptedData),
@@ -191,12 +446,53 @@ impl ContentInfo {
             }))
         })
     }
-    pub fn data(&self, password: &[u8]) -> Option<Vec<u8>> {
+    pub fn data(&self, password: &[u8]) -> Result<Vec<u8>, Pkcs12Error> {
         match self {
-            ContentInfo::Data(data) => Some(data.to_owned()),
-            ContentInfo::EncryptedData(encrypted) => encrypted.data(password),
-            ContentInfo::OtherContext(_) => None,
+            ContentInfo::Data(data) => Ok(data.to_owned()),
+            ContentInfo::EncryptedData(encrypted) => {
+                let data = encrypted.data(password)?;
+                //some tools encrypt the encrypted data once more, one extra level is unwrapped
+                let Some(inner) = nested_encrypted_data(&data) else {
+                    return Ok(data);
+                };
+                let data = inner.data(password)?;
+                if nested_encrypted_data(&data).is_some() {
+                    return Err(Pkcs12Error::NestedEncryption);
+                }
+                Ok(data)
+            }
+            ContentInfo::OtherContext(other) => {
+                Err(Pkcs12Error::UnsupportedAuthSafe(other.content_type.clone()))
+            }
+        }
+    }
+    /// The encapsulated content of a signedData content info, the DER of the
+    /// authenticated safe in a PFX with public-key integrity.
+    ///
+    /// The signature isn't checked. `None` for other content types, and when
+    /// the SignedData isn't DER or carries no eContent.
+    pub fn signed_data_econtent(&self) -> Option<Vec<u8>> {
+        let ContentInfo::OtherContext(other) = self else {
+            return None;
+        };
+        if other.content_type != *OID_SIGNED_DATA_CONTENT_TYPE {
+            return None;
         }
+        // SignedData ::= SEQUENCE { version, digestAlgorithms, encapContentInfo, .. }
+        yasna::parse_der(&other.content, |r| {
+            r.read_sequence(|r| {
+                r.next().read_u8()?;
+                r.next().read_set_of(|r| r.read_der().map(drop))?;
+                let econtent = r.next().read_sequence(|r| {
+                    r.next().read_oid()?;
+                    r.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_bytes()))
+                })?;
+                // certificates, crls and signerInfos aren't looked at
+                while r.read_optional(|r| r.read_der())?.is_some() {}
+                Ok(econtent)
+            })
+        })
+        .ok()?
     }
     pub fn oid(&self) -> ObjectIdentifier {
         match self {
@@ -261,6 +557,37 @@ impl Pkcs12Pbes2Params {
             self.encryption_scheme.write(w.next());
         })
     }
+    /// False when the PBKDF2 keyLength contradicts the key size of the cipher.
+    pub fn key_length_matches(&self) -> bool {
+        key_length_matches(&self.key_derivation_function, &self.encryption_scheme)
+    }
+}
+
+// key size an encryption scheme needs, `None` when it's variable like for RC2
+fn scheme_key_len(encryption_scheme: &AlgorithmIdentifier) -> Option<u64> {
+    match encryption_scheme {
+        AlgorithmIdentifier::AesCbcPad(_) => Some(32),
+        AlgorithmIdentifier::Aes128CbcPad(_) => Some(16),
+        AlgorithmIdentifier::Aes192CbcPad(_) => Some(24),
+        AlgorithmIdentifier::DesEde3Cbc(_) => Some(24),
+        _ => None,
+    }
+}
+
+fn key_length_matches(
+    key_derivation_function: &AlgorithmIdentifier,
+    encryption_scheme: &AlgorithmIdentifier,
+) -> bool {
+    let AlgorithmIdentifier::Pbkdf2(params) = key_derivation_function else {
+        return true;
+    };
+    match (params.key_length, scheme_key_len(encryption_scheme)) {
+        (Some(key_length), Some(needed)) => key_length == needed,
+        //RC2 keys are 1 to 128 bytes, and a length from the file is never
+        //allocated past that
+        (Some(key_length), None) => (1..=128).contains(&key_length),
+        _ => true,
+    }
 }
 
 impl Pkcs12PbeParams {
@@ -310,10 +637,15 @@ impl Pbkdf2Params {
             if let Some(key_length) = self.key_length {
                 w.next().write_u64(key_length);
             }
-            self.prf.write(w.next());
+            // DER leaves out a DEFAULT value
+            if *self.prf != AlgorithmIdentifier::HmacWithSha1(None) {
+                self.prf.write(w.next());
+            }
         });
     }
 }
+/// Only a specified salt can be derived with, decrypting with an otherSource
+/// salt fails with `Pkcs12Error::UnsupportedSaltSource`.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Pbkdf2Salt {
     Specified(Vec<u8>),
@@ -337,6 +669,47 @@ impl Pbkdf2Salt {
     }
 }
 
+/// RC2-CBC-Parameter from RFC 8018 B.2.3.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rc2CbcParams {
+    pub version: Option<u64>,
+    pub iv: Vec<u8>,
+}
+
+impl Rc2CbcParams {
+    pub fn parse(r: BERReader) -> Result<Self, ASN1Error> {
+        r.read_sequence(|r| {
+            let version = r.read_optional(|r| r.read_u64())?;
+            let iv = r.next().read_bytes()?;
+            if iv.len() != 8 {
+                return Err(ASN1Error::new(ASN1ErrorKind::Invalid));
+            }
+            Ok(Rc2CbcParams { version, iv })
+        })
+    }
+    pub fn write(&self, w: DERWriter) {
+        w.write_sequence(|w| {
+            if let Some(version) = self.version {
+                w.next().write_u64(version);
+            }
+            w.next().write_bytes(&self.iv);
+        })
+    }
+    /// The effective key bits encoded in rc2ParameterVersion.
+    pub fn effective_key_bits(&self) -> Option<usize> {
+        match self.version {
+            None => Some(32),
+            Some(160) => Some(40),
+            Some(52) => Some(56),
+            Some(120) => Some(64),
+            Some(58) => Some(128),
+            //the rc2 crate panics past 1024 bits
+            Some(version) if (256..=1024).contains(&version) => usize::try_from(version).ok(),
+            Some(_) => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OtherAlgorithmIdentifier {
     pub algorithm_type: ObjectIdentifier,
@@ -345,29 +718,80 @@ pub struct OtherAlgorithmIdentifier {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AlgorithmIdentifier {
-    Sha1,
-    Sha2,
+    // the digests keep their parameters as raw DER, a NULL or absent
+    Sha1(Option<Vec<u8>>),
+    Sha2(Option<Vec<u8>>),
+    Sha224(Option<Vec<u8>>),
+    Sha512_256(Option<Vec<u8>>),
     HmacWithSha1(Option<Vec<u8>>),
     HmacWithSha256(Option<Vec<u8>>),
+    HmacWithSha512(Option<Vec<u8>>),
     PbewithSHAAnd40BitRC2CBC(Pkcs12PbeParams),
     PbeWithSHAAnd3KeyTripleDESCBC(Pkcs12PbeParams),
+    // decrypted only with the `legacy-insecure` feature
+    PbeWithSHA1And128BitRC4(Pkcs12PbeParams),
+    PbeWithSHA1And40BitRC4(Pkcs12PbeParams),
+    // PBES1 of RFC 8018 with single DES. SHA-1 is decrypted only with the
+    // `legacy-insecure` feature, MD5 isn't implemented
+    PbeWithMD5AndDESCBC(Pkcs12PbeParams),
+    PbeWithSHA1AndDESCBC(Pkcs12PbeParams),
     Pbes2(Pkcs12Pbes2Params),
     Pbkdf2(Pbkdf2Params),
+    // AES-256-CBC, 32 byte key, and its 16 byte IV
     AesCbcPad(Vec<u8>),
+    // AES-128-CBC, 16 byte key, and its 16 byte IV
+    Aes128CbcPad(Vec<u8>),
+    // AES-192-CBC, 24 byte key, and its 16 byte IV
+    Aes192CbcPad(Vec<u8>),
+    // id-aes256-wrap, recognized but not decrypted as a PBES2 scheme, see
+    // `aes256_key_unwrap`
+    AesKeyWrap,
+    Rc2Cbc(Rc2CbcParams),
+    // the 8 byte IV
+    DesEde3Cbc(Vec<u8>),
     OtherAlg(OtherAlgorithmIdentifier),
 }
 
+// the NULL parameters digest algorithms are written with
+const NULL_PARAMS: [u8; 2] = [0x05, 0x00];
+
+// digest parameters are either NULL or absent (RFC 5754 2)
+fn read_digest_params(r: &mut yasna::BERReaderSeq) -> Result<Option<Vec<u8>>, ASN1Error> {
+    let params = r.read_optional(|r| r.read_der())?;
+    match params {
+        Some(params) if params != NULL_PARAMS => Err(ASN1Error::new(ASN1ErrorKind::Invalid)),
+        _ => Ok(params),
+    }
+}
+
 impl AlgorithmIdentifier {
+    /// Digest algorithms with NULL parameters, as they're usually written.
+    pub fn sha1() -> Self {
+        AlgorithmIdentifier::Sha1(Some(NULL_PARAMS.to_vec()))
+    }
+    pub fn sha256() -> Self {
+        AlgorithmIdentifier::Sha2(Some(NULL_PARAMS.to_vec()))
+    }
+    pub fn sha224() -> Self {
+        AlgorithmIdentifier::Sha224(Some(NULL_PARAMS.to_vec()))
+    }
+    pub fn sha512_256() -> Self {
+        AlgorithmIdentifier::Sha512_256(Some(NULL_PARAMS.to_vec()))
+    }
     pub fn parse(r: BERReader) -> Result<Self, ASN1Error> {
         r.read_sequence(|r| {
             let algorithm_type = r.next().read_oid()?;
             if algorithm_type == *OID_SHA1 {
-                r.read_optional(|r| r.read_null())?;
-                return Ok(AlgorithmIdentifier::Sha1);
+                return Ok(AlgorithmIdentifier::Sha1(read_digest_params(r)?));
             }
             if algorithm_type == *OID_SHA2 {
-                r.read_optional(|r| r.read_null())?;
-                return Ok(AlgorithmIdentifier::Sha2);
+                return Ok(AlgorithmIdentifier::Sha2(read_digest_params(r)?));
+            }
+            if algorithm_type == *OID_SHA224 {
+                return Ok(AlgorithmIdentifier::Sha224(read_digest_params(r)?));
+            }
+            if algorithm_type == *OID_SHA512_256 {
+                return Ok(AlgorithmIdentifier::Sha512_256(read_digest_params(r)?));
             }
             if algorithm_type == *OID_PBE_WITH_SHA1_AND40_BIT_RC2_CBC {
                 let params = Pkcs12PbeParams::parse(r.next())?;
@@ -377,6 +801,22 @@ impl AlgorithmIdentifier {
                 let params = Pkcs12PbeParams::parse(r.next())?;
                 return Ok(AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(params));
             }
+            if algorithm_type == *OID_PBE_WITH_SHA1_AND128_BIT_RC4 {
+                let params = Pkcs12PbeParams::parse(r.next())?;
+                return Ok(AlgorithmIdentifier::PbeWithSHA1And128BitRC4(params));
+            }
+            if algorithm_type == *OID_PBE_WITH_SHA1_AND40_BIT_RC4 {
+                let params = Pkcs12PbeParams::parse(r.next())?;
+                return Ok(AlgorithmIdentifier::PbeWithSHA1And40BitRC4(params));
+            }
+            if algorithm_type == *OID_PBE_WITH_MD5_AND_DES_CBC {
+                let params = Pkcs12PbeParams::parse(r.next())?;
+                return Ok(AlgorithmIdentifier::PbeWithMD5AndDESCBC(params));
+            }
+            if algorithm_type == *OID_PBE_WITH_SHA1_AND_DES_CBC {
+                let params = Pkcs12PbeParams::parse(r.next())?;
+                return Ok(AlgorithmIdentifier::PbeWithSHA1AndDESCBC(params));
+            }
             if algorithm_type == *OID_PBES2 {
                 let params = Pkcs12Pbes2Params::parse(r.next())?;
                 return Ok(AlgorithmIdentifier::Pbes2(params));
@@ -385,6 +825,7 @@ impl AlgorithmIdentifier {
                 let params = Pbkdf2Params::parse(r.next())?;
                 return Ok(AlgorithmIdentifier::Pbkdf2(params));
             }
+            //parameters are kept as raw DER, usually NULL
             if algorithm_type == *OID_HMAC_WITH_SHA1 {
                 let r = r.read_optional(|r| r.read_der())?;
                 return Ok(AlgorithmIdentifier::HmacWithSha1(r));
@@ -393,10 +834,41 @@ impl AlgorithmIdentifier {
                 let r = r.read_optional(|r| r.read_der())?;
                 return Ok(AlgorithmIdentifier::HmacWithSha256(r));
             }
+            if algorithm_type == *OID_HMAC_WITH_SHA512 {
+                let r = r.read_optional(|r| r.read_der())?;
+                return Ok(AlgorithmIdentifier::HmacWithSha512(r));
+            }
             if algorithm_type == *OID_AES_CBC_PAD {
                 let iv = r.next().read_bytes()?;
+                if iv.len() != 16 {
+                    return Err(ASN1Error::new(ASN1ErrorKind::Invalid));
+                }
                 return Ok(AlgorithmIdentifier::AesCbcPad(iv));
             }
+            if algorithm_type == *OID_AES128_CBC_PAD || algorithm_type == *OID_AES192_CBC_PAD {
+                let iv = r.next().read_bytes()?;
+                if iv.len() != 16 {
+                    return Err(ASN1Error::new(ASN1ErrorKind::Invalid));
+                }
+                if algorithm_type == *OID_AES128_CBC_PAD {
+                    return Ok(AlgorithmIdentifier::Aes128CbcPad(iv));
+                }
+                return Ok(AlgorithmIdentifier::Aes192CbcPad(iv));
+            }
+            if algorithm_type == *OID_RC2_CBC {
+                let params = Rc2CbcParams::parse(r.next())?;
+                return Ok(AlgorithmIdentifier::Rc2Cbc(params));
+            }
+            if algorithm_type == *OID_DES_EDE3_CBC {
+                let iv = r.next().read_bytes()?;
+                if iv.len() != 8 {
+                    return Err(ASN1Error::new(ASN1ErrorKind::Invalid));
+                }
+                return Ok(AlgorithmIdentifier::DesEde3Cbc(iv));
+            }
+            if algorithm_type == *OID_AES256_WRAP {
+                return Ok(AlgorithmIdentifier::AesKeyWrap);
+            }
             let params = r.read_optional(|r| r.read_der())?;
             Ok(AlgorithmIdentifier::OtherAlg(OtherAlgorithmIdentifier {
                 algorithm_type,
@@ -404,14 +876,38 @@ impl AlgorithmIdentifier {
             }))
         })
     }
-    pub fn decrypt_pbe(&self, ciphertext: &[u8], password: &[u8]) -> Option<Vec<u8>> {
+    pub fn decrypt_pbe(&self, ciphertext: &[u8], password: &[u8]) -> Result<Vec<u8>, Pkcs12Error> {
+        if let Some(alg) = self.unsupported() {
+            return Err(Pkcs12Error::UnsupportedAlgorithm(alg.clone()));
+        }
+        if let AlgorithmIdentifier::Pbes2(param) = self {
+            if let AlgorithmIdentifier::Pbkdf2(Pbkdf2Params {
+                salt: Pbkdf2Salt::OtherSource(source),
+                ..
+            }) = param.key_derivation_function.as_ref()
+            {
+                return Err(Pkcs12Error::UnsupportedSaltSource(source.as_ref().clone()));
+            }
+        }
+        self.decrypt_pbe_inner(ciphertext, password)
+            .ok_or_else(|| Pkcs12Error::decryption_failed(self, ciphertext))
+    }
+    fn decrypt_pbe_inner(&self, ciphertext: &[u8], password: &[u8]) -> Option<Vec<u8>> {
         match self {
-            AlgorithmIdentifier::Sha1 => None,
-            AlgorithmIdentifier::Sha2 => None,
+            AlgorithmIdentifier::Sha1(_) => None,
+            AlgorithmIdentifier::Sha2(_) => None,
+            AlgorithmIdentifier::Sha224(_) => None,
+            AlgorithmIdentifier::Sha512_256(_) => None,
             AlgorithmIdentifier::HmacWithSha1(_) => None,
             AlgorithmIdentifier::HmacWithSha256(_) => None,
+            AlgorithmIdentifier::HmacWithSha512(_) => None,
             AlgorithmIdentifier::Pbkdf2(_) => None,
             AlgorithmIdentifier::AesCbcPad(_) => None,
+            AlgorithmIdentifier::Aes128CbcPad(_) => None,
+            AlgorithmIdentifier::Aes192CbcPad(_) => None,
+            AlgorithmIdentifier::AesKeyWrap => None,
+            AlgorithmIdentifier::Rc2Cbc(_) => None,
+            AlgorithmIdentifier::DesEde3Cbc(_) => None,
 
             AlgorithmIdentifier::Pbes2(Pkcs12Pbes2Params {
                 key_derivation_function,
@@ -426,36 +922,210 @@ impl AlgorithmIdentifier {
                 let Ok(str) = std::str::from_utf8(password) else {
                     return None;
                 };
-                let password = &bmp_string(str);
-                pbe_with_sha1_and40_bit_rc2_cbc(ciphertext, password, &param.salt, param.iterations)
+                pkcs12_passwords(str).iter().find_map(|password| {
+                    pbe_with_sha1_and40_bit_rc2_cbc(
+                        ciphertext,
+                        password,
+                        &param.salt,
+                        param.iterations,
+                    )
+                })
             }
             AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(param) => {
+                let Ok(str) = std::str::from_utf8(password) else {
+                    return None;
+                };
+                pkcs12_passwords(str).iter().find_map(|password| {
+                    pbe_with_sha_and3_key_triple_des_cbc(
+                        ciphertext,
+                        password,
+                        &param.salt,
+                        param.iterations,
+                    )
+                })
+            }
+            #[cfg(feature = "legacy-insecure")]
+            AlgorithmIdentifier::PbeWithSHA1And128BitRC4(param)
+            | AlgorithmIdentifier::PbeWithSHA1And40BitRC4(param) => {
                 let Ok(str) = std::str::from_utf8(password) else {
                     return None;
                 };
                 let password = &bmp_string(str);
-                pbe_with_sha_and3_key_triple_des_cbc(
-                    ciphertext,
-                    password,
-                    &param.salt,
-                    param.iterations,
-                )
+                let key_len = match self {
+                    AlgorithmIdentifier::PbeWithSHA1And128BitRC4(_) => 16,
+                    _ => 5,
+                };
+                pbe_with_sha1_and_rc4(ciphertext, password, &param.salt, param.iterations, key_len)
+            }
+            #[cfg(not(feature = "legacy-insecure"))]
+            AlgorithmIdentifier::PbeWithSHA1And128BitRC4(_)
+            | AlgorithmIdentifier::PbeWithSHA1And40BitRC4(_) => None,
+            #[cfg(feature = "legacy-insecure")]
+            AlgorithmIdentifier::PbeWithSHA1AndDESCBC(param) => {
+                pbe_with_sha1_and_des_cbc(ciphertext, password, &param.salt, param.iterations)
+            }
+            #[cfg(not(feature = "legacy-insecure"))]
+            AlgorithmIdentifier::PbeWithSHA1AndDESCBC(_) => None,
+            // rejected by `unsupported` before getting here
+            AlgorithmIdentifier::PbeWithMD5AndDESCBC(_) => None,
+            AlgorithmIdentifier::OtherAlg(_) => None,
+        }
+    }
+    // the part of an encryption algorithm that can't be decrypted with, if any
+    fn unsupported(&self) -> Option<&AlgorithmIdentifier> {
+        match self {
+            AlgorithmIdentifier::PbewithSHAAnd40BitRC2CBC(_)
+            | AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(_) => None,
+            #[cfg(feature = "legacy-insecure")]
+            AlgorithmIdentifier::PbeWithSHA1And128BitRC4(_)
+            | AlgorithmIdentifier::PbeWithSHA1And40BitRC4(_)
+            | AlgorithmIdentifier::PbeWithSHA1AndDESCBC(_) => None,
+            // there is no MD5 dependency, PBE-MD5-DES is only parsed and
+            // written back, decrypting it gives UnsupportedAlgorithm
+            AlgorithmIdentifier::PbeWithMD5AndDESCBC(_) => Some(self),
+            AlgorithmIdentifier::Pbes2(param) => {
+                match param.key_derivation_function.as_ref() {
+                    AlgorithmIdentifier::Pbkdf2(kdf) => match kdf.prf.as_ref() {
+                        AlgorithmIdentifier::HmacWithSha1(_)
+                        | AlgorithmIdentifier::HmacWithSha256(_)
+                        | AlgorithmIdentifier::HmacWithSha512(_) => {}
+                        prf => return Some(prf),
+                    },
+                    kdf => return Some(kdf),
+                }
+                match param.encryption_scheme.as_ref() {
+                    AlgorithmIdentifier::AesCbcPad(_)
+                    | AlgorithmIdentifier::Aes128CbcPad(_)
+                    | AlgorithmIdentifier::Aes192CbcPad(_)
+                    | AlgorithmIdentifier::Rc2Cbc(_)
+                    | AlgorithmIdentifier::DesEde3Cbc(_) => None,
+                    scheme => Some(scheme),
+                }
+            }
+            alg => Some(alg),
+        }
+    }
+    /// Block size of the cipher of a PBES1 or PBES2 algorithm that can be
+    /// decrypted, PBE-MD5-DES can't and has none.
+    pub fn cipher_block_size(&self) -> Option<usize> {
+        match self {
+            AlgorithmIdentifier::PbewithSHAAnd40BitRC2CBC(_)
+            | AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(_)
+            | AlgorithmIdentifier::PbeWithSHA1AndDESCBC(_) => Some(8),
+            AlgorithmIdentifier::Pbes2(param) => param.encryption_scheme.cipher_block_size(),
+            AlgorithmIdentifier::AesCbcPad(_)
+            | AlgorithmIdentifier::Aes128CbcPad(_)
+            | AlgorithmIdentifier::Aes192CbcPad(_) => Some(16),
+            AlgorithmIdentifier::AesKeyWrap
+            | AlgorithmIdentifier::Rc2Cbc(_)
+            | AlgorithmIdentifier::DesEde3Cbc(_) => Some(8),
+            _ => None,
+        }
+    }
+    pub fn kdf_info(&self) -> Option<KdfInfo> {
+        match self {
+            AlgorithmIdentifier::PbewithSHAAnd40BitRC2CBC(param)
+            | AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(param)
+            | AlgorithmIdentifier::PbeWithSHA1And128BitRC4(param)
+            | AlgorithmIdentifier::PbeWithSHA1And40BitRC4(param)
+            | AlgorithmIdentifier::PbeWithMD5AndDESCBC(param)
+            | AlgorithmIdentifier::PbeWithSHA1AndDESCBC(param) => Some(KdfInfo {
+                salt: param.salt.clone(),
+                iterations: param.iterations,
+                prf: None,
+            }),
+            AlgorithmIdentifier::Pbes2(param) => param.key_derivation_function.kdf_info(),
+            AlgorithmIdentifier::Pbkdf2(Pbkdf2Params {
+                salt: Pbkdf2Salt::Specified(salt),
+                iteration_count,
+                prf,
+                ..
+            }) => Some(KdfInfo {
+                salt: salt.clone(),
+                iterations: *iteration_count,
+                prf: Some(prf.as_ref().clone()),
+            }),
+            _ => None,
+        }
+    }
+    /// The iteration count of a PKCS#12 PBE or PBES2 scheme, also when the
+    /// salt comes from an otherSource and `kdf_info` gives `None`.
+    pub fn iteration_count(&self) -> Option<u64> {
+        match self {
+            AlgorithmIdentifier::PbewithSHAAnd40BitRC2CBC(param)
+            | AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(param)
+            | AlgorithmIdentifier::PbeWithSHA1And128BitRC4(param)
+            | AlgorithmIdentifier::PbeWithSHA1And40BitRC4(param)
+            | AlgorithmIdentifier::PbeWithMD5AndDESCBC(param)
+            | AlgorithmIdentifier::PbeWithSHA1AndDESCBC(param) => Some(param.iterations),
+            AlgorithmIdentifier::Pbes2(param) => param.key_derivation_function.iteration_count(),
+            AlgorithmIdentifier::Pbkdf2(param) => Some(param.iteration_count),
+            _ => None,
+        }
+    }
+    /// How well the algorithm holds up today, see `Strength` for the policy.
+    pub fn strength(&self) -> Strength {
+        match self {
+            AlgorithmIdentifier::Sha2(_)
+            | AlgorithmIdentifier::Sha224(_)
+            | AlgorithmIdentifier::Sha512_256(_)
+            | AlgorithmIdentifier::HmacWithSha256(_)
+            | AlgorithmIdentifier::HmacWithSha512(_)
+            | AlgorithmIdentifier::AesCbcPad(_)
+            | AlgorithmIdentifier::Aes128CbcPad(_)
+            | AlgorithmIdentifier::Aes192CbcPad(_)
+            | AlgorithmIdentifier::AesKeyWrap => Strength::Secure,
+            AlgorithmIdentifier::HmacWithSha1(_) | AlgorithmIdentifier::DesEde3Cbc(_) => {
+                Strength::Weak
             }
-            AlgorithmIdentifier::OtherAlg(id) => {
-                debug_assert!(false, "{id:?}");
-                None
+            AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(params) => {
+                Strength::Weak.min(Strength::of_iterations(params.iterations))
             }
+            AlgorithmIdentifier::OtherAlg(_)
+            | AlgorithmIdentifier::Sha1(_)
+            | AlgorithmIdentifier::PbewithSHAAnd40BitRC2CBC(_)
+            | AlgorithmIdentifier::PbeWithSHA1And128BitRC4(_)
+            | AlgorithmIdentifier::PbeWithSHA1And40BitRC4(_)
+            | AlgorithmIdentifier::PbeWithMD5AndDESCBC(_)
+            | AlgorithmIdentifier::PbeWithSHA1AndDESCBC(_) => Strength::Broken,
+            AlgorithmIdentifier::Rc2Cbc(params) => match params.effective_key_bits() {
+                Some(bits) if bits >= Strength::MIN_RC2_KEY_BITS => Strength::Weak,
+                _ => Strength::Broken,
+            },
+            AlgorithmIdentifier::Pbkdf2(params) => {
+                Strength::of_iterations(params.iteration_count).min(params.prf.strength())
+            }
+            AlgorithmIdentifier::Pbes2(params) => params
+                .key_derivation_function
+                .strength()
+                .min(params.encryption_scheme.strength()),
         }
     }
     pub fn write(&self, w: DERWriter) {
         w.write_sequence(|w| match self {
-            AlgorithmIdentifier::Sha1 => {
+            AlgorithmIdentifier::Sha1(params) => {
                 w.next().write_oid(&OID_SHA1);
-                w.next().write_null();
+                if let Some(params) = params {
+                    w.next().write_der(params);
+                }
             }
-            AlgorithmIdentifier::Sha2 => {
+            AlgorithmIdentifier::Sha2(params) => {
                 w.next().write_oid(&OID_SHA2);
-                w.next().write_null();
+                if let Some(params) = params {
+                    w.next().write_der(params);
+                }
+            }
+            AlgorithmIdentifier::Sha224(params) => {
+                w.next().write_oid(&OID_SHA224);
+                if let Some(params) = params {
+                    w.next().write_der(params);
+                }
+            }
+            AlgorithmIdentifier::Sha512_256(params) => {
+                w.next().write_oid(&OID_SHA512_256);
+                if let Some(params) = params {
+                    w.next().write_der(params);
+                }
             }
             AlgorithmIdentifier::PbewithSHAAnd40BitRC2CBC(p) => {
                 w.next().write_oid(&OID_PBE_WITH_SHA1_AND40_BIT_RC2_CBC);
@@ -465,6 +1135,22 @@ impl AlgorithmIdentifier {
                 w.next().write_oid(&OID_PBE_WITH_SHA_AND3_KEY_TRIPLE_DESCBC);
                 p.write(w.next());
             }
+            AlgorithmIdentifier::PbeWithSHA1And128BitRC4(p) => {
+                w.next().write_oid(&OID_PBE_WITH_SHA1_AND128_BIT_RC4);
+                p.write(w.next());
+            }
+            AlgorithmIdentifier::PbeWithSHA1And40BitRC4(p) => {
+                w.next().write_oid(&OID_PBE_WITH_SHA1_AND40_BIT_RC4);
+                p.write(w.next());
+            }
+            AlgorithmIdentifier::PbeWithMD5AndDESCBC(p) => {
+                w.next().write_oid(&OID_PBE_WITH_MD5_AND_DES_CBC);
+                p.write(w.next());
+            }
+            AlgorithmIdentifier::PbeWithSHA1AndDESCBC(p) => {
+                w.next().write_oid(&OID_PBE_WITH_SHA1_AND_DES_CBC);
+                p.write(w.next());
+            }
             AlgorithmIdentifier::Pbes2(p) => {
                 w.next().write_oid(&OID_PBES2);
                 p.write(w.next());
@@ -479,16 +1165,41 @@ impl AlgorithmIdentifier {
                 w.next().write_oid(&OID_AES_CBC_PAD);
                 w.next().write_bytes(iv);
             }
+            AlgorithmIdentifier::Aes128CbcPad(iv) => {
+                w.next().write_oid(&OID_AES128_CBC_PAD);
+                w.next().write_bytes(iv);
+            }
+            AlgorithmIdentifier::Aes192CbcPad(iv) => {
+                w.next().write_oid(&OID_AES192_CBC_PAD);
+                w.next().write_bytes(iv);
+            }
+            AlgorithmIdentifier::AesKeyWrap => {
+                w.next().write_oid(&OID_AES256_WRAP);
+            }
+            AlgorithmIdentifier::Rc2Cbc(p) => {
+                w.next().write_oid(&OID_RC2_CBC);
+                p.write(w.next());
+            }
+            AlgorithmIdentifier::DesEde3Cbc(iv) => {
+                w.next().write_oid(&OID_DES_EDE3_CBC);
+                w.next().write_bytes(iv);
+            }
             AlgorithmIdentifier::HmacWithSha1(r) => {
                 w.next().write_oid(&OID_HMAC_WITH_SHA1);
                 if let Some(r) = r {
-                    w.next().write_bytes(r);
+                    w.next().write_der(r);
                 }
             }
             AlgorithmIdentifier::HmacWithSha256(r) => {
                 w.next().write_oid(&OID_HMAC_WITH_SHA256);
                 if let Some(r) = r {
-                    w.next().write_bytes(r);
+                    w.next().write_der(r);
+                }
+            }
+            AlgorithmIdentifier::HmacWithSha512(r) => {
+                w.next().write_oid(&OID_HMAC_WITH_SHA512);
+                if let Some(r) = r {
+                    w.next().write_der(r);
                 }
             }
             AlgorithmIdentifier::Pbkdf2(pbkdf2_params) => {
@@ -499,46 +1210,524 @@ impl AlgorithmIdentifier {
     }
 }
 
-fn pbes2_decrypt(
-    key_derivation_function: &AlgorithmIdentifier,
-    encryption_scheme: &AlgorithmIdentifier,
-    cipher_text: &[u8],
-    password: &[u8],
-) -> Option<Vec<u8>> {
-    let AlgorithmIdentifier::Pbkdf2(params) = key_derivation_function else {
-        return None;
-    };
-    let Pbkdf2Salt::Specified(salt) = &params.salt else {
-        return None;
-    };
-    let mut key = vec![0; params.key_length.unwrap_or(32) as usize];
-    match params.prf.as_ref() {
-        AlgorithmIdentifier::HmacWithSha1(_) => {
-            pbkdf2::pbkdf2_hmac::<Sha1>(password, salt, params.iteration_count as u32, &mut key)
-        }
-        AlgorithmIdentifier::HmacWithSha256(_) => {
-            pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, params.iteration_count as u32, &mut key)
+impl std::fmt::Display for AlgorithmIdentifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AlgorithmIdentifier::Sha1(_) => write!(f, "SHA-1"),
+            AlgorithmIdentifier::Sha2(_) => write!(f, "SHA-256"),
+            AlgorithmIdentifier::Sha224(_) => write!(f, "SHA-224"),
+            AlgorithmIdentifier::Sha512_256(_) => write!(f, "SHA-512/256"),
+            AlgorithmIdentifier::HmacWithSha1(_) => write!(f, "HMAC-SHA1"),
+            AlgorithmIdentifier::HmacWithSha256(_) => write!(f, "HMAC-SHA256"),
+            AlgorithmIdentifier::HmacWithSha512(_) => write!(f, "HMAC-SHA512"),
+            AlgorithmIdentifier::PbewithSHAAnd40BitRC2CBC(_) => write!(f, "PBE-SHA1-RC2-40"),
+            AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(_) => write!(f, "PBE-SHA1-3DES"),
+            AlgorithmIdentifier::PbeWithSHA1And128BitRC4(_) => write!(f, "PBE-SHA1-RC4-128"),
+            AlgorithmIdentifier::PbeWithSHA1And40BitRC4(_) => write!(f, "PBE-SHA1-RC4-40"),
+            AlgorithmIdentifier::PbeWithMD5AndDESCBC(_) => write!(f, "PBE-MD5-DES"),
+            AlgorithmIdentifier::PbeWithSHA1AndDESCBC(_) => write!(f, "PBE-SHA1-DES"),
+            AlgorithmIdentifier::Pbes2(param) => write!(
+                f,
+                "PBES2 {}, {}",
+                param.key_derivation_function, param.encryption_scheme
+            ),
+            AlgorithmIdentifier::Pbkdf2(param) => write!(f, "PBKDF2-{}", param.prf),
+            AlgorithmIdentifier::AesCbcPad(_) => write!(f, "AES-256-CBC"),
+            AlgorithmIdentifier::Aes128CbcPad(_) => write!(f, "AES-128-CBC"),
+            AlgorithmIdentifier::Aes192CbcPad(_) => write!(f, "AES-192-CBC"),
+            AlgorithmIdentifier::AesKeyWrap => write!(f, "AES-256-KW"),
+            AlgorithmIdentifier::Rc2Cbc(param) => match param.effective_key_bits() {
+                Some(bits) => write!(f, "RC2-{}-CBC", bits),
+                None => write!(f, "RC2-CBC"),
+            },
+            AlgorithmIdentifier::DesEde3Cbc(_) => write!(f, "DES-EDE3-CBC"),
+            AlgorithmIdentifier::OtherAlg(other) => {
+                match foreign_algorithm_name(&other.algorithm_type) {
+                    Some(name) => write!(f, "{} ({})", name, other.algorithm_type),
+                    None => write!(f, "{}", other.algorithm_type),
+                }
+            }
         }
-        _ => return None,
     }
-
-    let AlgorithmIdentifier::AesCbcPad(iv) = encryption_scheme else {
-        return None;
-    };
-    let decryptor = Aes256CbcDec::new(key.as_slice().into(), iv.as_slice().into());
-    let result = decryptor
-        .decrypt_padded_vec_mut::<Pkcs7>(cipher_text)
-        .expect("failed");
-    Some(result)
 }
 
-#[derive(Debug)]
-pub struct DigestInfo {
-    pub digest_algorithm: AlgorithmIdentifier,
-    pub digest: Vec<u8>,
+// algorithms that turn up in the wild but aren't implemented here
+const FOREIGN_ALGORITHMS: &[(&[u64], &str)] = &[
+    (&[1, 2, 643, 2, 2, 21], "GOST 28147-89"),
+    (&[1, 2, 643, 2, 2, 9], "GOST R 34.11-94"),
+    (&[1, 2, 643, 2, 2, 10], "HMAC-GOST R 34.11-94"),
+    (&[1, 2, 643, 7, 1, 1, 2, 2], "GOST R 34.11-2012-256"),
+    (&[1, 2, 643, 7, 1, 1, 2, 3], "GOST R 34.11-2012-512"),
+    (&[1, 2, 643, 7, 1, 1, 4, 1], "HMAC-GOST R 34.11-2012-256"),
+    (&[1, 2, 643, 7, 1, 1, 4, 2], "HMAC-GOST R 34.11-2012-512"),
+    (&[1, 2, 643, 7, 1, 1, 5, 1, 1], "Magma-CTR-ACPKM"),
+    (&[1, 2, 643, 7, 1, 1, 5, 1, 2], "Magma-CTR-ACPKM-OMAC"),
+    (&[1, 2, 643, 7, 1, 1, 5, 2, 1], "Kuznyechik-CTR-ACPKM"),
+    (&[1, 2, 643, 7, 1, 1, 5, 2, 2], "Kuznyechik-CTR-ACPKM-OMAC"),
+    (&[1, 3, 36, 3, 3, 2, 8, 1, 1, 7], "brainpoolP256r1"),
+    (&[1, 3, 36, 3, 3, 2, 8, 1, 1, 11], "brainpoolP384r1"),
+    (&[1, 3, 36, 3, 3, 2, 8, 1, 1, 13], "brainpoolP512r1"),
+    (&[1, 2, 410, 200004, 1, 4], "SEED-CBC"),
+    (&[1, 2, 410, 200046, 1, 1, 2], "ARIA-128-CBC"),
+    (&[1, 2, 410, 200046, 1, 1, 12], "ARIA-256-CBC"),
+    (&[1, 2, 392, 200011, 61, 1, 1, 1, 2], "Camellia-128-CBC"),
+    (&[1, 2, 392, 200011, 61, 1, 1, 1, 4], "Camellia-256-CBC"),
+    (&[1, 2, 156, 10197, 1, 104, 2], "SM4-CBC"),
+];
+
+fn foreign_algorithm_name(oid: &ObjectIdentifier) -> Option<&'static str> {
+    FOREIGN_ALGORITHMS
+        .iter()
+        .find(|(components, _)| oid.components().as_slice() == *components)
+        .map(|(_, name)| *name)
 }
 
-impl DigestInfo {
+#[test]
+fn test_algorithm_identifier_display() {
+    let pbes2 = AlgorithmIdentifier::Pbes2(Pkcs12Pbes2Params {
+        key_derivation_function: Box::new(AlgorithmIdentifier::Pbkdf2(Pbkdf2Params {
+            salt: Pbkdf2Salt::Specified(vec![1; 16]),
+            iteration_count: 2048,
+            key_length: None,
+            prf: Box::new(AlgorithmIdentifier::HmacWithSha256(None)),
+        })),
+        encryption_scheme: Box::new(AlgorithmIdentifier::AesCbcPad(vec![0; 16])),
+    });
+    assert_eq!(pbes2.to_string(), "PBES2 PBKDF2-HMAC-SHA256, AES-256-CBC");
+    let pbe = AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(Pkcs12PbeParams {
+        salt: vec![1; 8],
+        iterations: 2048,
+    });
+    assert_eq!(pbe.to_string(), "PBE-SHA1-3DES");
+    let other = AlgorithmIdentifier::OtherAlg(OtherAlgorithmIdentifier {
+        algorithm_type: as_oid(&[1, 2, 643, 7, 1, 1, 2, 2]),
+        params: None,
+    });
+    assert_eq!(
+        other.to_string(),
+        "GOST R 34.11-2012-256 (1.2.643.7.1.1.2.2)"
+    );
+    let unknown = AlgorithmIdentifier::OtherAlg(OtherAlgorithmIdentifier {
+        algorithm_type: as_oid(&[1, 2, 3, 4]),
+        params: None,
+    });
+    assert_eq!(unknown.to_string(), "1.2.3.4");
+}
+
+#[test]
+fn test_decrypt_unsupported_algorithm() {
+    let gost = AlgorithmIdentifier::OtherAlg(OtherAlgorithmIdentifier {
+        algorithm_type: as_oid(&[1, 2, 643, 7, 1, 1, 5, 2, 1]),
+        params: None,
+    });
+    let pbes2 = AlgorithmIdentifier::Pbes2(Pkcs12Pbes2Params {
+        key_derivation_function: Box::new(AlgorithmIdentifier::Pbkdf2(Pbkdf2Params {
+            salt: Pbkdf2Salt::Specified(vec![1; 16]),
+            iteration_count: 2048,
+            key_length: None,
+            prf: Box::new(AlgorithmIdentifier::HmacWithSha256(None)),
+        })),
+        encryption_scheme: Box::new(gost.clone()),
+    });
+    let err = pbes2.decrypt_pbe(&[0; 32], b"changeit").unwrap_err();
+    match &err {
+        Pkcs12Error::UnsupportedAlgorithm(alg) => assert_eq!(alg, &gost),
+        other => panic!("{other:?}"),
+    }
+    assert_eq!(
+        err.to_string(),
+        "unsupported algorithm Kuznyechik-CTR-ACPKM (1.2.643.7.1.1.5.2.1)"
+    );
+    let err = AlgorithmIdentifier::sha1()
+        .decrypt_pbe(&[0; 32], b"changeit")
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        Pkcs12Error::UnsupportedAlgorithm(AlgorithmIdentifier::Sha1(_))
+    ));
+}
+
+#[test]
+fn test_decrypt_other_salt_source() {
+    let source = AlgorithmIdentifier::OtherAlg(OtherAlgorithmIdentifier {
+        algorithm_type: as_oid(&[1, 2, 3, 4]),
+        params: None,
+    });
+    let pbes2 = AlgorithmIdentifier::Pbes2(Pkcs12Pbes2Params {
+        key_derivation_function: Box::new(AlgorithmIdentifier::Pbkdf2(Pbkdf2Params {
+            salt: Pbkdf2Salt::OtherSource(Box::new(source.clone())),
+            iteration_count: 2048,
+            key_length: None,
+            prf: Box::new(AlgorithmIdentifier::HmacWithSha256(None)),
+        })),
+        encryption_scheme: Box::new(AlgorithmIdentifier::AesCbcPad(vec![0; 16])),
+    });
+    let der = yasna::construct_der(|w| pbes2.write(w));
+    let pbes2 = yasna::parse_der(&der, AlgorithmIdentifier::parse).unwrap();
+    let err = pbes2.decrypt_pbe(&[0; 32], b"changeit").unwrap_err();
+    match &err {
+        Pkcs12Error::UnsupportedSaltSource(alg) => assert_eq!(alg, &source),
+        other => panic!("{other:?}"),
+    }
+    assert_eq!(err.to_string(), "unsupported PBKDF2 salt source 1.2.3.4");
+}
+
+/// Rating of an algorithm by `AlgorithmIdentifier::strength`, ordered from
+/// `Broken` to `Secure`.
+///
+/// - `Secure`: AES, SHA-2 digests and HMACs, PBKDF2 with a SHA-2 PRF and at
+///   least `MIN_PBKDF2_ITERATIONS` iterations
+/// - `Weak`: 3DES, RC2 with at least `MIN_RC2_KEY_BITS` effective key bits,
+///   PBKDF2 with HMAC-SHA1 or fewer iterations
+/// - `Broken`: 40 bit RC2, RC4, single DES, SHA-1 as the MAC digest, any key
+///   derivation, PBKDF2 or the PKCS#12 one, with fewer than `MIN_ITERATIONS`
+///   iterations, and algorithms this crate doesn't know
+///
+/// PBES2 is rated as the weaker of its key derivation and its cipher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Strength {
+    Broken,
+    Weak,
+    Secure,
+}
+
+impl Strength {
+    /// The fewest PBKDF2 iterations rated `Secure`, the count OWASP
+    /// recommends for PBKDF2-HMAC-SHA256 and `PFX::upgrade_to_modern` uses.
+    /// The 2048 `PfxBuilder` writes by default are rated `Weak`.
+    pub const MIN_PBKDF2_ITERATIONS: u64 = MODERN_PBKDF2_ITERATIONS;
+    /// The fewest iterations of a key derivation rated better than `Broken`,
+    /// the minimum RFC 8018 recommends.
+    pub const MIN_ITERATIONS: u64 = 1000;
+    /// The fewest effective RC2 key bits rated `Weak` rather than `Broken`.
+    pub const MIN_RC2_KEY_BITS: usize = 128;
+
+    // the best a key derivation with `iterations` can be rated
+    fn of_iterations(iterations: u64) -> Strength {
+        if iterations < Strength::MIN_ITERATIONS {
+            Strength::Broken
+        } else if iterations < Strength::MIN_PBKDF2_ITERATIONS {
+            Strength::Weak
+        } else {
+            Strength::Secure
+        }
+    }
+}
+
+/// Key derivation parameters of a PBES1 or PBES2 algorithm.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KdfInfo {
+    pub salt: Vec<u8>,
+    pub iterations: u64,
+    /// PBKDF2 pseudo random function, `None` for the PKCS#12 PBES1 schemes.
+    pub prf: Option<AlgorithmIdentifier>,
+}
+
+fn pbes2_decrypt(
+    key_derivation_function: &AlgorithmIdentifier,
+    encryption_scheme: &AlgorithmIdentifier,
+    cipher_text: &[u8],
+    password: &[u8],
+) -> Option<Vec<u8>> {
+    let AlgorithmIdentifier::Pbkdf2(params) = key_derivation_function else {
+        return None;
+    };
+    let Pbkdf2Salt::Specified(salt) = &params.salt else {
+        return None;
+    };
+    // PBKDF2 counts rounds in a u32, a larger count is never truncated
+    let iterations = u32::try_from(params.iteration_count).ok()?;
+    if iterations == 0 || !key_length_matches(key_derivation_function, encryption_scheme) {
+        return None;
+    }
+    let default_key_length = match encryption_scheme {
+        AlgorithmIdentifier::Rc2Cbc(_) | AlgorithmIdentifier::Aes128CbcPad(_) => 16,
+        AlgorithmIdentifier::DesEde3Cbc(_) | AlgorithmIdentifier::Aes192CbcPad(_) => 24,
+        _ => 32,
+    };
+    let mut key = vec![0; params.key_length.unwrap_or(default_key_length) as usize];
+    match params.prf.as_ref() {
+        AlgorithmIdentifier::HmacWithSha1(_) => {
+            pbkdf2::pbkdf2_hmac::<Sha1>(password, salt, iterations, &mut key)
+        }
+        AlgorithmIdentifier::HmacWithSha256(_) => {
+            pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, &mut key)
+        }
+        AlgorithmIdentifier::HmacWithSha512(_) => {
+            pbkdf2::pbkdf2_hmac::<Sha512>(password, salt, iterations, &mut key)
+        }
+        _ => return None,
+    }
+
+    let iv = match encryption_scheme {
+        AlgorithmIdentifier::AesCbcPad(iv) => iv,
+        AlgorithmIdentifier::Rc2Cbc(params) => return rc2_cbc_decrypt(&key, params, cipher_text),
+        AlgorithmIdentifier::DesEde3Cbc(iv) => return des_ede3_cbc_decrypt(&key, iv, cipher_text),
+        AlgorithmIdentifier::Aes128CbcPad(iv) => {
+            let decryptor = cbc::Decryptor::<aes::Aes128>::new_from_slices(&key, iv).ok()?;
+            return decrypt_pkcs7(decryptor, cipher_text);
+        }
+        AlgorithmIdentifier::Aes192CbcPad(iv) => {
+            let decryptor = cbc::Decryptor::<aes::Aes192>::new_from_slices(&key, iv).ok()?;
+            return decrypt_pkcs7(decryptor, cipher_text);
+        }
+        _ => return None,
+    };
+    let decryptor = Aes256CbcDec::new(key.as_slice().into(), iv.as_slice().into());
+    decrypt_pkcs7(decryptor, cipher_text)
+}
+
+// CBC decryption with the PKCS#7 padding stripped. with `lenient-padding` whole
+// extra padding blocks are stripped too, but only when that leaves a SEQUENCE
+fn decrypt_pkcs7<C: BlockDecryptMut>(decryptor: C, cipher_text: &[u8]) -> Option<Vec<u8>> {
+    let plain = decryptor
+        .decrypt_padded_vec_mut::<Pkcs7>(cipher_text)
+        .ok()?;
+    #[cfg(feature = "lenient-padding")]
+    {
+        let mut rest = plain.as_slice();
+        while let Some(stripped) = strip_pkcs7(rest, C::block_size()) {
+            if stripped.first() == Some(&0x30)
+                && yasna::parse_ber(stripped, |r| r.read_der()).is_ok()
+            {
+                return Some(stripped.to_vec());
+            }
+            rest = stripped;
+        }
+    }
+    Some(plain)
+}
+
+#[cfg(feature = "lenient-padding")]
+fn strip_pkcs7(data: &[u8], block_size: usize) -> Option<&[u8]> {
+    let pad = *data.last()? as usize;
+    if pad == 0 || pad > block_size || pad > data.len() {
+        return None;
+    }
+    let (rest, padding) = data.split_at(data.len() - pad);
+    padding.iter().all(|b| *b as usize == pad).then(|| rest)
+}
+
+// `Pkcs7` pads to the block size of the cipher it's used with, for the 8 byte
+// blocks of 3DES and RC2 that is PKCS#5 padding. never strip 16 byte padding here
+fn des_ede3_cbc_decrypt(key: &[u8], iv: &[u8], cipher_text: &[u8]) -> Option<Vec<u8>> {
+    use cbc::Decryptor;
+    use des::TdesEde3;
+
+    let decryptor = Decryptor::<TdesEde3>::new_from_slices(key, iv).ok()?;
+    decrypt_pkcs7(decryptor, cipher_text)
+}
+
+fn rc2_cbc_decrypt(key: &[u8], params: &Rc2CbcParams, cipher_text: &[u8]) -> Option<Vec<u8>> {
+    use cbc::Decryptor;
+    use cipher::InnerIvInit;
+    use rc2::Rc2;
+
+    //the rc2 crate panics on keys outside 1 to 128 bytes
+    if !(1..=128).contains(&key.len()) {
+        return None;
+    }
+    let rc2 = Rc2::new_with_eff_key_len(key, params.effective_key_bits()?);
+    let decryptor = Decryptor::inner_iv_slice_init(rc2, &params.iv).ok()?;
+    decrypt_pkcs7(decryptor, cipher_text)
+}
+
+#[test]
+fn test_hmac_params_round_trip() {
+    let null = yasna::construct_der(|w| w.write_null());
+    for alg in [
+        AlgorithmIdentifier::HmacWithSha1(Some(null.clone())),
+        AlgorithmIdentifier::HmacWithSha256(Some(null.clone())),
+        AlgorithmIdentifier::HmacWithSha256(None),
+    ] {
+        let der = yasna::construct_der(|w| alg.write(w));
+        assert_eq!(
+            yasna::parse_der(&der, AlgorithmIdentifier::parse).unwrap(),
+            alg
+        );
+    }
+
+    let params = Pbkdf2Params {
+        salt: Pbkdf2Salt::Specified(vec![1; 8]),
+        iteration_count: 2048,
+        key_length: None,
+        prf: Box::new(AlgorithmIdentifier::HmacWithSha256(Some(null))),
+    };
+    let der = yasna::construct_der(|w| params.write(w));
+    assert_eq!(yasna::parse_der(&der, Pbkdf2Params::parse).unwrap(), params);
+}
+
+#[test]
+fn test_digest_params_round_trip() {
+    for oid in [&*OID_SHA1, &*OID_SHA2, &*OID_SHA224, &*OID_SHA512_256] {
+        let with_null = yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_oid(oid);
+                w.next().write_null();
+            })
+        });
+        let without_null = yasna::construct_der(|w| w.write_sequence(|w| w.next().write_oid(oid)));
+        for der in [with_null, without_null] {
+            let alg = yasna::parse_der(&der, AlgorithmIdentifier::parse).unwrap();
+            assert_eq!(yasna::construct_der(|w| alg.write(w)), der);
+        }
+        let other_params = yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_oid(oid);
+                w.next().write_u8(0);
+            })
+        });
+        assert!(yasna::parse_der(&other_params, AlgorithmIdentifier::parse).is_err());
+    }
+    for oid in [
+        &*OID_HMAC_WITH_SHA1,
+        &*OID_HMAC_WITH_SHA256,
+        &*OID_HMAC_WITH_SHA512,
+    ] {
+        let with_null = yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_oid(oid);
+                w.next().write_null();
+            })
+        });
+        let without_null = yasna::construct_der(|w| w.write_sequence(|w| w.next().write_oid(oid)));
+        for der in [with_null, without_null] {
+            let alg = yasna::parse_der(&der, AlgorithmIdentifier::parse).unwrap();
+            assert_eq!(yasna::construct_der(|w| alg.write(w)), der);
+        }
+    }
+
+    //a MAC whose digest algorithm has no parameters stays that way
+    let mut mac_data =
+        MacData::new_with_algorithm(b"data", b"changeit", AlgorithmIdentifier::sha256(), 1)
+            .unwrap();
+    mac_data.mac.digest_algorithm = AlgorithmIdentifier::Sha2(None);
+    let der = yasna::construct_der(|w| mac_data.write(w));
+    let parsed = yasna::parse_der(&der, MacData::parse).unwrap();
+    assert_eq!(parsed.mac.digest_algorithm, AlgorithmIdentifier::Sha2(None));
+    assert_eq!(yasna::construct_der(|w| parsed.write(w)), der);
+    assert!(parsed.verify_mac(b"data", &bmp_string("changeit")));
+}
+
+#[test]
+fn test_aes_cbc_pad_iv_length() {
+    let der = yasna::construct_der(|w| AlgorithmIdentifier::AesCbcPad(vec![0; 16]).write(w));
+    assert_eq!(
+        yasna::parse_der(&der, AlgorithmIdentifier::parse).unwrap(),
+        AlgorithmIdentifier::AesCbcPad(vec![0; 16])
+    );
+    for len in [0, 8, 15, 17] {
+        let der = yasna::construct_der(|w| AlgorithmIdentifier::AesCbcPad(vec![0; len]).write(w));
+        assert!(yasna::parse_der(&der, AlgorithmIdentifier::parse).is_err());
+    }
+    for alg in [
+        AlgorithmIdentifier::Aes128CbcPad(vec![1; 16]),
+        AlgorithmIdentifier::Aes192CbcPad(vec![1; 16]),
+    ] {
+        let der = yasna::construct_der(|w| alg.write(w));
+        assert_eq!(
+            yasna::parse_der(&der, AlgorithmIdentifier::parse).unwrap(),
+            alg
+        );
+    }
+    let der = yasna::construct_der(|w| AlgorithmIdentifier::Aes128CbcPad(vec![0; 8]).write(w));
+    assert!(yasna::parse_der(&der, AlgorithmIdentifier::parse).is_err());
+}
+
+#[test]
+fn test_read_pbes2_aes128_aes192() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    //certificates with AES-192-CBC, the key with AES-128-CBC
+    let p12 = fs::read("pbes2_aes128_192.p12").unwrap();
+
+    let pfx = PFX::parse(&p12).unwrap();
+    let contents = pfx.content_infos().unwrap();
+    assert!(matches!(
+        &contents[0],
+        ContentInfoSummary::EncryptedData(alg) if alg.to_string() == "PBES2 PBKDF2-HMAC-SHA256, AES-192-CBC"
+    ));
+    assert!(pfx.verify_mac("changeit"));
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key]);
+    assert_eq!(pfx.cert_x509_bags("changeit").unwrap(), vec![cert]);
+}
+
+/// RFC 3394 unwrap with the default initial value of a key wrapped with
+/// id-aes256-wrap under the 256 bit key-encryption `key`.
+///
+/// AES key wrap protects a content encryption key, not content, so it is
+/// not accepted as a PBES2 encryption scheme. `None` if `cipher_text` isn't
+/// a whole number of 64 bit blocks or the integrity check fails.
+pub fn aes256_key_unwrap(key: &[u8], cipher_text: &[u8]) -> Option<Vec<u8>> {
+    use cipher::{generic_array::GenericArray, BlockDecrypt, KeyInit};
+    use subtle::ConstantTimeEq;
+
+    if cipher_text.len() % 8 != 0 || cipher_text.len() < 24 {
+        return None;
+    }
+    let aes = aes::Aes256::new_from_slice(key).ok()?;
+    let n = cipher_text.len() / 8 - 1;
+    let mut a = [0u8; 8];
+    a.copy_from_slice(&cipher_text[..8]);
+    let mut r = cipher_text[8..].to_vec();
+    for j in (0..6).rev() {
+        for i in (1..=n).rev() {
+            let t = (n * j + i) as u64;
+            let mut block = GenericArray::from([0u8; 16]);
+            block[..8].copy_from_slice(&(u64::from_be_bytes(a) ^ t).to_be_bytes());
+            block[8..].copy_from_slice(&r[(i - 1) * 8..i * 8]);
+            aes.decrypt_block(&mut block);
+            a.copy_from_slice(&block[..8]);
+            r[(i - 1) * 8..i * 8].copy_from_slice(&block[8..]);
+        }
+    }
+    // compared in constant time like a MAC
+    if !bool::from(a.ct_eq(&[0xA6; 8])) {
+        return None;
+    }
+    Some(r)
+}
+
+#[test]
+fn test_aes256_key_unwrap() {
+    use hex_literal::hex;
+    let kek = hex!("000102030405060708090A0B0C0D0E0F101112131415161718191A1B1C1D1E1F");
+    let wrapped =
+        hex!("28C9F404C4B810F4CBCCB35CFB87F8263F5786E2D80ED326CBC7F0E71A99F43BFB988B9B7A02DD21");
+    let key = hex!("00112233445566778899AABBCCDDEEFF000102030405060708090A0B0C0D0E0F");
+    assert_eq!(aes256_key_unwrap(&kek, &wrapped).unwrap(), key);
+    assert_eq!(aes256_key_unwrap(&key, &wrapped), None);
+
+    let algorithm = AlgorithmIdentifier::Pbes2(Pkcs12Pbes2Params {
+        key_derivation_function: Box::new(AlgorithmIdentifier::Pbkdf2(Pbkdf2Params {
+            salt: Pbkdf2Salt::Specified(vec![1; 16]),
+            iteration_count: 2048,
+            key_length: None,
+            prf: Box::new(AlgorithmIdentifier::HmacWithSha256(None)),
+        })),
+        encryption_scheme: Box::new(AlgorithmIdentifier::AesKeyWrap),
+    });
+    assert!(matches!(
+        algorithm.decrypt_pbe(&wrapped, b"changeit"),
+        Err(Pkcs12Error::UnsupportedAlgorithm(
+            AlgorithmIdentifier::AesKeyWrap
+        ))
+    ));
+
+    let der = yasna::construct_der(|w| AlgorithmIdentifier::AesKeyWrap.write(w));
+    assert_eq!(
+        yasna::parse_der(&der, AlgorithmIdentifier::parse).unwrap(),
+        AlgorithmIdentifier::AesKeyWrap
+    );
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DigestInfo {
+    pub digest_algorithm: AlgorithmIdentifier,
+    pub digest: Vec<u8>,
+}
+
+impl DigestInfo {
     pub fn parse(r: BERReader) -> Result<Self, ASN1Error> {
         r.read_sequence(|r| {
             let digest_algorithm = AlgorithmIdentifier::parse(r.next())?;
@@ -557,24 +1746,110 @@ impl DigestInfo {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MacData {
     pub mac: DigestInfo,
     pub salt: Vec<u8>,
     pub iterations: u32,
 }
 
+// integers with redundant leading zero bytes are rejected by yasna, so any
+// length is read, `None` for values past u32
+fn read_lenient_u32_checked(r: BERReader) -> Result<Option<u32>, ASN1Error> {
+    let bytes = r.read_tagged_implicit(TAG_INTEGER, |r| r.read_bytes())?;
+    if bytes.is_empty() || bytes[0] & 0x80 != 0 {
+        return Err(ASN1Error::new(ASN1ErrorKind::Invalid));
+    }
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    if bytes.len() - start > 4 {
+        return Ok(None);
+    }
+    Ok(Some(
+        bytes[start..]
+            .iter()
+            .fold(0, |acc, b| (acc << 8) | u32::from(*b)),
+    ))
+}
+
+// values past u32 fail with IntegerOverflow
+fn read_lenient_u32(r: BERReader) -> Result<u32, ASN1Error> {
+    read_lenient_u32_checked(r)?.ok_or_else(|| ASN1Error::new(ASN1ErrorKind::IntegerOverflow))
+}
+
+// HMAC keyed with the PKCS#12 KDF (id 3) output, as long as the digest output
+fn pkcs12_mac<D: Digest + BlockSizeUser>(
+    password: &[u8],
+    salt: &[u8],
+    iterations: u32,
+) -> Option<SimpleHmac<D>> {
+    let key_len = <D as Digest>::output_size() as u64;
+    let key = pbepkcs12sha::<D>(password, salt, iterations as u64, 3, key_len)?;
+    <SimpleHmac<D> as Mac>::new_from_slice(&key).ok()
+}
+
+fn verify_pkcs12_mac<D: Digest + BlockSizeUser>(
+    reader: impl io::Read,
+    password: &[u8],
+    salt: &[u8],
+    iterations: u32,
+    digest: &[u8],
+) -> io::Result<bool> {
+    match pkcs12_mac::<D>(password, salt, iterations) {
+        Some(mac) => Ok(mac_reader(mac, reader)?.verify_slice(digest).is_ok()),
+        None => Ok(false),
+    }
+}
+
+fn pkcs12_mac_digest<D: Digest + BlockSizeUser>(
+    reader: impl io::Read,
+    password: &[u8],
+    salt: &[u8],
+    iterations: u32,
+) -> io::Result<Option<Vec<u8>>> {
+    match pkcs12_mac::<D>(password, salt, iterations) {
+        Some(mac) => Ok(Some(
+            mac_reader(mac, reader)?.finalize().into_bytes().to_vec(),
+        )),
+        None => Ok(None),
+    }
+}
+
+// feeds `reader` to `mac` a chunk at a time
+fn mac_reader<M: Mac>(mut mac: M, mut reader: impl io::Read) -> io::Result<M> {
+    let mut buf = [0u8; 8192];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(mac),
+            Ok(n) => mac.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 impl MacData {
+    /// Parses a MacData, an iteration count past u32 fails with
+    /// `IntegerOverflow`. `PFX::parse` reports that as `InvalidKdfParams`.
     pub fn parse(r: BERReader) -> Result<MacData, ASN1Error> {
+        MacData::parse_checked(r)?.ok_or_else(|| ASN1Error::new(ASN1ErrorKind::IntegerOverflow))
+    }
+
+    // `None` when the iteration count doesn't fit in `iterations`
+    fn parse_checked(r: BERReader) -> Result<Option<MacData>, ASN1Error> {
         r.read_sequence(|r| {
             let mac = DigestInfo::parse(r.next())?;
             let salt = r.next().read_bytes()?;
-            let iterations = r.next().read_u32()?;
-            Ok(MacData {
+            //iterations INTEGER DEFAULT 1
+            let iterations = match r.read_optional(read_lenient_u32_checked)? {
+                Some(Some(iterations)) => iterations,
+                Some(None) => return Ok(None),
+                None => 1,
+            };
+            Ok(Some(MacData {
                 mac,
                 salt,
                 iterations,
-            })
+            }))
         })
     }
 
@@ -582,109 +1857,556 @@ impl MacData {
         w.write_sequence(|w| {
             self.mac.write(w.next());
             w.next().write_bytes(&self.salt);
-            w.next().write_u32(self.iterations);
+            if self.iterations != 1 {
+                w.next().write_u32(self.iterations);
+            }
         })
     }
 
+    /// Whether the MAC digest is one `verify_mac` can compute.
+    ///
+    /// `verify_mac` is false for a digest it can't compute, this tells that
+    /// apart from a wrong password. An unknown digest gives
+    /// `Pkcs12Error::UnsupportedMacAlgorithm`, a known algorithm that isn't a
+    /// digest `Pkcs12Error::UnsupportedAlgorithm`.
+    pub fn check_algorithm(&self) -> Result<(), Pkcs12Error> {
+        match &self.mac.digest_algorithm {
+            AlgorithmIdentifier::Sha1(_)
+            | AlgorithmIdentifier::Sha2(_)
+            | AlgorithmIdentifier::Sha224(_)
+            | AlgorithmIdentifier::Sha512_256(_) => Ok(()),
+            AlgorithmIdentifier::OtherAlg(other) => Err(Pkcs12Error::UnsupportedMacAlgorithm(
+                other.algorithm_type.clone(),
+            )),
+            alg => Err(Pkcs12Error::UnsupportedAlgorithm(alg.clone())),
+        }
+    }
+
     pub fn verify_mac(&self, data: &[u8], password: &[u8]) -> bool {
+        // reading a slice doesn't fail
+        self.verify_mac_reader(data, password).unwrap_or(false)
+    }
+
+    /// Like `verify_mac`, with the data read from `reader` in chunks, so a
+    /// large authenticated safe needn't be in memory at once.
+    pub fn verify_mac_reader(&self, reader: impl io::Read, password: &[u8]) -> io::Result<bool> {
+        let (salt, iterations, digest) = (&self.salt, self.iterations, &self.mac.digest);
         match self.mac.digest_algorithm {
-            AlgorithmIdentifier::Sha1 => {
-                let key = pbepkcs12sha::<Sha1>(password, &self.salt, self.iterations as u64, 3, 20);
-                let mut mac = HmacSha1::new_from_slice(&key).unwrap();
-                mac.update(data);
-                mac.verify_slice(&self.mac.digest).is_ok()
+            AlgorithmIdentifier::Sha1(_) => {
+                verify_pkcs12_mac::<Sha1>(reader, password, salt, iterations, digest)
+            }
+            AlgorithmIdentifier::Sha2(_) => {
+                verify_pkcs12_mac::<Sha256>(reader, password, salt, iterations, digest)
             }
-            AlgorithmIdentifier::Sha2 => {
-                let key =
-                    pbepkcs12sha::<Sha256>(password, &self.salt, self.iterations as u64, 3, 32);
-                let mut mac = HmacSha256::new_from_slice(&key).unwrap();
-                mac.update(data);
-                mac.verify_slice(&self.mac.digest).is_ok()
+            AlgorithmIdentifier::Sha224(_) => {
+                verify_pkcs12_mac::<Sha224>(reader, password, salt, iterations, digest)
             }
-            _ => {
-                debug_assert!(false, "digest should be sha1 or sha2");
-                false
+            AlgorithmIdentifier::Sha512_256(_) => {
+                verify_pkcs12_mac::<Sha512_256>(reader, password, salt, iterations, digest)
             }
+            _ => Ok(false),
         }
     }
 
     pub fn new(data: &[u8], password: &[u8]) -> MacData {
-        let salt = rand::<8>().unwrap();
-        let password = std::str::from_utf8(password).unwrap();
+        Self::new_with_algorithm(
+            data,
+            password,
+            AlgorithmIdentifier::sha1(),
+            ITERATIONS as u32,
+        )
+        .unwrap()
+    }
+
+    /// `digest_algorithm` is one of `AlgorithmIdentifier::Sha1`, `Sha2`, `Sha224` or `Sha512_256`.
+    pub fn new_with_algorithm(
+        data: &[u8],
+        password: &[u8],
+        digest_algorithm: AlgorithmIdentifier,
+        iterations: u32,
+    ) -> Option<MacData> {
+        // reading a slice doesn't fail
+        Self::new_from_reader(data, password, digest_algorithm, iterations)
+            .ok()
+            .flatten()
+    }
+
+    /// Like `new_with_algorithm`, with the data read from `reader` in chunks.
+    pub fn new_from_reader(
+        reader: impl io::Read,
+        password: &[u8],
+        digest_algorithm: AlgorithmIdentifier,
+        iterations: u32,
+    ) -> io::Result<Option<MacData>> {
+        let Some(salt) = rand::<8>() else {
+            return Ok(None);
+        };
+        let Ok(password) = std::str::from_utf8(password) else {
+            return Ok(None);
+        };
         let password = &bmp_string(password);
-        let key = pbepkcs12sha::<Sha1>(password, &salt, ITERATIONS, 3, 20);
-        let mut mac = HmacSha1::new_from_slice(&key).unwrap();
-        mac.update(data);
-        let digest = mac.finalize().into_bytes().to_vec();
-        MacData {
+        let digest = match digest_algorithm {
+            AlgorithmIdentifier::Sha1(_) => {
+                pkcs12_mac_digest::<Sha1>(reader, password, &salt, iterations)?
+            }
+            AlgorithmIdentifier::Sha2(_) => {
+                pkcs12_mac_digest::<Sha256>(reader, password, &salt, iterations)?
+            }
+            AlgorithmIdentifier::Sha224(_) => {
+                pkcs12_mac_digest::<Sha224>(reader, password, &salt, iterations)?
+            }
+            AlgorithmIdentifier::Sha512_256(_) => {
+                pkcs12_mac_digest::<Sha512_256>(reader, password, &salt, iterations)?
+            }
+            _ => None,
+        };
+        Ok(digest.map(|digest| MacData {
             mac: DigestInfo {
-                digest_algorithm: AlgorithmIdentifier::Sha1,
+                digest_algorithm,
                 digest,
             },
             salt: salt.to_vec(),
-            iterations: ITERATIONS as u32,
-        }
+            iterations,
+        }))
     }
 }
 
-fn rand<const IV_SIZE: usize>() -> Option<[u8; IV_SIZE]> {
-    let mut buf = [0u8; IV_SIZE];
-    if getrandom(&mut buf).is_ok() {
-        Some(buf)
-    } else {
-        None
+#[test]
+fn test_unsupported_mac_algorithm() {
+    // id-tc26-gost3411-12-256, Streebog-256
+    let streebog = ObjectIdentifier::from_slice(&[1, 2, 643, 7, 1, 1, 2, 2]);
+    let mut pfx = PfxBuilder::new().build("changeit").unwrap();
+    pfx.mac_data.as_mut().unwrap().mac.digest_algorithm =
+        AlgorithmIdentifier::OtherAlg(OtherAlgorithmIdentifier {
+            algorithm_type: streebog.clone(),
+            params: None,
+        });
+    let pfx = PFX::parse(&pfx.to_der()).unwrap();
+    match pfx.verify("changeit") {
+        Err(Pkcs12Error::UnsupportedMacAlgorithm(oid)) => assert_eq!(oid, streebog),
+        other => panic!("unexpected result {other:?}"),
     }
+    assert_eq!(pfx.mac_status("changeit"), MacStatus::Failed);
+    assert_eq!(pfx.verify_any(&["changeit"]), None);
+
+    let pfx = PfxBuilder::new().build("changeit").unwrap();
+    assert!(pfx.mac_data.as_ref().unwrap().check_algorithm().is_ok());
+    assert!(matches!(pfx.verify("wrong"), Err(Pkcs12Error::InvalidMac)));
 }
 
-pub trait DataEncryptor {
-    fn encrypt_keybag<KDF: KeyDeriver>(&self, data: &[u8], password: &[u8]) -> Option<SafeBagKind> {
-        self.encrypt_keybag_key_deriver(data, password, &KDF::default())
+#[test]
+fn test_mac_data_reader() {
+    // larger than the chunks the reader is read in
+    let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    for alg in [AlgorithmIdentifier::sha1(), AlgorithmIdentifier::sha256()] {
+        let mac_data = MacData::new_from_reader(io::Cursor::new(&data), b"changeit", alg, 2048)
+            .unwrap()
+            .unwrap();
+        let password = bmp_string("changeit");
+        assert!(mac_data.verify_mac(&data, &password));
+        assert!(mac_data
+            .verify_mac_reader(io::Cursor::new(&data), &password)
+            .unwrap());
+        assert!(!mac_data.verify_mac_reader(&data[1..], &password).unwrap());
     }
-    fn encrypt_keybag_key_deriver(
-        &self,
-        data: &[u8],
-        password: &[u8],
-        key_deriver: &impl KeyDeriver,
-    ) -> Option<SafeBagKind>;
-    fn encrypt<KDF: KeyDeriver>(
-        &self,
-        data: &[u8],
-        password: &[u8],
-    ) -> Option<EncryptedContentInfo> {
-        self.encrypt_key_deriver(data, password, &KDF::default())
+    struct Failing;
+    impl io::Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::Other, "broken"))
+        }
     }
-    fn encrypt_key_deriver(
-        &self,
-        data: &[u8],
-        password: &[u8],
-        key_deriver: &impl KeyDeriver,
-    ) -> Option<EncryptedContentInfo>;
-
-    fn new() -> impl DataEncryptor;
-}
-pub trait KeyDeriver: Default {
-    fn derive_key(&self, password: &[u8]) -> Option<Vec<u8>>;
-    fn get_algorithm(&self) -> AlgorithmIdentifier;
-    fn new(alg: AlgorithmIdentifier) -> impl KeyDeriver;
+    let mac_data = MacData::new(b"data", b"changeit");
+    assert!(mac_data
+        .verify_mac_reader(Failing, &bmp_string("changeit"))
+        .is_err());
 }
 
-pub struct AesCbcDataEncryptor {
-    iv: Vec<u8>,
+#[test]
+fn test_mac_data_sha256() {
+    let mac_data =
+        MacData::new_with_algorithm(b"data", b"changeit", AlgorithmIdentifier::sha256(), 2048)
+            .unwrap();
+    assert_eq!(mac_data.mac.digest.len(), 32);
+    assert!(mac_data.verify_mac(b"data", &bmp_string("changeit")));
+    assert!(!mac_data.verify_mac(b"data", &bmp_string("wrong")));
+    assert!(!mac_data.verify_mac(b"other", &bmp_string("changeit")));
+
+    let der = yasna::construct_der(|w| mac_data.write(w));
+    let parsed = yasna::parse_der(&der, MacData::parse).unwrap();
+    assert_eq!(parsed.mac.digest_algorithm, AlgorithmIdentifier::sha256());
+    assert!(parsed.verify_mac(b"data", &bmp_string("changeit")));
 }
-pub struct Pbkdf2(AlgorithmIdentifier);
 
-impl Default for Pbkdf2 {
-    fn default() -> Self {
+#[test]
+fn test_mac_data_sha224_sha512_256() {
+    for (alg, len) in [
+        (AlgorithmIdentifier::sha224(), 28),
+        (AlgorithmIdentifier::sha512_256(), 32),
+    ] {
+        let mac_data =
+            MacData::new_with_algorithm(b"data", b"changeit", alg.clone(), 2048).unwrap();
+        assert_eq!(mac_data.mac.digest.len(), len);
+        assert!(mac_data.verify_mac(b"data", &bmp_string("changeit")));
+        assert!(!mac_data.verify_mac(b"data", &bmp_string("wrong")));
+
+        let der = yasna::construct_der(|w| mac_data.write(w));
+        let parsed = yasna::parse_der(&der, MacData::parse).unwrap();
+        assert_eq!(parsed.mac.digest_algorithm, alg);
+    }
+    let salt = [0u8; 8];
+    assert_eq!(
+        pkcs12_kdf::<Sha224>(&[0, 0], &salt, 1, 3, 28)
+            .unwrap()
+            .len(),
+        28
+    );
+    assert_eq!(
+        pkcs12_kdf::<Sha512_256>(&[0, 0], &salt, 1, 3, 32)
+            .unwrap()
+            .len(),
+        32
+    );
+}
+
+#[test]
+fn test_read_mac_sha224_sha512_256() {
+    for (name, alg) in [
+        ("mac_sha224.p12", AlgorithmIdentifier::sha224()),
+        ("mac_sha512_256.p12", AlgorithmIdentifier::sha512_256()),
+    ] {
+        let p12 = fs::read(name).unwrap();
+        let pfx = PFX::parse(&p12).unwrap();
+        assert!(pfx.verify_mac("changeit"));
+        assert!(!pfx.verify_mac("wrong"));
+        assert_eq!(pfx.mac_algorithm(), Some(&alg));
+        assert_eq!(pfx.mac_iterations(), Some(2048));
+    }
+}
+
+#[test]
+fn test_verify_any() {
+    let p12 = fs::read("mac_sha224.p12").unwrap();
+    let mut pfx = PFX::parse(&p12).unwrap();
+    assert_eq!(pfx.verify_any(&["wrong", "changeit", "changeit"]), Some(1));
+    assert_eq!(pfx.verify_any(&["wrong", ""]), None);
+    assert_eq!(pfx.verify_any(&[]), None);
+    pfx.mac_data = None;
+    assert_eq!(pfx.verify_any(&["changeit"]), None);
+}
+
+#[test]
+fn test_diagnose() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+
+    let mut pfx =
+        PFX::new::<AesCbcDataEncryptor, Pbkdf2>(&cert, &key, None, "content", "look").unwrap();
+    assert_eq!(
+        pfx.diagnose("content"),
+        Diagnosis {
+            mac_ok: true,
+            content_decrypts: true
+        }
+    );
+    let auth_safe = pfx.auth_safe_der("content").unwrap();
+    pfx.mac_data = Some(MacData::new(&auth_safe, b"integrity"));
+    assert_eq!(
+        pfx.diagnose("integrity"),
+        Diagnosis {
+            mac_ok: true,
+            content_decrypts: false
+        }
+    );
+    assert_eq!(
+        pfx.diagnose("content"),
+        Diagnosis {
+            mac_ok: false,
+            content_decrypts: true
+        }
+    );
+    assert_eq!(
+        pfx.diagnose("wrong"),
+        Diagnosis {
+            mac_ok: false,
+            content_decrypts: false
+        }
+    );
+}
+
+#[test]
+fn test_pfx_clone() {
+    let p12 = fs::read("mac_sha224.p12").unwrap();
+    let pfx = PFX::parse(&p12).unwrap();
+    let mut copy = pfx.clone();
+    assert_eq!(copy, pfx);
+    copy.mac_data = None;
+    assert_ne!(copy, pfx);
+    assert!(pfx.verify_mac("changeit") && !pfx.verify_mac("wrong"));
+}
+
+#[test]
+fn test_remove_and_set_mac() {
+    let p12 = fs::read("mac_sha224.p12").unwrap();
+    let mut pfx = PFX::parse(&p12).unwrap();
+    pfx.remove_mac();
+    let without_mac = PFX::parse(&pfx.to_der()).unwrap();
+    assert!(!without_mac.has_mac());
+    assert_eq!(without_mac, pfx);
+
+    pfx.set_mac("other").unwrap();
+    let with_mac = PFX::parse(&pfx.to_der()).unwrap();
+    assert_eq!(with_mac.mac_status("other"), MacStatus::Verified);
+    assert_eq!(with_mac.mac_status("changeit"), MacStatus::Failed);
+}
+
+#[test]
+fn test_mac_status() {
+    let p12 = fs::read("mac_sha224.p12").unwrap();
+    let mut pfx = PFX::parse(&p12).unwrap();
+    assert!(pfx.has_mac());
+    assert_eq!(pfx.mac_status("changeit"), MacStatus::Verified);
+    assert_eq!(pfx.mac_status("wrong"), MacStatus::Failed);
+    pfx.mac_data = None;
+    assert!(!pfx.has_mac());
+    assert_eq!(pfx.mac_status("changeit"), MacStatus::NoMac);
+    assert!(pfx.verify_mac("wrong"));
+}
+
+#[test]
+fn test_mac_accessors() {
+    let p12 = fs::read("openssl_nomaciter.p12").unwrap();
+    let mut pfx = PFX::parse(&p12).unwrap();
+    assert_eq!(pfx.mac_algorithm(), Some(&AlgorithmIdentifier::sha1()));
+    assert_eq!(pfx.mac_iterations(), Some(1));
+    pfx.mac_data = None;
+    assert_eq!(pfx.mac_algorithm(), None);
+    assert_eq!(pfx.mac_iterations(), None);
+}
+
+#[test]
+fn test_mac_data_iterations() {
+    let mac_data =
+        MacData::new_with_algorithm(b"data", b"changeit", AlgorithmIdentifier::sha1(), 1).unwrap();
+    let der = yasna::construct_der(|w| mac_data.write(w));
+    let parsed = yasna::parse_der(&der, MacData::parse).unwrap();
+    assert_eq!(parsed.iterations, 1);
+    assert!(parsed.verify_mac(b"data", &bmp_string("changeit")));
+
+    let padded = yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            mac_data.mac.write(w.next());
+            w.next().write_bytes(&mac_data.salt);
+            w.next()
+                .write_tagged_implicit(TAG_INTEGER, |w| w.write_bytes(&[0, 0, 0x08, 0x00]));
+        })
+    });
+    let parsed = yasna::parse_der(&padded, MacData::parse).unwrap();
+    assert_eq!(parsed.iterations, 2048);
+
+    let negative = yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            mac_data.mac.write(w.next());
+            w.next().write_bytes(&mac_data.salt);
+            w.next().write_i64(-1);
+        })
+    });
+    assert!(yasna::parse_der(&negative, MacData::parse).is_err());
+
+    let too_large = yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            mac_data.mac.write(w.next());
+            w.next().write_bytes(&mac_data.salt);
+            w.next().write_u64(1 << 32);
+        })
+    });
+    let err = yasna::parse_der(&too_large, MacData::parse).unwrap_err();
+    assert_eq!(err.kind(), ASN1ErrorKind::IntegerOverflow);
+
+    let pfx = PfxBuilder::new()
+        .add_ca(&fs::read("ca.der").unwrap())
+        .build("changeit")
+        .unwrap();
+    let der = yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next().write_u8(3);
+            pfx.auth_safe.write(w.next());
+            w.next().write_der(&too_large);
+        })
+    });
+    assert!(matches!(
+        PFX::parse(&der),
+        Err(Pkcs12Error::InvalidKdfParams)
+    ));
+}
+
+#[test]
+fn test_read_mac_iterations_600000() {
+    let p12 = fs::read("mac_iter_600000.p12").unwrap();
+    let pfx = PFX::parse(&p12).unwrap();
+    assert_eq!(pfx.mac_iterations(), Some(600_000));
+    assert!(pfx.verify_mac("changeit"));
+}
+
+#[test]
+fn test_read_openssl_nomaciter() {
+    let key = fs::read("clientkey.der").unwrap();
+    // written by `openssl pkcs12 -export -nomaciter`, which leaves the MAC
+    // iterations out, not by Windows CryptoAPI, no export from it was at hand
+    let p12 = fs::read("openssl_nomaciter.p12").unwrap();
+
+    let pfx = PFX::parse(&p12).unwrap();
+    assert_eq!(pfx.mac_data.as_ref().unwrap().iterations, 1);
+    assert!(pfx.verify_mac("changeit"));
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key]);
+}
+
+fn random_bytes(len: usize) -> Option<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    getrandom(&mut buf).ok()?;
+    Some(buf)
+}
+
+fn rand<const IV_SIZE: usize>() -> Option<[u8; IV_SIZE]> {
+    let mut buf = [0u8; IV_SIZE];
+    if getrandom(&mut buf).is_ok() {
+        Some(buf)
+    } else {
+        None
+    }
+}
+
+pub trait DataEncryptor {
+    // length of the random salt the encryptor makes itself, 0 when the key deriver holds the salt
+    const SALT_LEN: usize;
+    // length of the random IV, 0 when the IV is derived from the password like in PKCS#12 PBE
+    const IV_LEN: usize;
+    fn generate_salt() -> Option<Vec<u8>> {
+        random_bytes(Self::SALT_LEN)
+    }
+    fn generate_iv() -> Option<Vec<u8>> {
+        random_bytes(Self::IV_LEN)
+    }
+    fn encrypt_keybag<KDF: KeyDeriver>(&self, data: &[u8], password: &[u8]) -> Option<SafeBagKind> {
+        self.encrypt_keybag_key_deriver(data, password, &KDF::default())
+    }
+    fn encrypt_keybag_key_deriver(
+        &self,
+        data: &[u8],
+        password: &[u8],
+        key_deriver: &impl KeyDeriver,
+    ) -> Option<SafeBagKind>;
+    fn encrypt<KDF: KeyDeriver>(
+        &self,
+        data: &[u8],
+        password: &[u8],
+    ) -> Option<EncryptedContentInfo> {
+        self.encrypt_key_deriver(data, password, &KDF::default())
+    }
+    fn encrypt_key_deriver(
+        &self,
+        data: &[u8],
+        password: &[u8],
+        key_deriver: &impl KeyDeriver,
+    ) -> Option<EncryptedContentInfo>;
+
+    fn new() -> impl DataEncryptor;
+}
+pub trait KeyDeriver: Default {
+    const SALT_LEN: usize;
+    fn generate_salt() -> Option<Vec<u8>> {
+        random_bytes(Self::SALT_LEN)
+    }
+    fn derive_key(&self, password: &[u8]) -> Option<Vec<u8>>;
+    fn get_algorithm(&self) -> AlgorithmIdentifier;
+    fn new(alg: AlgorithmIdentifier) -> impl KeyDeriver;
+}
+
+pub struct AesCbcDataEncryptor {
+    iv: Vec<u8>,
+}
+
+impl AesCbcDataEncryptor {
+    /// An encryptor with a fixed IV instead of a random one, for tests and
+    /// reproducible builds. Never reuse an IV with the same key otherwise.
+    pub fn with_iv(iv: [u8; 16]) -> Self {
+        Self { iv: iv.to_vec() }
+    }
+}
+pub struct Pbkdf2(AlgorithmIdentifier);
+
+impl Default for Pbkdf2 {
+    fn default() -> Self {
+        Self::new_sha256()
+    }
+}
+
+impl Pbkdf2 {
+    // PBKDF2 with a fresh salt and 2048 iterations. the keyLength is left out,
+    // the AES-256 of `AesCbcDataEncryptor` implies the 32 bytes it needs
+    fn with_prf(prf: AlgorithmIdentifier) -> Self {
         Self(AlgorithmIdentifier::Pbkdf2(Pbkdf2Params {
-            salt: Pbkdf2Salt::Specified(rand::<16>().unwrap().to_vec()),
+            salt: Pbkdf2Salt::Specified(Self::generate_salt().unwrap()),
             iteration_count: 2048,
             key_length: None,
-            prf: Box::new(AlgorithmIdentifier::HmacWithSha256(None)),
+            prf: Box::new(prf),
         }))
     }
+    fn with_iterations(mut self, iterations: u64) -> Self {
+        if let AlgorithmIdentifier::Pbkdf2(params) = &mut self.0 {
+            params.iteration_count = iterations;
+        }
+        self
+    }
+    /// PBKDF2 with HMAC-SHA1 as the PRF, for readers without SHA-2 support.
+    ///
+    /// Like the other constructors it leaves the optional keyLength out, as
+    /// OpenSSL does: the key size follows from the encryption scheme, the
+    /// 32 bytes of the AES-256-CBC `AesCbcDataEncryptor` writes.
+    pub fn new_sha1() -> Self {
+        Self::with_prf(AlgorithmIdentifier::HmacWithSha1(None))
+    }
+    /// PBKDF2 with HMAC-SHA256 as the PRF, the default.
+    pub fn new_sha256() -> Self {
+        Self::with_prf(AlgorithmIdentifier::HmacWithSha256(None))
+    }
+    /// PBKDF2 with HMAC-SHA512 as the PRF.
+    pub fn new_sha512() -> Self {
+        Self::with_prf(AlgorithmIdentifier::HmacWithSha512(None))
+    }
+    /// Iteration count that makes PBKDF2-HMAC-SHA256 take about `target` on
+    /// this machine, measured with a short probe and scaled linearly.
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    pub fn calibrate(target: std::time::Duration) -> u32 {
+        let mut iterations = 1024u32;
+        let mut key = [0u8; 32];
+        loop {
+            let start = std::time::Instant::now();
+            pbkdf2::pbkdf2_hmac::<Sha256>(b"password", &[0; 16], iterations, &mut key);
+            let elapsed = start.elapsed();
+            //probes under 10ms are too noisy to scale from
+            if elapsed.as_millis() >= 10 || iterations > u32::MAX / 2 {
+                let scale = target.as_secs_f64() / elapsed.as_secs_f64().max(f64::EPSILON);
+                return (iterations as f64 * scale).clamp(1.0, u32::MAX as f64) as u32;
+            }
+            iterations *= 2;
+        }
+    }
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+#[test]
+fn test_pbkdf2_calibrate() {
+    use std::time::Duration;
+    let short = Pbkdf2::calibrate(Duration::from_millis(1));
+    let long = Pbkdf2::calibrate(Duration::from_millis(100));
+    assert!(short >= 1);
+    assert!(long > short);
 }
 
 impl KeyDeriver for Pbkdf2 {
+    // at least 128 bits as recommended by NIST SP 800-132
+    const SALT_LEN: usize = 16;
+
     fn derive_key(&self, password: &[u8]) -> Option<Vec<u8>> {
         let AlgorithmIdentifier::Pbkdf2(params) = &self.0 else {
             return None;
@@ -692,17 +2414,21 @@ impl KeyDeriver for Pbkdf2 {
         let Pbkdf2Salt::Specified(salt) = &params.salt else {
             return None;
         };
+        let iterations = u32::try_from(params.iteration_count).ok()?;
+        if iterations == 0 {
+            return None;
+        }
         let mut key = vec![0; params.key_length.unwrap_or(32) as usize];
         match params.prf.as_ref() {
             AlgorithmIdentifier::HmacWithSha1(_) => {
-                pbkdf2::pbkdf2_hmac::<Sha1>(password, salt, params.iteration_count as u32, &mut key)
+                pbkdf2::pbkdf2_hmac::<Sha1>(password, salt, iterations, &mut key)
+            }
+            AlgorithmIdentifier::HmacWithSha256(_) => {
+                pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, &mut key)
+            }
+            AlgorithmIdentifier::HmacWithSha512(_) => {
+                pbkdf2::pbkdf2_hmac::<Sha512>(password, salt, iterations, &mut key)
             }
-            AlgorithmIdentifier::HmacWithSha256(_) => pbkdf2::pbkdf2_hmac::<Sha256>(
-                password,
-                salt,
-                params.iteration_count as u32,
-                &mut key,
-            ),
             _ => return None,
         }
         Some(key)
@@ -717,9 +2443,14 @@ impl KeyDeriver for Pbkdf2 {
     }
 }
 impl DataEncryptor for AesCbcDataEncryptor {
+    const SALT_LEN: usize = 0;
+    // the AES block size
+    const IV_LEN: usize = 16;
+
     fn new() -> impl DataEncryptor {
-        let salt = rand::<16>().unwrap().to_vec();
-        Self { iv: salt }
+        Self {
+            iv: Self::generate_iv().unwrap(),
+        }
     }
     fn encrypt_keybag_key_deriver(
         &self,
@@ -728,6 +2459,10 @@ impl DataEncryptor for AesCbcDataEncryptor {
         key_deriver: &impl KeyDeriver,
     ) -> Option<SafeBagKind> {
         let key = key_deriver.derive_key(password)?;
+        //a keyLength set on the key deriver has to fit AES-256
+        if key.len() != 32 {
+            return None;
+        }
         let cbc = Aes256CbcEnc::new(key.as_slice().into(), self.iv.as_slice().into());
         let encrypted_data = cbc.encrypt_padded_vec_mut::<Pkcs7>(data);
         Some(SafeBagKind::Pkcs8ShroudedKeyBag(EncryptedPrivateKeyInfo {
@@ -746,6 +2481,10 @@ impl DataEncryptor for AesCbcDataEncryptor {
         key_deriver: &impl KeyDeriver,
     ) -> Option<EncryptedContentInfo> {
         let key = key_deriver.derive_key(password)?;
+        //a keyLength set on the key deriver has to fit AES-256
+        if key.len() != 32 {
+            return None;
+        }
         let cbc = Aes256CbcEnc::new(key.as_slice().into(), self.iv.as_slice().into());
         let encrypted_content = cbc.encrypt_padded_vec_mut::<Pkcs7>(data);
         Some(EncryptedContentInfo {
@@ -763,7 +2502,7 @@ impl Default for PbeWithShaAnd40BitRc2CbcEncryptKeyDeriver {
     fn default() -> Self {
         Self(AlgorithmIdentifier::PbewithSHAAnd40BitRC2CBC(
             Pkcs12PbeParams {
-                salt: rand::<8>().unwrap().to_vec(),
+                salt: Self::generate_salt().unwrap(),
                 iterations: ITERATIONS,
             },
         ))
@@ -772,6 +2511,9 @@ impl Default for PbeWithShaAnd40BitRc2CbcEncryptKeyDeriver {
 struct PbeWithShaAnd40BitRc2CbcEncryptor;
 
 impl KeyDeriver for PbeWithShaAnd40BitRc2CbcEncryptKeyDeriver {
+    // the 8 byte salt of RFC 7292 appendix C
+    const SALT_LEN: usize = 8;
+
     fn derive_key(&self, _password: &[u8]) -> Option<Vec<u8>> {
         None
     }
@@ -785,6 +2527,85 @@ impl KeyDeriver for PbeWithShaAnd40BitRc2CbcEncryptKeyDeriver {
     }
 }
 impl DataEncryptor for PbeWithShaAnd40BitRc2CbcEncryptor {
+    // the 8 byte salt of RFC 7292 appendix C, key and IV both come from the PKCS#12 KDF
+    const SALT_LEN: usize = 8;
+    const IV_LEN: usize = 0;
+
+    fn encrypt_keybag_key_deriver(
+        &self,
+        data: &[u8],
+        password: &[u8],
+        key_deriver: &impl KeyDeriver,
+    ) -> Option<SafeBagKind> {
+        // keys are shrouded with 3DES, like `openssl pkcs12 -export` before 3.0
+        PbeWithShaAnd3KeyTripleDesCbcEncryptor.encrypt_keybag_key_deriver(
+            data,
+            password,
+            key_deriver,
+        )
+    }
+
+    fn encrypt_key_deriver(
+        &self,
+        data: &[u8],
+        password: &[u8],
+        _key_deriver: &impl KeyDeriver,
+    ) -> Option<EncryptedContentInfo> {
+        let password = std::str::from_utf8(password).ok()?;
+        let password = bmp_string(password);
+        let salt = Self::generate_salt()?;
+        let encrypted_content =
+            pbe_with_sha_and40_bit_rc2_cbc_encrypt::<Sha1>(data, &password, &salt, ITERATIONS)?;
+        let content_encryption_algorithm =
+            AlgorithmIdentifier::PbewithSHAAnd40BitRC2CBC(Pkcs12PbeParams {
+                salt,
+                iterations: ITERATIONS,
+            });
+        Some(EncryptedContentInfo {
+            content_encryption_algorithm,
+            encrypted_content,
+        })
+    }
+
+    fn new() -> impl DataEncryptor {
+        Self {}
+    }
+}
+
+struct PbeWithShaAnd3KeyTripleDesCbcEncryptKeyDeriver(AlgorithmIdentifier);
+impl Default for PbeWithShaAnd3KeyTripleDesCbcEncryptKeyDeriver {
+    fn default() -> Self {
+        Self(AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(
+            Pkcs12PbeParams {
+                salt: Self::generate_salt().unwrap(),
+                iterations: ITERATIONS,
+            },
+        ))
+    }
+}
+struct PbeWithShaAnd3KeyTripleDesCbcEncryptor;
+
+impl KeyDeriver for PbeWithShaAnd3KeyTripleDesCbcEncryptKeyDeriver {
+    // the 8 byte salt of RFC 7292 appendix C
+    const SALT_LEN: usize = 8;
+
+    fn derive_key(&self, _password: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn get_algorithm(&self) -> AlgorithmIdentifier {
+        self.0.clone()
+    }
+
+    fn new(alg: AlgorithmIdentifier) -> impl KeyDeriver {
+        Self(alg)
+    }
+}
+impl DataEncryptor for PbeWithShaAnd3KeyTripleDesCbcEncryptor {
+    // the 8 byte salt of RFC 7292 appendix C, key and IV both come from the PKCS#12 KDF
+    const SALT_LEN: usize = 8;
+    const IV_LEN: usize = 0;
+
     fn encrypt_keybag_key_deriver(
         &self,
         data: &[u8],
@@ -793,7 +2614,7 @@ impl DataEncryptor for PbeWithShaAnd40BitRc2CbcEncryptor {
     ) -> Option<SafeBagKind> {
         let password = std::str::from_utf8(password).ok()?;
         let password = bmp_string(password);
-        let salt = rand::<8>()?.to_vec();
+        let salt = Self::generate_salt()?;
         let encrypted_data =
             pbe_with_sha_and3_key_triple_des_cbc_encrypt(data, &password, &salt, ITERATIONS)?;
         let param = Pkcs12PbeParams {
@@ -815,11 +2636,11 @@ impl DataEncryptor for PbeWithShaAnd40BitRc2CbcEncryptor {
     ) -> Option<EncryptedContentInfo> {
         let password = std::str::from_utf8(password).ok()?;
         let password = bmp_string(password);
-        let salt = rand::<8>()?.to_vec();
+        let salt = Self::generate_salt()?;
         let encrypted_content =
-            pbe_with_sha_and40_bit_rc2_cbc_encrypt::<Sha1>(data, &password, &salt, ITERATIONS)?;
+            pbe_with_sha_and3_key_triple_des_cbc_encrypt(data, &password, &salt, ITERATIONS)?;
         let content_encryption_algorithm =
-            AlgorithmIdentifier::PbewithSHAAnd40BitRC2CBC(Pkcs12PbeParams {
+            AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(Pkcs12PbeParams {
                 salt,
                 iterations: ITERATIONS,
             });
@@ -834,73 +2655,1822 @@ impl DataEncryptor for PbeWithShaAnd40BitRc2CbcEncryptor {
     }
 }
 
-#[derive(Debug)]
-pub struct PFX {
-    pub version: u8,
-    pub auth_safe: ContentInfo,
-    pub mac_data: Option<MacData>,
+#[test]
+fn test_encryptor_salt_iv_lengths() {
+    let aes = AesCbcDataEncryptor::new()
+        .encrypt::<Pbkdf2>(b"data", b"changeit")
+        .unwrap();
+    let AlgorithmIdentifier::Pbes2(params) = &aes.content_encryption_algorithm else {
+        panic!("expected PBES2");
+    };
+    let AlgorithmIdentifier::AesCbcPad(iv) = params.encryption_scheme.as_ref() else {
+        panic!("expected AES-CBC");
+    };
+    assert_eq!(iv.len(), AesCbcDataEncryptor::IV_LEN);
+    let info = aes.content_encryption_algorithm.kdf_info().unwrap();
+    assert_eq!(info.salt.len(), <Pbkdf2 as KeyDeriver>::SALT_LEN);
+
+    let legacy = PbeWithShaAnd40BitRc2CbcEncryptor::new()
+        .encrypt::<PbeWithShaAnd40BitRc2CbcEncryptKeyDeriver>(b"data", b"changeit")
+        .unwrap();
+    let info = legacy.content_encryption_algorithm.kdf_info().unwrap();
+    assert_eq!(info.salt.len(), PbeWithShaAnd40BitRc2CbcEncryptor::SALT_LEN);
 }
 
-impl PFX {
-    pub fn new<Encryptor: DataEncryptor, KDF: KeyDeriver>(
-        cert_der: &[u8],
-        key_der: &[u8],
-        ca_der: Option<&[u8]>,
-        password: &str,
-        name: &str,
-    ) -> Option<PFX> {
-        let mut cas = vec![];
-        if let Some(ca) = ca_der {
-            cas.push(ca);
-        }
-        Self::new_with_cas::<Encryptor, KDF>(cert_der, key_der, &cas, password, name)
-    }
-    pub fn new_with_cas<Encryptor: DataEncryptor, KDF: KeyDeriver>(
-        cert_der: &[u8],
-        key_der: &[u8],
-        ca_der_list: &[&[u8]],
-        password: &str,
-        name: &str,
-    ) -> Option<PFX> {
-        let data_encryptor = Encryptor::new();
-        let key_bag_inner = data_encryptor.encrypt_keybag::<KDF>(key_der, password.as_bytes())?;
-        let friendly_name = PKCS12Attribute::FriendlyName(name.to_owned());
-        let local_key_id = PKCS12Attribute::LocalKeyId(sha::<Sha1>(cert_der));
-        let key_bag = SafeBag {
-            bag: key_bag_inner,
-            attributes: vec![friendly_name.clone(), local_key_id.clone()],
+#[test]
+fn test_pbkdf2_key_length() {
+    let key_deriver = |key_length| {
+        Pbkdf2::new(AlgorithmIdentifier::Pbkdf2(Pbkdf2Params {
+            salt: Pbkdf2Salt::Specified(vec![7; 16]),
+            iteration_count: 2048,
+            key_length,
+            prf: Box::new(AlgorithmIdentifier::HmacWithSha256(None)),
+        }))
+    };
+    let encryptor = AesCbcDataEncryptor::new();
+    let encrypted = encryptor
+        .encrypt_key_deriver(b"data", b"changeit", &key_deriver(Some(32)))
+        .unwrap();
+    let AlgorithmIdentifier::Pbes2(params) = &encrypted.content_encryption_algorithm else {
+        panic!("expected PBES2");
+    };
+    assert!(params.key_length_matches());
+    let der = yasna::construct_der(|w| encrypted.write(w));
+    let parsed = yasna::parse_der(&der, EncryptedContentInfo::parse).unwrap();
+    assert_eq!(parsed, encrypted);
+    assert_eq!(parsed.data(b"changeit").unwrap(), b"data");
+
+    assert!(encryptor
+        .encrypt_key_deriver(b"data", b"changeit", &key_deriver(Some(16)))
+        .is_none());
+
+    let mut contradicting = params.clone();
+    let AlgorithmIdentifier::Pbkdf2(kdf) = contradicting.key_derivation_function.as_mut() else {
+        panic!("expected PBKDF2");
+    };
+    kdf.key_length = Some(16);
+    assert!(!contradicting.key_length_matches());
+    let alg = AlgorithmIdentifier::Pbes2(contradicting);
+    assert!(matches!(
+        alg.decrypt_pbe(&encrypted.encrypted_content, b"changeit"),
+        Err(Pkcs12Error::InvalidKdfParams)
+    ));
+}
+
+#[test]
+fn test_pbkdf2_prf_constructors() {
+    let bags = [SafeBag::cert_x509(&[1, 2, 3])];
+    for (key_deriver, prf) in [
+        (Pbkdf2::new_sha1(), AlgorithmIdentifier::HmacWithSha1(None)),
+        (
+            Pbkdf2::new_sha256(),
+            AlgorithmIdentifier::HmacWithSha256(None),
+        ),
+        (
+            Pbkdf2::new_sha512(),
+            AlgorithmIdentifier::HmacWithSha512(None),
+        ),
+    ] {
+        let encryptor = AesCbcDataEncryptor::new();
+        let encrypted =
+            EncryptedData::from_safe_bags_with(&encryptor, &key_deriver, &bags, b"changeit")
+                .unwrap();
+        let der = yasna::construct_der(|w| encrypted.write(w));
+        let parsed = yasna::parse_der(&der, EncryptedData::parse).unwrap();
+        let AlgorithmIdentifier::Pbes2(params) =
+            &parsed.encrypted_content_info.content_encryption_algorithm
+        else {
+            panic!("expected PBES2");
         };
-        let cert_bag_inner = SafeBagKind::CertBag(CertBag::X509(cert_der.to_owned()));
-        let cert_bag = SafeBag {
-            bag: cert_bag_inner,
-            attributes: vec![friendly_name, local_key_id],
+        let AlgorithmIdentifier::Pbkdf2(kdf) = params.key_derivation_function.as_ref() else {
+            panic!("expected PBKDF2");
         };
-        let mut cert_bags = vec![cert_bag];
+        assert_eq!(*kdf.prf, prf);
+        assert_eq!(kdf.key_length, None);
+        assert_eq!(parsed.data(b"changeit").unwrap(), safe_contents_der(&bags));
+    }
+    let AlgorithmIdentifier::Pbkdf2(kdf) = Pbkdf2::default().get_algorithm() else {
+        panic!("expected PBKDF2");
+    };
+    assert_eq!(*kdf.prf, AlgorithmIdentifier::HmacWithSha256(None));
+}
+
+#[test]
+fn test_read_pbkdf2_prf_without_key_length() {
+    // `openssl pkcs12 -export` writes a prf but no keyLength
+    let pfx = PFX::parse(&fs::read("pbkdf2_prf_no_key_length.p12").unwrap()).unwrap();
+    let null = yasna::construct_der(|w| w.write_null());
+    for content in pfx.content_infos().unwrap() {
+        if let ContentInfoSummary::EncryptedData(AlgorithmIdentifier::Pbes2(params)) = content {
+            let AlgorithmIdentifier::Pbkdf2(kdf) = *params.key_derivation_function else {
+                panic!("expected PBKDF2");
+            };
+            assert_eq!(kdf.key_length, None);
+            assert_eq!(
+                *kdf.prf,
+                AlgorithmIdentifier::HmacWithSha256(Some(null.clone()))
+            );
+        }
+    }
+    assert!(pfx.verify_mac("changeit"));
+    assert_eq!(
+        pfx.cert_x509_bags("changeit").unwrap(),
+        vec![fs::read("clientcert.der").unwrap()]
+    );
+    assert_eq!(
+        pfx.key_bags("changeit").unwrap(),
+        vec![fs::read("clientkey.der").unwrap()]
+    );
+
+    // and the other way around, a keyLength with the prf left at its default
+    let params = Pbkdf2Params {
+        salt: Pbkdf2Salt::Specified(vec![7; 16]),
+        iteration_count: 2048,
+        key_length: Some(32),
+        prf: Box::new(AlgorithmIdentifier::HmacWithSha1(None)),
+    };
+    let der = yasna::construct_der(|w| params.write(w));
+    assert_eq!(yasna::parse_der(&der, Pbkdf2Params::parse).unwrap(), params);
+}
+
+#[test]
+fn test_encrypted_content_info_fixed_iv() {
+    let bags = [SafeBag {
+        bag: SafeBagKind::CertBag(CertBag::X509(vec![1, 2, 3])),
+        attributes: vec![],
+        raw_der: None,
+    }];
+    let encrypt = || {
+        let key_deriver = Pbkdf2::new(AlgorithmIdentifier::Pbkdf2(Pbkdf2Params {
+            salt: Pbkdf2Salt::Specified(vec![7; 16]),
+            iteration_count: 2048,
+            key_length: None,
+            prf: Box::new(AlgorithmIdentifier::HmacWithSha256(None)),
+        }));
+        let encryptor = AesCbcDataEncryptor::with_iv([9; 16]);
+        EncryptedData::from_safe_bags_with(&encryptor, &key_deriver, &bags, b"changeit").unwrap()
+    };
+    let first = encrypt();
+    assert_eq!(first, encrypt());
+    assert_eq!(first.data(b"changeit").unwrap(), safe_contents_der(&bags));
+}
+
+/// Caps for parsing untrusted input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Deepest nesting of constructed ASN.1 elements.
+    pub max_depth: usize,
+    /// Number of bags in all safe contents together.
+    pub max_bags: usize,
+    /// Size of the input, and of the input together with all decrypted contents.
+    pub max_total_len: usize,
+    /// Iteration count of the MAC and of every key derivation.
+    pub max_iterations: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_depth: 32,
+            max_bags: 10_000,
+            max_total_len: 16 * 1024 * 1024,
+            max_iterations: 10_000_000,
+        }
+    }
+}
+
+impl Limits {
+    fn check(&self, ber: &[u8], total_len: usize) -> Result<(), Pkcs12Error> {
+        self.check_len(total_len)?;
+        self.check_depth(ber_depth(ber)?)
+    }
+    fn check_len(&self, total_len: usize) -> Result<(), Pkcs12Error> {
+        if total_len > self.max_total_len {
+            return Err(Pkcs12Error::LimitExceeded);
+        }
+        Ok(())
+    }
+    fn check_depth(&self, depth: usize) -> Result<(), Pkcs12Error> {
+        if depth > self.max_depth {
+            return Err(Pkcs12Error::LimitExceeded);
+        }
+        Ok(())
+    }
+    fn check_iterations(&self, iterations: Option<u64>) -> Result<(), Pkcs12Error> {
+        if iterations.map_or(false, |iterations| iterations > self.max_iterations) {
+            return Err(Pkcs12Error::LimitExceeded);
+        }
+        Ok(())
+    }
+    // the key derivation of an encrypted content info, before decrypting it
+    fn check_content(&self, content: &ContentInfo) -> Result<(), Pkcs12Error> {
+        if let ContentInfo::EncryptedData(encrypted) = content {
+            let algorithm = &encrypted
+                .encrypted_content_info
+                .content_encryption_algorithm;
+            self.check_iterations(algorithm.iteration_count())?;
+        }
+        Ok(())
+    }
+    // the key derivation of the shrouded keys, before anyone decrypts them
+    fn check_bags(&self, safe_bags: &[SafeBag]) -> Result<(), Pkcs12Error> {
+        for safe_bag in safe_bags {
+            if let SafeBagKind::Pkcs8ShroudedKeyBag(key) = &safe_bag.bag {
+                self.check_iterations(key.encryption_algorithm.iteration_count())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// yasna only reports extra data as such, this tells how much there is. the
+// input is walked again only after yasna found extra data
+fn trailing_data_error(bytes: &[u8], err: ASN1Error) -> Pkcs12Error {
+    if err.kind() == ASN1ErrorKind::Extra {
+        if let Ok(len) = ber_element_len(bytes) {
+            if len < bytes.len() {
+                return Pkcs12Error::TrailingData(bytes.len() - len);
+            }
+        }
+    }
+    err.into()
+}
+
+// deepest nesting of constructed elements, walked without recursion
+fn ber_depth(ber: &[u8]) -> Result<usize, ASN1Error> {
+    ber_walk(ber, false).map(|(depth, _)| depth)
+}
+
+// length of the first element, header included
+fn ber_element_len(ber: &[u8]) -> Result<usize, ASN1Error> {
+    ber_walk(ber, true).map(|(_, len)| len)
+}
+
+// returns the depth and the offset the walk stopped at
+fn ber_walk(ber: &[u8], first_only: bool) -> Result<(usize, usize), ASN1Error> {
+    let eof = || ASN1Error::new(ASN1ErrorKind::Eof);
+    let invalid = || ASN1Error::new(ASN1ErrorKind::Invalid);
+    // end offsets of the enclosing elements, `None` for indefinite lengths
+    let mut open: Vec<Option<usize>> = vec![];
+    let mut depth = 0;
+    let mut pos = 0;
+    while pos < ber.len() || !open.is_empty() {
+        if first_only && pos > 0 && open.is_empty() {
+            break;
+        }
+        match open.last() {
+            Some(Some(end)) if pos == *end => {
+                open.pop();
+                continue;
+            }
+            Some(None) if ber.get(pos..pos + 2) == Some(&[0, 0]) => {
+                pos += 2;
+                open.pop();
+                continue;
+            }
+            _ => {}
+        }
+        let identifier = *ber.get(pos).ok_or_else(eof)?;
+        pos += 1;
+        if identifier & 0x1f == 0x1f {
+            while ber.get(pos).ok_or_else(eof)? & 0x80 != 0 {
+                pos += 1;
+            }
+            pos += 1;
+        }
+        let first = *ber.get(pos).ok_or_else(eof)?;
+        pos += 1;
+        let end = if first == 0x80 {
+            None
+        } else if first < 0x80 {
+            Some(pos + first as usize)
+        } else {
+            let n = (first & 0x7f) as usize;
+            if n > std::mem::size_of::<usize>() {
+                return Err(invalid());
+            }
+            let mut length = 0usize;
+            for _ in 0..n {
+                length = (length << 8) | *ber.get(pos).ok_or_else(eof)? as usize;
+                pos += 1;
+            }
+            Some(pos.checked_add(length).ok_or_else(invalid)?)
+        };
+        if let (Some(end), Some(Some(outer_end))) = (end, open.last()) {
+            if end > *outer_end {
+                return Err(invalid());
+            }
+        }
+        if end.map_or(false, |end| end > ber.len()) {
+            return Err(eof());
+        }
+        if identifier & 0x20 != 0 {
+            open.push(end);
+            depth = depth.max(open.len());
+        } else {
+            pos = end.ok_or_else(invalid)?;
+        }
+    }
+    Ok((depth, pos))
+}
+
+#[test]
+fn test_ber_depth() {
+    assert_eq!(ber_depth(&[0x04, 0x01, 0x00]).unwrap(), 0);
+    assert_eq!(ber_depth(&[0x30, 0x03, 0x04, 0x01, 0x00]).unwrap(), 1);
+    assert_eq!(
+        ber_depth(&[0x30, 0x80, 0x30, 0x80, 0x02, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00]).unwrap(),
+        2
+    );
+    assert!(ber_depth(&[0x30, 0x05, 0x04, 0x01, 0x00]).is_err());
+    assert!(ber_depth(&[0x30, 0x02, 0x04, 0x02, 0x00, 0x00]).is_err());
+    assert!(ber_depth(&[0x30, 0x80, 0x04, 0x01, 0x00]).is_err());
+    assert_eq!(
+        ber_element_len(&[0x30, 0x03, 0x04, 0x01, 0x00, 0xff]).unwrap(),
+        5
+    );
+    assert_eq!(
+        ber_element_len(&[0x30, 0x80, 0x04, 0x01, 0x00, 0x00, 0x00, 0x01]).unwrap(),
+        7
+    );
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityMode {
+    // a MacData keyed with the password
+    Password,
+    // the auth_safe is a signedData
+    PublicKey,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacStatus {
+    Verified,
+    // no MacData, integrity may come from a signature or not at all
+    NoMac,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PFX {
+    pub version: u8,
+    pub auth_safe: ContentInfo,
+    pub mac_data: Option<MacData>,
+}
+
+impl PFX {
+    pub fn new<Encryptor: DataEncryptor, KDF: KeyDeriver>(
+        cert_der: &[u8],
+        key_der: &[u8],
+        ca_der: Option<&[u8]>,
+        password: &str,
+        name: &str,
+    ) -> Option<PFX> {
+        let mut cas = vec![];
+        if let Some(ca) = ca_der {
+            cas.push(ca);
+        }
+        Self::new_with_cas::<Encryptor, KDF>(cert_der, key_der, &cas, password, name)
+    }
+    pub fn new_with_cas<Encryptor: DataEncryptor, KDF: KeyDeriver>(
+        cert_der: &[u8],
+        key_der: &[u8],
+        ca_der_list: &[&[u8]],
+        password: &str,
+        name: &str,
+    ) -> Option<PFX> {
+        Self::identity_builder::<Encryptor, KDF>(cert_der, key_der, ca_der_list, name)
+            .build(password)
+    }
+    /// Like `new_with_cas`, giving each CA certificate bag an optional friendly name.
+    pub fn new_with_named_cas<Encryptor: DataEncryptor, KDF: KeyDeriver>(
+        cert_der: &[u8],
+        key_der: &[u8],
+        cas: &[(&[u8], Option<&str>)],
+        password: &str,
+        name: &str,
+    ) -> Option<PFX> {
+        let mut builder = PfxBuilder::new()
+            .encryption::<Encryptor, KDF>()
+            .add_identity(cert_der, key_der, name);
+        for (ca_der, ca_name) in cas {
+            builder = match ca_name {
+                Some(ca_name) => builder.add_named_ca(ca_der, ca_name),
+                None => builder.add_ca(ca_der),
+            };
+        }
+        builder.build(password)
+    }
+    /// Same as `new` but leaves out the MacData, the result isn't integrity protected.
+    pub fn new_without_mac<Encryptor: DataEncryptor, KDF: KeyDeriver>(
+        cert_der: &[u8],
+        key_der: &[u8],
+        ca_der: Option<&[u8]>,
+        password: &str,
+        name: &str,
+    ) -> Option<PFX> {
+        let cas: Vec<&[u8]> = ca_der.into_iter().collect();
+        let contents = Self::identity_builder::<Encryptor, KDF>(cert_der, key_der, &cas, name)
+            .build_auth_safe(password)?;
+        Some(PFX {
+            version: 3,
+            auth_safe: ContentInfo::Data(contents),
+            mac_data: None,
+        })
+    }
+    fn identity_builder<Encryptor: DataEncryptor, KDF: KeyDeriver>(
+        cert_der: &[u8],
+        key_der: &[u8],
+        ca_der_list: &[&[u8]],
+        name: &str,
+    ) -> PfxBuilder {
+        let mut builder = PfxBuilder::new()
+            .encryption::<Encryptor, KDF>()
+            .add_identity(cert_der, key_der, name);
         for ca in ca_der_list {
-            cert_bags.push(SafeBag {
-                bag: SafeBagKind::CertBag(CertBag::X509((*ca).to_owned())),
+            builder = builder.add_ca(ca);
+        }
+        builder
+    }
+
+    /// Like `parse` for untrusted input, the limits on decrypted contents are
+    /// applied by `bags_with_limits`. The iteration counts of the MAC and of
+    /// everything readable without the password are checked here.
+    pub fn parse_with_limits(bytes: &[u8], limits: &Limits) -> Result<PFX, Pkcs12Error> {
+        limits.check_len(bytes.len())?;
+        // one walk for both the trailing data and the nesting depth
+        let (depth, len) = ber_walk(bytes, true)?;
+        if len < bytes.len() {
+            return Err(Pkcs12Error::TrailingData(bytes.len() - len));
+        }
+        limits.check_depth(depth)?;
+        let pfx = Self::parse(bytes)?;
+        if let Some(mac_data) = &pfx.mac_data {
+            limits.check_iterations(Some(mac_data.iterations.into()))?;
+        }
+        if let ContentInfo::Data(data) = &pfx.auth_safe {
+            let contents = yasna::parse_ber(data, |r| r.collect_sequence_of(ContentInfo::parse))?;
+            for content in contents.iter() {
+                limits.check_content(content)?;
+                if let ContentInfo::Data(data) = content {
+                    let safe_bags =
+                        yasna::parse_ber(data, |r| r.collect_sequence_of(SafeBag::parse))?;
+                    limits.check_bags(&safe_bags)?;
+                }
+            }
+        }
+        Ok(pfx)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<PFX, Pkcs12Error> {
+        let (version, auth_safe, mac_data) = yasna::parse_ber(bytes, |r| {
+            r.read_sequence(|r| {
+                //version 3 is the only one, but some writers put 0 or leave it out
+                let version = r.read_optional(read_lenient_u32)?.unwrap_or(3);
+                let auth_safe = ContentInfo::parse(r.next())?;
+                let mac_data = r.read_optional(MacData::parse_checked)?;
+                Ok((version, auth_safe, mac_data))
+            })
+        })
+        .map_err(|err| trailing_data_error(bytes, err))?;
+        if version > 3 {
+            return Err(Pkcs12Error::UnsupportedVersion(version));
+        }
+        let mac_data = mac_data
+            .map(|mac_data| mac_data.ok_or(Pkcs12Error::InvalidKdfParams))
+            .transpose()?;
+        Ok(PFX {
+            version: version as u8,
+            auth_safe,
+            mac_data,
+        })
+    }
+    /// Reads and parses a file, the outer error is from reading it.
+    #[cfg(feature = "std")]
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Result<PFX, Pkcs12Error>> {
+        let bytes = fs::read(path)?;
+        Ok(PFX::parse(&bytes))
+    }
+    #[cfg(feature = "std")]
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.to_der())
+    }
+
+    pub fn write(&self, w: DERWriter) {
+        w.write_sequence(|w| {
+            w.next().write_u8(self.version);
+            self.auth_safe.write(w.next());
+            if let Some(mac_data) = &self.mac_data {
+                mac_data.write(w.next())
+            }
+        })
+    }
+
+    /// Encodes the PFX as DER.
+    ///
+    /// The authenticated safe of a `Data` auth_safe, `OtherContext` contents,
+    /// `OtherBagKind` values and unchanged parsed bags (`SafeBag::raw_der`)
+    /// are written back as the bytes that were parsed, so a DER encoded file
+    /// round-trips exactly as long as the following parts of it were in
+    /// their canonical form:
+    /// - BER input (indefinite lengths, constructed strings) is written as DER
+    /// - an `EncryptedContentInfo` content type is always written as `data`
+    /// - an empty attribute SET of a bag built in code or changed since
+    ///   parsing is left out
+    pub fn to_der(&self) -> Vec<u8> {
+        yasna::construct_der(|w| self.write(w))
+    }
+    pub fn bags(&self, password: &str) -> Result<Vec<SafeBag>, Pkcs12Error> {
+        self.collect_bags(password, None)
+    }
+    /// Calls `f` with every bag, decrypting the content infos one at a time.
+    ///
+    /// Stops at the first `ControlFlow::Break` and returns its value, so the
+    /// content infos after the bag looked for are neither decrypted nor kept.
+    pub fn visit_bags<B>(
+        &self,
+        password: &str,
+        mut f: impl FnMut(&SafeBag) -> ControlFlow<B>,
+    ) -> Result<Option<B>, Pkcs12Error> {
+        let password = password.as_bytes();
+        let data = self.auth_safe.data(password)?;
+        let contents = yasna::parse_ber(&data, |r| r.collect_sequence_of(ContentInfo::parse))?;
+        for content in contents.iter() {
+            let data = content.data(password)?;
+            let safe_bags = yasna::parse_ber(&data, |r| r.collect_sequence_of(SafeBag::parse))?;
+            for safe_bag in safe_bags.iter() {
+                if let ControlFlow::Break(value) = f(safe_bag) {
+                    return Ok(Some(value));
+                }
+            }
+        }
+        Ok(None)
+    }
+    /// Like `bags`, failing with `Pkcs12Error::LimitExceeded` when the decrypted
+    /// contents exceed `limits`.
+    pub fn bags_with_limits(
+        &self,
+        password: &str,
+        limits: &Limits,
+    ) -> Result<Vec<SafeBag>, Pkcs12Error> {
+        self.collect_bags(password, Some(limits))
+    }
+    fn collect_bags(
+        &self,
+        password: &str,
+        limits: Option<&Limits>,
+    ) -> Result<Vec<SafeBag>, Pkcs12Error> {
+        let password = password.as_bytes();
+
+        let data = self.auth_safe.data(password)?;
+        let mut total_len = data.len();
+        if let Some(limits) = limits {
+            limits.check(&data, total_len)?;
+        }
+        let contents = yasna::parse_ber(&data, |r| r.collect_sequence_of(ContentInfo::parse))?;
+
+        let mut result = vec![];
+        for content in contents.iter() {
+            if let Some(limits) = limits {
+                limits.check_content(content)?;
+            }
+            let data = content.data(password)?;
+            total_len += data.len();
+            if let Some(limits) = limits {
+                limits.check(&data, total_len)?;
+            }
+
+            let safe_bags = yasna::parse_ber(&data, |r| r.collect_sequence_of(SafeBag::parse))?;
+            if let Some(limits) = limits {
+                limits.check_bags(&safe_bags)?;
+            }
+
+            result.extend(safe_bags);
+            if limits.map_or(false, |limits| result.len() > limits.max_bags) {
+                return Err(Pkcs12Error::LimitExceeded);
+            }
+        }
+        Ok(result)
+    }
+    /// DER-encoded EncryptedPrivateKeyInfo of the shrouded keys, without decrypting them.
+    /// Keys inside password encrypted contents are skipped.
+    pub fn encrypted_key_bags(&self) -> Result<Vec<Vec<u8>>, Pkcs12Error> {
+        let data = self.auth_safe.data(&[])?;
+        let contents = yasna::parse_ber(&data, |r| r.collect_sequence_of(ContentInfo::parse))?;
+
+        let mut result = vec![];
+        for content in contents.iter() {
+            let ContentInfo::Data(data) = content else {
+                continue;
+            };
+            let safe_bags = yasna::parse_ber(data, |r| r.collect_sequence_of(SafeBag::parse))?;
+            for safe_bag in safe_bags {
+                if let SafeBagKind::Pkcs8ShroudedKeyBag(key) = safe_bag.bag {
+                    result.push(key.to_der());
+                }
+            }
+        }
+        Ok(result)
+    }
+    /// The content infos of a plain authenticated safe, without decrypting
+    /// anything, e.g. to show how a file is protected before asking for the
+    /// password.
+    pub fn content_infos(&self) -> Result<Vec<ContentInfoSummary>, Pkcs12Error> {
+        let data = self.auth_safe.data(&[])?;
+        let contents = yasna::parse_ber(&data, |r| r.collect_sequence_of(ContentInfo::parse))?;
+        let mut result = vec![];
+        for content in contents {
+            result.push(match content {
+                ContentInfo::Data(data) => {
+                    let bags = yasna::parse_ber(&data, |r| r.collect_sequence_of(SafeBag::parse))?;
+                    ContentInfoSummary::Data { bags: bags.len() }
+                }
+                ContentInfo::EncryptedData(encrypted) => ContentInfoSummary::EncryptedData(
+                    encrypted
+                        .encrypted_content_info
+                        .content_encryption_algorithm,
+                ),
+                ContentInfo::OtherContext(other) => ContentInfoSummary::Other(other.content_type),
+            });
+        }
+        Ok(result)
+    }
+    /// Friendly names of the bags in unencrypted contents, e.g. the shrouded
+    /// key bags `PFX::new` writes, readable without the password.
+    ///
+    /// Names of bags inside password encrypted contents, usually the
+    /// certificates, are only available through `bags` with the password.
+    /// Contents that fail to parse are skipped.
+    pub fn friendly_names_plaintext(&self) -> Vec<String> {
+        let Ok(data) = self.auth_safe.data(&[]) else {
+            return vec![];
+        };
+        let Ok(contents) = yasna::parse_ber(&data, |r| r.collect_sequence_of(ContentInfo::parse))
+        else {
+            return vec![];
+        };
+        let mut result = vec![];
+        for content in contents.iter() {
+            let ContentInfo::Data(data) = content else {
+                continue;
+            };
+            let Ok(safe_bags) = yasna::parse_ber(data, |r| r.collect_sequence_of(SafeBag::parse))
+            else {
+                continue;
+            };
+            result.extend(safe_bags.iter().filter_map(SafeBag::friendly_name));
+        }
+        result
+    }
+    /// Runs `f` on the decrypted bags, lets the caller borrow from them instead of copying.
+    pub fn with_bags<F, R>(&self, password: &str, f: F) -> Result<R, Pkcs12Error>
+    where
+        F: FnOnce(&[SafeBag]) -> R,
+    {
+        let bags = self.bags(password)?;
+        Ok(f(&bags))
+    }
+    //DER-encoded X.509 certificate
+    pub fn cert_bags(&self, password: &str) -> Result<Vec<Vec<u8>>, Pkcs12Error> {
+        self.cert_x509_bags(password)
+    }
+    //DER-encoded X.509 certificate
+    pub fn cert_x509_bags(&self, password: &str) -> Result<Vec<Vec<u8>>, Pkcs12Error> {
+        let mut result = vec![];
+        for safe_bag in self.bags(password)? {
+            if let Some(cert) = safe_bag.bag.get_x509_cert() {
+                result.push(cert);
+            }
+        }
+        Ok(result)
+    }
+    /// The DER-encoded X.509 certificate paired with the private key.
+    ///
+    /// That is the certificate bag whose localKeyId equals the localKeyId of a
+    /// key bag. When no certificate is linked to a key this way, the first
+    /// certificate is returned instead, `None` only if there are no certificates.
+    pub fn leaf_cert(&self, password: &str) -> Result<Option<Vec<u8>>, Pkcs12Error> {
+        let bags = self.bags(password)?;
+        let key_ids: Vec<Vec<u8>> = bags
+            .iter()
+            .filter(|bag| {
+                matches!(
+                    bag.bag,
+                    SafeBagKind::Pkcs8ShroudedKeyBag(_) | SafeBagKind::KeyBag(_)
+                )
+            })
+            .filter_map(SafeBag::local_key_id)
+            .collect();
+        let certs = bags
+            .iter()
+            .filter_map(|bag| Some((bag.bag.get_x509_cert()?, bag.local_key_id())));
+        let mut first = None;
+        for (cert, local_key_id) in certs {
+            if matches!(&local_key_id, Some(id) if key_ids.contains(id)) {
+                return Ok(Some(cert));
+            }
+            first.get_or_insert(cert);
+        }
+        Ok(first)
+    }
+    /// Whether the issuer of a CRL bag is the subject of a certificate bag
+    /// in the same file.
+    ///
+    /// The names are compared as DER, the CRL signature isn't checked, so
+    /// this only tells that a bundled CRL is about one of the bundled
+    /// certificates' issuers.
+    #[cfg(feature = "x509")]
+    pub fn crl_covers(&self, password: &str) -> Result<bool, Pkcs12Error> {
+        let bags = self.bags(password)?;
+        let mut subjects = vec![];
+        for cert in bags.iter().filter_map(|bag| bag.bag.get_x509_cert()) {
+            subjects.push(x509::cert_subject(&cert)?);
+        }
+        for safe_bag in bags.iter() {
+            let SafeBagKind::OtherBagKind(other) = &safe_bag.bag else {
+                continue;
+            };
+            if other.bag_id != *OID_CRL_BAG {
+                continue;
+            }
+            if let Some(crl) = x509::crl_bag_x509_crl(&other.bag_value)? {
+                if subjects.contains(&x509::crl_issuer(&crl)?) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+    pub fn cert_sdsi_bags(&self, password: &str) -> Result<Vec<String>, Pkcs12Error> {
+        let mut result = vec![];
+        for safe_bag in self.bags(password)? {
+            if let Some(cert) = safe_bag.bag.get_sdsi_cert() {
+                result.push(cert);
+            }
+        }
+        Ok(result)
+    }
+    pub fn secret_entries(&self, password: &str) -> Result<Vec<SecretEntry>, Pkcs12Error> {
+        let mut result = vec![];
+        for safe_bag in self.bags(password)? {
+            let friendly_name = safe_bag.friendly_name();
+            if let SafeBagKind::SecretBag(secret) = safe_bag.bag {
+                result.push(SecretEntry {
+                    friendly_name,
+                    secret_type_id: secret.secret_type_id,
+                    value: secret.secret_value,
+                });
+            }
+        }
+        Ok(result)
+    }
+    /// Bags of a type this crate doesn't know, with their raw DER value; secret
+    /// bags are listed here as well, as they were before `SafeBagKind::SecretBag`.
+    pub fn other_bags(&self, password: &str) -> Result<Vec<OtherBag>, Pkcs12Error> {
+        let mut result = vec![];
+        for safe_bag in self.bags(password)? {
+            match safe_bag.bag {
+                SafeBagKind::OtherBagKind(other) => result.push(other),
+                SafeBagKind::SecretBag(secret) => result.push(OtherBag {
+                    bag_id: OID_SECRET_BAG.clone(),
+                    bag_value: yasna::construct_der(|w| secret.write(w)),
+                }),
+                _ => {}
+            }
+        }
+        Ok(result)
+    }
+    pub fn key_bags(&self, password: &str) -> Result<Vec<Vec<u8>>, Pkcs12Error> {
+        let bmp_password = password.as_bytes();
+        let mut result = vec![];
+        for safe_bag in self.bags(password)? {
+            if let Some(key) = safe_bag.bag.get_key(bmp_password) {
+                result.push(key);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Shrouded keys, each decrypted only on `KeyHandle::decrypt`.
+    pub fn key_bag_handles(&self, password: &str) -> Result<Vec<KeyHandle>, Pkcs12Error> {
+        let mut result = vec![];
+        for safe_bag in self.bags(password)? {
+            let friendly_name = safe_bag.friendly_name();
+            let local_key_id = safe_bag.local_key_id();
+            if let SafeBagKind::Pkcs8ShroudedKeyBag(key) = safe_bag.bag {
+                result.push(KeyHandle {
+                    friendly_name,
+                    local_key_id,
+                    key,
+                });
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn integrity_mode(&self) -> IntegrityMode {
+        if self.mac_data.is_some() {
+            return IntegrityMode::Password;
+        }
+        if self.auth_safe.oid() == *OID_SIGNED_DATA_CONTENT_TYPE {
+            return IntegrityMode::PublicKey;
+        }
+        IntegrityMode::None
+    }
+
+    /// The authenticated safe, the bytes covered by the MAC or signature.
+    pub fn auth_safe_der(&self, password: &str) -> Result<Vec<u8>, Pkcs12Error> {
+        self.auth_safe.data(password.as_bytes())
+    }
+
+    /// Checks the MacData, a PFX without one passes.
+    pub fn verify(&self, password: &str) -> Result<(), Pkcs12Error> {
+        let Some(mac_data) = &self.mac_data else {
+            return Ok(());
+        };
+        mac_data.check_algorithm()?;
+        if mac_data.iterations == 0 {
+            return Err(Pkcs12Error::InvalidKdfParams);
+        }
+        let data = self.auth_safe_der(password)?;
+        if !pkcs12_passwords(password)
+            .iter()
+            .any(|password| mac_data.verify_mac(&data, password))
+        {
+            return Err(Pkcs12Error::InvalidMac);
+        }
+        Ok(())
+    }
+
+    /// Also true without a MAC, use `mac_status` to tell the two apart.
+    pub fn verify_mac(&self, password: &str) -> bool {
+        self.verify(password).is_ok()
+    }
+
+    pub fn has_mac(&self) -> bool {
+        self.mac_data.is_some()
+    }
+
+    /// Drops the MacData, `to_der` then leaves it out.
+    pub fn remove_mac(&mut self) {
+        self.mac_data = None;
+    }
+
+    /// Replaces the MacData with a new one keyed with `password`, as `PfxBuilder::build` writes it.
+    pub fn set_mac(&mut self, password: &str) -> Result<(), Pkcs12Error> {
+        let data = self.auth_safe_der(password)?;
+        self.mac_data = Some(MacData::new(&data, password.as_bytes()));
+        Ok(())
+    }
+
+    /// Like `verify_mac`, but a missing MAC isn't reported as verified.
+    pub fn mac_status(&self, password: &str) -> MacStatus {
+        if !self.has_mac() {
+            return MacStatus::NoMac;
+        }
+        match self.verify(password) {
+            Ok(()) => MacStatus::Verified,
+            Err(_) => MacStatus::Failed,
+        }
+    }
+
+    /// Index of the first of `passwords` the MAC verifies with.
+    ///
+    /// The authenticated safe is read once, only the MAC key is derived per
+    /// candidate, and the MAC comparison is constant time. A PFX without
+    /// MacData has nothing to check against and gives `None`.
+    pub fn verify_any(&self, passwords: &[&str]) -> Option<usize> {
+        let mac_data = self.mac_data.as_ref()?;
+        if mac_data.iterations == 0 || mac_data.check_algorithm().is_err() {
+            return None;
+        }
+        let ContentInfo::Data(data) = &self.auth_safe else {
+            return None;
+        };
+        passwords
+            .iter()
+            .position(|password| mac_data.verify_mac(data, &bmp_string(password)))
+    }
+
+    /// Checks the MAC and the encryption of the contents with `password`
+    /// separately, for files whose tool used a different password for each.
+    pub fn diagnose(&self, password: &str) -> Diagnosis {
+        let mac_ok = self.verify(password).is_ok();
+        let content_decrypts = match self.bags(password) {
+            //a wrong password can unpad by chance, a parsable key is the real check
+            Ok(bags) => bags.iter().all(|bag| match &bag.bag {
+                SafeBagKind::Pkcs8ShroudedKeyBag(key) => key
+                    .decrypt(password.as_bytes())
+                    .map_or(false, |key| private_key_algorithm(&key).is_some()),
+                _ => true,
+            }),
+            Err(_) => false,
+        };
+        Diagnosis {
+            mac_ok,
+            content_decrypts,
+        }
+    }
+
+    /// Whether both files hold the same bags with the same attributes, in
+    /// any order and however they are encrypted.
+    ///
+    /// Shrouded keys are compared decrypted, so they equal the same key in a
+    /// plain key bag. Salts, IVs, algorithms and the MAC are ignored. Fails
+    /// when either file doesn't decrypt with `password`.
+    pub fn semantically_eq(&self, other: &PFX, password: &str) -> bool {
+        match (
+            self.normalized_bags(password),
+            other.normalized_bags(password),
+        ) {
+            (Some(bags), Some(other_bags)) => bags == other_bags,
+            _ => false,
+        }
+    }
+    fn normalized_bags(&self, password: &str) -> Option<Vec<Vec<u8>>> {
+        let mut bags = vec![];
+        for mut safe_bag in self.bags(password).ok()? {
+            if let SafeBagKind::Pkcs8ShroudedKeyBag(key) = &safe_bag.bag {
+                safe_bag.bag = SafeBagKind::KeyBag(key.decrypt(password.as_bytes()).ok()?);
+            }
+            //the attributes are sorted when written
+            bags.push(safe_bag.to_der());
+        }
+        bags.sort();
+        Some(bags)
+    }
+
+    /// Weaknesses of the file, like a private key stored without encryption.
+    pub fn warnings(&self, password: &str) -> Result<Vec<SecurityWarning>, Pkcs12Error> {
+        let mut warnings = vec![];
+        if self.mac_data.is_none() {
+            warnings.push(SecurityWarning::NoMac);
+        }
+        let bags = self.bags(password)?;
+        if bags
+            .iter()
+            .any(|bag| matches!(bag.bag, SafeBagKind::KeyBag(_)))
+        {
+            warnings.push(SecurityWarning::UnencryptedPrivateKey);
+        }
+        Ok(warnings)
+    }
+
+    /// A human readable summary of the MAC, the content infos, the bags and
+    /// the `warnings`, one item per line.
+    pub fn describe(&self, password: &str) -> Result<String, Pkcs12Error> {
+        let mut lines = vec![];
+        match &self.mac_data {
+            Some(mac_data) => lines.push(format!(
+                "MAC: {}, {} iterations",
+                mac_data.mac.digest_algorithm, mac_data.iterations
+            )),
+            None => lines.push("MAC: none".to_owned()),
+        }
+        for content in self.content_infos()? {
+            lines.push(match content {
+                ContentInfoSummary::Data { bags } => format!("data: {bags} bags"),
+                ContentInfoSummary::EncryptedData(alg) => format!("encrypted data: {alg}"),
+                ContentInfoSummary::Other(oid) => format!("content type {oid}"),
+            });
+        }
+        for bag in self.bags(password)? {
+            let kind = match &bag.bag {
+                SafeBagKind::KeyBag(_) => "unencrypted private key".to_owned(),
+                SafeBagKind::Pkcs8ShroudedKeyBag(key) => {
+                    format!("shrouded private key: {}", key.encryption_algorithm)
+                }
+                SafeBagKind::CertBag(CertBag::X509(_)) => "X.509 certificate".to_owned(),
+                SafeBagKind::CertBag(CertBag::SDSI(_)) => "SDSI certificate".to_owned(),
+                SafeBagKind::SecretBag(secret) => format!("secret {}", secret.secret_type_id),
+                SafeBagKind::OtherBagKind(other) => format!("bag {}", other.bag_id),
+            };
+            lines.push(match bag.friendly_name() {
+                Some(name) => format!("  {kind} \"{name}\""),
+                None => format!("  {kind}"),
+            });
+        }
+        for warning in self.warnings(password)? {
+            lines.push(format!("warning: {warning}"));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    pub fn mac_algorithm(&self) -> Option<&AlgorithmIdentifier> {
+        self.mac_data
+            .as_ref()
+            .map(|mac_data| &mac_data.mac.digest_algorithm)
+    }
+
+    pub fn mac_iterations(&self) -> Option<u32> {
+        self.mac_data.as_ref().map(|mac_data| mac_data.iterations)
+    }
+
+    /// Swaps the certificates for `cert_der` and `ca_der_list`, keeping the
+    /// shrouded key bags as they are.
+    ///
+    /// The first key and the new certificate get a localKeyId of
+    /// SHA-1(cert_der), the certificates are encrypted with `Encryptor` and
+    /// the MAC is recomputed with the old digest and iteration count.
+    pub fn replace_certs<Encryptor: DataEncryptor, KDF: KeyDeriver>(
+        &self,
+        password: &str,
+        cert_der: &[u8],
+        ca_der_list: &[&[u8]],
+    ) -> Result<PFX, Pkcs12Error> {
+        self.verify(password)?;
+        let local_key_id = sha::<Sha1>(cert_der);
+        let mut name = None;
+        let mut keys = vec![];
+        let mut others = vec![];
+        for mut safe_bag in self.bags(password)? {
+            match safe_bag.bag {
+                SafeBagKind::Pkcs8ShroudedKeyBag(_) if keys.is_empty() => {
+                    name = safe_bag.friendly_name();
+                    safe_bag
+                        .attributes
+                        .retain(|attr| !matches!(attr, PKCS12Attribute::LocalKeyId(_)));
+                    safe_bag
+                        .attributes
+                        .push(PKCS12Attribute::LocalKeyId(local_key_id.clone()));
+                    keys.push(safe_bag);
+                }
+                SafeBagKind::Pkcs8ShroudedKeyBag(_) => keys.push(safe_bag),
+                SafeBagKind::CertBag(_) => {}
+                _ => others.push(safe_bag),
+            }
+        }
+
+        let mut attributes = vec![];
+        if let Some(name) = name {
+            attributes.push(PKCS12Attribute::FriendlyName(name));
+        }
+        attributes.push(PKCS12Attribute::LocalKeyId(local_key_id));
+        let mut certs = vec![SafeBag {
+            bag: SafeBagKind::CertBag(CertBag::X509(cert_der.to_owned())),
+            attributes,
+            raw_der: None,
+        }];
+        for ca in ca_der_list {
+            certs.push(SafeBag {
+                bag: SafeBagKind::CertBag(CertBag::X509(ca.to_vec())),
                 attributes: vec![],
+                raw_der: None,
             });
         }
-        let contents = yasna::construct_der(|w| {
-            w.write_sequence_of(|w| {
-                ContentInfo::EncryptedData(
-                    EncryptedData::from_safe_bags::<Encryptor, KDF>(
-                        &cert_bags,
-                        password.as_bytes(),
-                    )
-                    .ok_or_else(|| ASN1Error::new(ASN1ErrorKind::Invalid))
-                    .unwrap(),
-                )
-                .write(w.next());
-                ContentInfo::Data(yasna::construct_der(|w| {
-                    w.write_sequence_of(|w| {
-                        key_bag.write(w.next());
-                    })
-                }))
-                .write(w.next());
-            });
+        //bags other than keys and certificates stay encrypted, next to the certificates
+        certs.extend(others);
+
+        let encrypted =
+            EncryptedData::from_safe_bags::<Encryptor, KDF>(&certs, password.as_bytes())
+                .ok_or(Pkcs12Error::EncryptionFailed)?;
+        let mut contents = vec![ContentInfo::EncryptedData(encrypted)];
+        if !keys.is_empty() {
+            contents.push(ContentInfo::Data(safe_contents_der(&keys)));
+        }
+        let auth_safe = content_infos_der(&contents);
+        let mac_data = match &self.mac_data {
+            Some(mac_data) => Some(
+                MacData::new_with_algorithm(
+                    &auth_safe,
+                    password.as_bytes(),
+                    mac_data.mac.digest_algorithm.clone(),
+                    mac_data.iterations,
+                )
+                .ok_or(Pkcs12Error::EncryptionFailed)?,
+            ),
+            None => None,
+        };
+        Ok(PFX {
+            version: self.version,
+            auth_safe: ContentInfo::Data(auth_safe),
+            mac_data,
+        })
+    }
+
+    /// Sets the friendly name of the bags named `old_name`, or with `None` of
+    /// the only key and the certificates sharing its localKeyId, and
+    /// recomputes the MAC with the old digest and iteration count.
+    ///
+    /// Content infos without a renamed bag are kept as they are, encrypted
+    /// ones holding a renamed bag are encrypted again with AES-256-CBC and
+    /// PBKDF2. Fails with `Pkcs12Error::MissingIdentity` when no bag is
+    /// named `old_name`, or with `None` unless there is exactly one key.
+    pub fn rename_entry(
+        &self,
+        password: &str,
+        old_name: Option<&str>,
+        new_name: &str,
+    ) -> Result<PFX, Pkcs12Error> {
+        self.verify(password)?;
+        let password_bytes = password.as_bytes();
+        let data = self.auth_safe.data(password_bytes)?;
+        let contents = yasna::parse_ber(&data, |r| r.collect_sequence_of(ContentInfo::parse))?;
+        let mut safe_contents = vec![];
+        for content in contents.iter() {
+            let data = content.data(password_bytes)?;
+            safe_contents.push(yasna::parse_ber(&data, |r| {
+                r.collect_sequence_of(SafeBag::parse)
+            })?);
+        }
+
+        let is_key = |bag: &SafeBag| {
+            matches!(
+                bag.bag,
+                SafeBagKind::KeyBag(_) | SafeBagKind::Pkcs8ShroudedKeyBag(_)
+            )
+        };
+        let key_id = match old_name {
+            Some(_) => None,
+            None => {
+                let keys: Vec<&SafeBag> = safe_contents
+                    .iter()
+                    .flatten()
+                    .filter(|bag| is_key(bag))
+                    .collect();
+                if keys.len() != 1 {
+                    return Err(Pkcs12Error::MissingIdentity);
+                }
+                keys[0].local_key_id()
+            }
+        };
+        let matches = |bag: &SafeBag| match old_name {
+            Some(name) => bag.friendly_name().as_deref() == Some(name),
+            None => {
+                is_key(bag)
+                    || (matches!(bag.bag, SafeBagKind::CertBag(_))
+                        && key_id.is_some()
+                        && bag.local_key_id() == key_id)
+            }
+        };
+
+        let mut renamed_any = false;
+        let mut new_contents = vec![];
+        for (content, bags) in contents.into_iter().zip(safe_contents) {
+            let mut renamed = false;
+            let bags: Vec<SafeBag> = bags
+                .into_iter()
+                .map(|bag| {
+                    if matches(&bag) {
+                        renamed = true;
+                        bag.with_friendly_name(new_name)
+                    } else {
+                        bag
+                    }
+                })
+                .collect();
+            renamed_any |= renamed;
+            new_contents.push(match content {
+                content if !renamed => content,
+                ContentInfo::EncryptedData(_) => ContentInfo::EncryptedData(
+                    EncryptedData::from_safe_bags::<AesCbcDataEncryptor, Pbkdf2>(
+                        &bags,
+                        password_bytes,
+                    )
+                    .ok_or(Pkcs12Error::EncryptionFailed)?,
+                ),
+                _ => ContentInfo::Data(safe_contents_der(&bags)),
+            });
+        }
+        if !renamed_any {
+            return Err(Pkcs12Error::MissingIdentity);
+        }
+
+        let auth_safe = content_infos_der(&new_contents);
+        let mac_data = match &self.mac_data {
+            Some(mac_data) => Some(
+                MacData::new_with_algorithm(
+                    &auth_safe,
+                    password_bytes,
+                    mac_data.mac.digest_algorithm.clone(),
+                    mac_data.iterations,
+                )
+                .ok_or(Pkcs12Error::EncryptionFailed)?,
+            ),
+            None => None,
+        };
+        Ok(PFX {
+            version: self.version,
+            auth_safe: ContentInfo::Data(auth_safe),
+            mac_data,
+        })
+    }
+
+    /// Re-encrypts all bags with AES-256-CBC and PBKDF2-HMAC-SHA256 and adds
+    /// a SHA-256 MAC, keeping the password, the attributes and unknown bags.
+    ///
+    /// Uses the iteration counts of `TranscodeOptions::modern`.
+    pub fn upgrade_to_modern(&self, password: &str) -> Result<PFX, Pkcs12Error> {
+        self.transcode(password, &TranscodeOptions::modern())
+    }
+
+    /// Re-encrypts all bags and recomputes the MAC with the algorithms of
+    /// `options`, keeping the password, the attributes and unknown bags.
+    ///
+    /// Shrouded keys are decrypted and encrypted again, all other bags go
+    /// into one encrypted content info.
+    pub fn transcode(
+        &self,
+        password: &str,
+        options: &TranscodeOptions,
+    ) -> Result<PFX, Pkcs12Error> {
+        self.verify(password)?;
+        let mut keys = vec![];
+        let mut others = vec![];
+        for safe_bag in self.bags(password)? {
+            if let SafeBagKind::Pkcs8ShroudedKeyBag(key) = &safe_bag.bag {
+                let key_der = key.decrypt(password.as_bytes())?;
+                let bag = (options.encrypt_key)(&key_der, password.as_bytes())
+                    .ok_or(Pkcs12Error::EncryptionFailed)?;
+                keys.push(SafeBag {
+                    bag,
+                    attributes: safe_bag.attributes,
+                    raw_der: None,
+                });
+            } else {
+                others.push(safe_bag);
+            }
+        }
+
+        let mut contents = vec![];
+        if !others.is_empty() {
+            let encrypted = (options.encrypt_content)(&others, password.as_bytes())
+                .ok_or(Pkcs12Error::EncryptionFailed)?;
+            contents.push(ContentInfo::EncryptedData(encrypted));
+        }
+        if !keys.is_empty() {
+            contents.push(ContentInfo::Data(safe_contents_der(&keys)));
+        }
+        let auth_safe = content_infos_der(&contents);
+        let mac_data = MacData::new_with_algorithm(
+            &auth_safe,
+            password.as_bytes(),
+            options.mac_algorithm.clone(),
+            options.mac_iterations,
+        )
+        .ok_or(Pkcs12Error::EncryptionFailed)?;
+        Ok(PFX {
+            version: 3,
+            auth_safe: ContentInfo::Data(auth_safe),
+            mac_data: Some(mac_data),
+        })
+    }
+}
+
+/// A content info of the authenticated safe as `PFX::content_infos` sees it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentInfoSummary {
+    // plain safe contents and the number of bags in it
+    Data { bags: usize },
+    // safe contents encrypted with the algorithm
+    EncryptedData(AlgorithmIdentifier),
+    // the content type of anything else, e.g. enveloped data
+    Other(ObjectIdentifier),
+}
+
+/// A weakness `PFX::warnings` finds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecurityWarning {
+    // the contents can be changed unnoticed
+    NoMac,
+    // a private key in a plain key bag rather than a shrouded one
+    UnencryptedPrivateKey,
+}
+
+impl std::fmt::Display for SecurityWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecurityWarning::NoMac => write!(f, "no MAC, integrity is not protected"),
+            SecurityWarning::UnencryptedPrivateKey => {
+                write!(f, "unencrypted private key present")
+            }
+        }
+    }
+}
+
+/// Result of `PFX::diagnose`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Diagnosis {
+    /// The MAC verifies, or there is none.
+    pub mac_ok: bool,
+    /// All contents and shrouded keys decrypt.
+    pub content_decrypts: bool,
+}
+
+/// Algorithms `PFX::transcode` writes with, by default AES-256-CBC with
+/// PBKDF2 for all bags and a SHA-256 MAC.
+#[derive(Debug, Clone)]
+pub struct TranscodeOptions {
+    encrypt_content: ContentEncryptFn,
+    encrypt_key: KeyEncryptFn,
+    /// One of the digests `MacData::new_with_algorithm` takes.
+    pub mac_algorithm: AlgorithmIdentifier,
+    pub mac_iterations: u32,
+}
+
+impl Default for TranscodeOptions {
+    fn default() -> Self {
+        TranscodeOptions {
+            encrypt_content: EncryptedData::from_safe_bags::<AesCbcDataEncryptor, Pbkdf2>,
+            encrypt_key: encrypt_key::<AesCbcDataEncryptor, Pbkdf2>,
+            mac_algorithm: AlgorithmIdentifier::sha256(),
+            mac_iterations: ITERATIONS as u32,
+        }
+    }
+}
+
+fn encrypt_content_modern(safe_bags: &[SafeBag], password: &[u8]) -> Option<EncryptedData> {
+    let key_deriver = Pbkdf2::new_sha256().with_iterations(MODERN_PBKDF2_ITERATIONS);
+    EncryptedData::from_safe_bags_with(
+        &AesCbcDataEncryptor::new(),
+        &key_deriver,
+        safe_bags,
+        password,
+    )
+}
+
+fn encrypt_key_modern(key_der: &[u8], password: &[u8]) -> Option<SafeBagKind> {
+    let key_deriver = Pbkdf2::new_sha256().with_iterations(MODERN_PBKDF2_ITERATIONS);
+    AesCbcDataEncryptor::new().encrypt_keybag_key_deriver(key_der, password, &key_deriver)
+}
+
+impl TranscodeOptions {
+    /// AES-256-CBC with 600,000 iterations of PBKDF2-HMAC-SHA256 and a
+    /// SHA-256 MAC with 10,000 iterations.
+    pub fn modern() -> Self {
+        TranscodeOptions {
+            encrypt_content: encrypt_content_modern,
+            encrypt_key: encrypt_key_modern,
+            mac_algorithm: AlgorithmIdentifier::sha256(),
+            mac_iterations: MODERN_MAC_ITERATIONS,
+        }
+    }
+    /// Encryption of the content info holding certificates and other bags.
+    pub fn cert_encryption<Encryptor: DataEncryptor, KDF: KeyDeriver>(mut self) -> Self {
+        self.encrypt_content = EncryptedData::from_safe_bags::<Encryptor, KDF>;
+        self
+    }
+    /// Encryption of the shrouded key bags.
+    pub fn key_encryption<Encryptor: DataEncryptor, KDF: KeyDeriver>(mut self) -> Self {
+        self.encrypt_key = encrypt_key::<Encryptor, KDF>;
+        self
+    }
+    pub fn mac(mut self, mac_algorithm: AlgorithmIdentifier, mac_iterations: u32) -> Self {
+        self.mac_algorithm = mac_algorithm;
+        self.mac_iterations = mac_iterations;
+        self
+    }
+}
+
+#[test]
+fn test_upgrade_to_modern() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+
+    let secret_type = as_oid(&[1, 3, 6, 1, 4, 1, 99999, 2]);
+    let value = yasna::construct_der(|w| w.write_bytes(b"secret"));
+    let legacy = PfxBuilder::new()
+        .encryption::<PbeWithShaAnd40BitRc2CbcEncryptor, PbeWithShaAnd40BitRc2CbcEncryptKeyDeriver>(
+        )
+        .add_identity(&cert, &key, "look")
+        .add_secret(secret_type.clone(), &value, "secret")
+        .build("changeit")
+        .unwrap();
+    let upgraded = legacy.upgrade_to_modern("changeit").unwrap();
+    assert!(upgraded.verify_mac("changeit"));
+    assert_eq!(
+        upgraded.mac_data.as_ref().unwrap().mac.digest_algorithm,
+        AlgorithmIdentifier::sha256()
+    );
+    assert_eq!(upgraded.mac_iterations(), Some(10_000));
+    for bag in upgraded.bags("changeit").unwrap() {
+        if let SafeBagKind::Pkcs8ShroudedKeyBag(key) = &bag.bag {
+            assert!(matches!(
+                key.encryption_algorithm,
+                AlgorithmIdentifier::Pbes2(_)
+            ));
+            assert_eq!(key.encryption_algorithm.iteration_count(), Some(600_000));
+            assert_eq!(bag.friendly_name(), Some("look".to_owned()));
+            assert!(bag.matches_cert(&cert));
+        }
+    }
+    let ContentInfo::Data(auth_safe) = &upgraded.auth_safe else {
+        panic!("expected a data auth_safe");
+    };
+    let contents =
+        yasna::parse_der(auth_safe, |r| r.collect_sequence_of(ContentInfo::parse)).unwrap();
+    assert!(contents.iter().all(|content| match content {
+        ContentInfo::EncryptedData(encrypted) => matches!(
+            encrypted
+                .encrypted_content_info
+                .content_encryption_algorithm,
+            AlgorithmIdentifier::Pbes2(_)
+        ),
+        _ => true,
+    }));
+    assert_eq!(upgraded.key_bags("changeit").unwrap(), vec![key]);
+    assert_eq!(upgraded.cert_x509_bags("changeit").unwrap(), vec![cert]);
+    assert_eq!(upgraded.secret_entries("changeit").unwrap()[0].value, value);
+}
+
+#[test]
+fn test_transcode() {
+    let p12 = fs::read("mac_sha224.p12").unwrap();
+    let pfx = PFX::parse(&p12).unwrap();
+    let bags = pfx.bags("changeit").unwrap();
+
+    let options = TranscodeOptions::default()
+        .cert_encryption::<PbeWithShaAnd40BitRc2CbcEncryptor, PbeWithShaAnd40BitRc2CbcEncryptKeyDeriver>()
+        .key_encryption::<PbeWithShaAnd40BitRc2CbcEncryptor, PbeWithShaAnd40BitRc2CbcEncryptKeyDeriver>()
+        .mac(AlgorithmIdentifier::sha1(), 1024);
+    let legacy = pfx.transcode("changeit", &options).unwrap();
+    assert!(legacy.verify_mac("changeit"));
+    assert_eq!(legacy.mac_algorithm(), Some(&AlgorithmIdentifier::sha1()));
+    assert_eq!(legacy.mac_iterations(), Some(1024));
+    for key in legacy.encrypted_key_bags().unwrap() {
+        let key = yasna::parse_der(&key, EncryptedPrivateKeyInfo::parse).unwrap();
+        assert_eq!(key.encryption_algorithm.to_string(), "PBE-SHA1-3DES");
+    }
+    assert_eq!(
+        legacy.key_bags("changeit").unwrap(),
+        pfx.key_bags("changeit").unwrap()
+    );
+    let legacy_bags = legacy.bags("changeit").unwrap();
+    assert_eq!(legacy_bags.len(), bags.len());
+    for bag in &bags {
+        if !matches!(bag.bag, SafeBagKind::Pkcs8ShroudedKeyBag(_)) {
+            assert!(legacy_bags.contains(bag));
+        }
+    }
+
+    assert!(matches!(
+        pfx.transcode("wrong", &options),
+        Err(Pkcs12Error::InvalidMac)
+    ));
+}
+
+#[test]
+fn test_replace_certs() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let ca = fs::read("ca.der").unwrap();
+
+    let pfx =
+        PFX::new::<AesCbcDataEncryptor, Pbkdf2>(&cert, &key, None, "changeit", "look").unwrap();
+    //the CA stands in for a renewed certificate
+    let renewed = pfx
+        .replace_certs::<AesCbcDataEncryptor, Pbkdf2>("changeit", &ca, &[&cert])
+        .unwrap();
+    assert!(renewed.verify_mac("changeit"));
+    assert_eq!(
+        renewed.cert_x509_bags("changeit").unwrap(),
+        vec![ca.clone(), cert]
+    );
+    assert_eq!(renewed.key_bags("changeit").unwrap(), vec![key]);
+    assert_eq!(
+        pfx.encrypted_key_bags().unwrap(),
+        renewed.encrypted_key_bags().unwrap()
+    );
+
+    let bags = renewed.bags("changeit").unwrap();
+    let local_key_id = sha::<Sha1>(&ca);
+    assert!(bags
+        .iter()
+        .filter(|bag| bag.local_key_id() == Some(local_key_id.clone()))
+        .all(|bag| bag.friendly_name() == Some("look".to_owned())));
+    assert_eq!(
+        bags.iter()
+            .filter(|bag| bag.local_key_id() == Some(local_key_id.clone()))
+            .count(),
+        2
+    );
+
+    let version0 = PFX::parse(&fs::read("pfx_version0.p12").unwrap()).unwrap();
+    let renewed = version0
+        .replace_certs::<AesCbcDataEncryptor, Pbkdf2>("changeit", &ca, &[])
+        .unwrap();
+    assert_eq!(renewed.version, 0);
+    assert!(renewed.verify_mac("changeit"));
+}
+
+/// A private key with its certificate and the remaining certificates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// DER-encoded PKCS#8 private key.
+    pub key: Vec<u8>,
+    /// DER-encoded X.509 certificate.
+    pub cert: Vec<u8>,
+    /// DER-encoded X.509 certificates, in the order of the file.
+    pub chain: Vec<Vec<u8>>,
+}
+
+impl Identity {
+    /// Parses `der`, verifies the MAC and picks the certificate sharing the
+    /// localKeyId of the first private key, or the first certificate when
+    /// they aren't linked by a localKeyId.
+    pub fn from_pkcs12(der: &[u8], password: &str) -> Result<Identity, Pkcs12Error> {
+        let pfx = PFX::parse(der)?;
+        pfx.verify(password)?;
+        let mut key = None;
+        let mut certs = vec![];
+        for safe_bag in pfx.bags(password)? {
+            if key.is_none() {
+                if let Some(der) = safe_bag.bag.get_key(password.as_bytes()) {
+                    key = Some((der, safe_bag.local_key_id()));
+                    continue;
+                }
+            }
+            if let Some(cert) = safe_bag.bag.get_x509_cert() {
+                certs.push((cert, safe_bag.local_key_id()));
+            }
+        }
+        let (key, local_key_id) = key.ok_or(Pkcs12Error::MissingIdentity)?;
+        if certs.is_empty() {
+            return Err(Pkcs12Error::MissingIdentity);
+        }
+        let leaf = local_key_id
+            .and_then(|id| {
+                certs
+                    .iter()
+                    .position(|(_, cert_id)| cert_id.as_ref() == Some(&id))
+            })
+            .unwrap_or(0);
+        let (cert, _) = certs.remove(leaf);
+        Ok(Identity {
+            key,
+            cert,
+            chain: certs.into_iter().map(|(cert, _)| cert).collect(),
+        })
+    }
+}
+
+#[test]
+fn test_identity_from_pkcs12() {
+    let ca = fs::read("ca.der").unwrap();
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+
+    // the CA comes first, the leaf has to be found by its localKeyId
+    let p12 = PfxBuilder::new()
+        .add_ca(&ca)
+        .add_identity(&cert, &key, "look")
+        .build("changeit")
+        .unwrap()
+        .to_der();
+    let identity = Identity::from_pkcs12(&p12, "changeit").unwrap();
+    assert_eq!(identity.key, key);
+    assert_eq!(identity.cert, cert);
+    assert_eq!(identity.chain, vec![ca]);
+
+    assert!(matches!(
+        Identity::from_pkcs12(&p12, "wrong"),
+        Err(Pkcs12Error::InvalidMac)
+    ));
+}
+
+fn content_infos_der(contents: &[ContentInfo]) -> Vec<u8> {
+    yasna::construct_der(|w| {
+        w.write_sequence_of(|w| {
+            for content in contents {
+                content.write(w.next());
+            }
+        })
+    })
+}
+
+fn safe_contents_der(safe_bags: &[SafeBag]) -> Vec<u8> {
+    yasna::construct_der(|w| {
+        w.write_sequence_of(|w| {
+            for sb in safe_bags {
+                sb.write(w.next());
+            }
+        })
+    })
+}
+
+type ContentEncryptFn = fn(&[SafeBag], &[u8]) -> Option<EncryptedData>;
+type KeyEncryptFn = fn(&[u8], &[u8]) -> Option<SafeBagKind>;
+
+fn encrypt_key<Encryptor: DataEncryptor, KDF: KeyDeriver>(
+    key_der: &[u8],
+    password: &[u8],
+) -> Option<SafeBagKind> {
+    Encryptor::new().encrypt_keybag::<KDF>(key_der, password)
+}
+
+/// How `PfxBuilder::add_identity` computes the localKeyId pairing a key with
+/// its certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocalKeyIdStrategy {
+    /// SHA-1 of the certificate DER, what OpenSSL and this crate write.
+    Sha1OfCert,
+    /// SHA-1 of the SubjectPublicKeyInfo DER, used by some Windows tools.
+    ///
+    /// `PfxBuilder::add_identity` falls back to `Sha1OfCert` for a
+    /// certificate whose SubjectPublicKeyInfo can't be found. Call
+    /// `local_key_id` first to catch those, its error is not swallowed.
+    Sha1OfSpki,
+    /// A fixed localKeyId, the same for every identity.
+    Custom(Vec<u8>),
+}
+
+impl Default for LocalKeyIdStrategy {
+    fn default() -> Self {
+        LocalKeyIdStrategy::Sha1OfCert
+    }
+}
+
+impl LocalKeyIdStrategy {
+    /// The localKeyId for `cert_der`, or an error when `Sha1OfSpki` can't
+    /// find the SubjectPublicKeyInfo.
+    pub fn local_key_id(&self, cert_der: &[u8]) -> Result<Vec<u8>, Pkcs12Error> {
+        Ok(match self {
+            LocalKeyIdStrategy::Sha1OfCert => sha::<Sha1>(cert_der),
+            LocalKeyIdStrategy::Sha1OfSpki => sha::<Sha1>(&x509::cert_spki(cert_der)?),
+            LocalKeyIdStrategy::Custom(id) => id.clone(),
+        })
+    }
+}
+
+/// Assembles a PFX from private keys, certificates and secrets.
+///
+/// Certificates are stored in an encrypted content info, or a plain one with
+/// `encrypt_certs(false)`, the shrouded keys
+/// in a plain one and secrets and other bags in an encrypted content info of
+/// their own.
+/// AES-256-CBC with PBKDF2 is used unless set otherwise with `encryption`,
+/// or for certificates and keys separately with `cert_encryption` and
+/// `key_encryption`.
+#[derive(Debug, Clone)]
+pub struct PfxBuilder {
+    keys: Vec<(Vec<u8>, Vec<PKCS12Attribute>)>,
+    shrouded_keys: Vec<SafeBag>,
+    certs: Vec<SafeBag>,
+    secrets: Vec<SafeBag>,
+    encrypt_content: ContentEncryptFn,
+    encrypt_key: KeyEncryptFn,
+    friendly_name_utf8: bool,
+    wrap_pkcs1_keys: bool,
+    encrypt_certs: bool,
+    local_key_id: LocalKeyIdStrategy,
+}
+
+impl Default for PfxBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PfxBuilder {
+    pub fn new() -> Self {
+        PfxBuilder {
+            keys: vec![],
+            shrouded_keys: vec![],
+            certs: vec![],
+            secrets: vec![],
+            encrypt_content: EncryptedData::from_safe_bags::<AesCbcDataEncryptor, Pbkdf2>,
+            encrypt_key: encrypt_key::<AesCbcDataEncryptor, Pbkdf2>,
+            friendly_name_utf8: false,
+            wrap_pkcs1_keys: false,
+            encrypt_certs: true,
+            local_key_id: LocalKeyIdStrategy::Sha1OfCert,
+        }
+    }
+    pub fn encryption<Encryptor: DataEncryptor, KDF: KeyDeriver>(self) -> Self {
+        self.cert_encryption::<Encryptor, KDF>()
+            .key_encryption::<Encryptor, KDF>()
+    }
+    /// Encryption of the content infos holding certificates and secrets.
+    pub fn cert_encryption<Encryptor: DataEncryptor, KDF: KeyDeriver>(mut self) -> Self {
+        self.encrypt_content = EncryptedData::from_safe_bags::<Encryptor, KDF>;
+        self
+    }
+    /// Encryption of the shrouded key bags.
+    pub fn key_encryption<Encryptor: DataEncryptor, KDF: KeyDeriver>(mut self) -> Self {
+        self.encrypt_key = encrypt_key::<Encryptor, KDF>;
+        self
+    }
+    /// The defaults of `openssl pkcs12 -export` before OpenSSL 3.0: certificates
+    /// encrypted with 40 bit RC2 and the key with 3DES, both with the PKCS#12 PBE.
+    pub fn legacy_encryption(self) -> Self {
+        self.encryption::<PbeWithShaAnd40BitRc2CbcEncryptor, PbeWithShaAnd40BitRc2CbcEncryptKeyDeriver>()
+    }
+    /// Certificates encrypted with 40 bit RC2 using the PKCS#12 PBE.
+    pub fn legacy_cert_encryption(self) -> Self {
+        self.cert_encryption::<PbeWithShaAnd40BitRc2CbcEncryptor, PbeWithShaAnd40BitRc2CbcEncryptKeyDeriver>()
+    }
+    /// Shrouded keys encrypted with 3DES using the PKCS#12 PBE.
+    pub fn legacy_key_encryption(self) -> Self {
+        self.key_encryption::<PbeWithShaAnd3KeyTripleDesCbcEncryptor, PbeWithShaAnd3KeyTripleDesCbcEncryptKeyDeriver>()
+    }
+    /// Writes friendly names as UTF8String instead of the BMPString RFC 7292
+    /// asks for, for tools that only read UTF8String.
+    pub fn friendly_name_utf8(mut self, utf8: bool) -> Self {
+        self.friendly_name_utf8 = utf8;
+        self
+    }
+    /// Stores PKCS#1 RSA keys wrapped into PKCS#8, other keys are left as they are.
+    pub fn wrap_pkcs1_keys(mut self, wrap: bool) -> Self {
+        self.wrap_pkcs1_keys = wrap;
+        self
+    }
+    /// With `false` the certificates go into a plain content info, readable
+    /// without the password. Keys stay shrouded either way.
+    pub fn encrypt_certs(mut self, encrypt: bool) -> Self {
+        self.encrypt_certs = encrypt;
+        self
+    }
+    /// The localKeyId of identities added afterwards. With `Sha1OfSpki` a
+    /// certificate that doesn't parse gets the SHA-1 of its DER instead.
+    pub fn local_key_id(mut self, strategy: LocalKeyIdStrategy) -> Self {
+        self.local_key_id = strategy;
+        self
+    }
+    /// The key and its certificate share the friendly name and a localKeyId,
+    /// SHA-1(cert_der) unless set otherwise with `local_key_id`.
+    pub fn add_identity(mut self, cert_der: &[u8], key_der: &[u8], name: &str) -> Self {
+        let friendly_name = PKCS12Attribute::FriendlyName(name.to_owned());
+        let local_key_id = self
+            .local_key_id
+            .local_key_id(cert_der)
+            .unwrap_or_else(|_| sha::<Sha1>(cert_der));
+        let local_key_id = PKCS12Attribute::LocalKeyId(local_key_id);
+        self.keys.push((
+            key_der.to_owned(),
+            vec![friendly_name.clone(), local_key_id.clone()],
+        ));
+        self.certs.push(SafeBag {
+            bag: SafeBagKind::CertBag(CertBag::X509(cert_der.to_owned())),
+            attributes: vec![friendly_name, local_key_id],
+            raw_der: None,
+        });
+        self
+    }
+    pub fn add_ca(mut self, ca_der: &[u8]) -> Self {
+        self.certs.push(SafeBag::cert_x509(ca_der));
+        self
+    }
+    /// Adds an already encrypted PKCS#8 key, a DER encoded
+    /// EncryptedPrivateKeyInfo, as a shrouded key bag without re-encrypting it.
+    ///
+    /// The key keeps the password it was encrypted with. Most tools, OpenSSL
+    /// and Java included, decrypt all bags with the PFX password, so unless
+    /// both are the same the key can only be read by decrypting its
+    /// `EncryptedPrivateKeyInfo` with the key password.
+    pub fn add_encrypted_key(
+        mut self,
+        epki_der: &[u8],
+        friendly_name: &str,
+    ) -> Result<Self, Pkcs12Error> {
+        let key = yasna::parse_der(epki_der, EncryptedPrivateKeyInfo::parse)?;
+        self.shrouded_keys
+            .push(SafeBag::shrouded_key(key).with_friendly_name(friendly_name));
+        Ok(self)
+    }
+    /// A CA certificate listed under `name` by keystore browsers.
+    pub fn add_named_ca(mut self, ca_der: &[u8], name: &str) -> Self {
+        self.certs
+            .push(SafeBag::cert_x509(ca_der).with_friendly_name(name));
+        self
+    }
+    /// `value_der` is the DER encoded secret value, e.g. an OCTET STRING.
+    pub fn add_secret(
+        mut self,
+        secret_type_id: ObjectIdentifier,
+        value_der: &[u8],
+        friendly_name: &str,
+    ) -> Self {
+        self.secrets.push(SafeBag {
+            bag: SafeBagKind::SecretBag(SecretBag {
+                secret_type_id,
+                secret_value: value_der.to_owned(),
+            }),
+            attributes: vec![PKCS12Attribute::FriendlyName(friendly_name.to_owned())],
+            raw_der: None,
+        });
+        self
+    }
+    /// A bag of a type this crate doesn't know, `value_der` is written as
+    /// the bag value as it is and read back unchanged by `PFX::other_bags`.
+    pub fn add_other_bag(
+        mut self,
+        bag_id: ObjectIdentifier,
+        value_der: Vec<u8>,
+        attributes: Vec<PKCS12Attribute>,
+    ) -> Self {
+        self.secrets.push(SafeBag {
+            bag: SafeBagKind::OtherBagKind(OtherBag {
+                bag_id,
+                bag_value: value_der,
+            }),
+            attributes,
+            raw_der: None,
         });
+        self
+    }
+    pub fn build(&self, password: &str) -> Option<PFX> {
+        let contents = self.build_auth_safe(password)?;
         let mac_data = MacData::new(&contents, password.as_bytes());
         Some(PFX {
             version: 3,
@@ -908,145 +4478,656 @@ impl PFX {
             mac_data: Some(mac_data),
         })
     }
-
-    pub fn parse(bytes: &[u8]) -> Result<PFX, ASN1Error> {
-        yasna::parse_ber(bytes, |r| {
-            r.read_sequence(|r| {
-                let version = r.next().read_u8()?;
-                let auth_safe = ContentInfo::parse(r.next())?;
-                let mac_data = r.read_optional(MacData::parse)?;
-                Ok(PFX {
-                    version,
-                    auth_safe,
-                    mac_data,
-                })
+    fn encoded(&self, safe_bags: &[SafeBag]) -> Vec<SafeBag> {
+        safe_bags
+            .iter()
+            .map(|safe_bag| SafeBag {
+                bag: safe_bag.bag.clone(),
+                attributes: self.encoded_attributes(&safe_bag.attributes),
+                raw_der: None,
+            })
+            .collect()
+    }
+    fn encoded_attributes(&self, attributes: &[PKCS12Attribute]) -> Vec<PKCS12Attribute> {
+        attributes
+            .iter()
+            .map(|attr| match attr {
+                PKCS12Attribute::FriendlyName(name) if self.friendly_name_utf8 => {
+                    PKCS12Attribute::Other(OtherAttribute {
+                        oid: OID_FRIENDLY_NAME.clone(),
+                        data: vec![yasna::construct_der(|w| w.write_utf8_string(name))],
+                    })
+                }
+                _ => attr.clone(),
             })
+            .collect()
+    }
+    fn build_auth_safe(&self, password: &str) -> Option<Vec<u8>> {
+        let password = password.as_bytes();
+        let mut contents = vec![];
+        if !self.certs.is_empty() && self.encrypt_certs {
+            let encrypted = (self.encrypt_content)(&self.encoded(&self.certs), password)?;
+            contents.push(ContentInfo::EncryptedData(encrypted));
+        } else if !self.certs.is_empty() {
+            let certs = safe_contents_der(&self.encoded(&self.certs));
+            contents.push(ContentInfo::Data(certs));
+        }
+        if !self.keys.is_empty() || !self.shrouded_keys.is_empty() {
+            let mut key_bags = vec![];
+            for (key_der, attributes) in &self.keys {
+                let key_der = if self.wrap_pkcs1_keys && is_pkcs1_rsa_key(key_der) {
+                    wrap_pkcs1_rsa_as_pkcs8(key_der)
+                } else {
+                    key_der.clone()
+                };
+                key_bags.push(SafeBag {
+                    bag: (self.encrypt_key)(&key_der, password)?,
+                    attributes: self.encoded_attributes(attributes),
+                    raw_der: None,
+                });
+            }
+            key_bags.extend(self.encoded(&self.shrouded_keys));
+            contents.push(ContentInfo::Data(safe_contents_der(&key_bags)));
+        }
+        if !self.secrets.is_empty() {
+            let encrypted = (self.encrypt_content)(&self.encoded(&self.secrets), password)?;
+            contents.push(ContentInfo::EncryptedData(encrypted));
+        }
+        Some(content_infos_der(&contents))
+    }
+}
+
+#[test]
+fn test_pfx_builder_friendly_name_utf8() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+
+    let pfx = PfxBuilder::new()
+        .friendly_name_utf8(true)
+        .add_identity(&cert, &key, "l\u{f6}ok")
+        .build("changeit")
+        .unwrap();
+    //the shrouded key bag sits in a plain data content
+    let utf8_der = yasna::construct_der(|w| w.write_utf8_string("l\u{f6}ok"));
+    let contains_utf8 = |pfx: &PFX| {
+        let der = pfx.auth_safe_der("changeit").unwrap();
+        der.windows(utf8_der.len()).any(|window| window == utf8_der)
+    };
+    assert!(contains_utf8(&pfx));
+    for bag in pfx.bags("changeit").unwrap() {
+        assert_eq!(bag.friendly_name(), Some("l\u{f6}ok".to_owned()));
+    }
+
+    let pfx = PfxBuilder::new()
+        .add_identity(&cert, &key, "l\u{f6}ok")
+        .build("changeit")
+        .unwrap();
+    assert!(!contains_utf8(&pfx));
+    for bag in pfx.bags("changeit").unwrap() {
+        assert_eq!(bag.friendly_name(), Some("l\u{f6}ok".to_owned()));
+    }
+}
+
+#[test]
+fn test_pfx_builder_plaintext_certs() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let ca = fs::read("ca.der").unwrap();
+    let pfx = PfxBuilder::new()
+        .encrypt_certs(false)
+        .add_identity(&cert, &key, "look")
+        .add_ca(&ca)
+        .build("changeit")
+        .unwrap();
+    let pfx = PFX::parse(&pfx.to_der()).unwrap();
+    assert_eq!(
+        pfx.content_infos().unwrap(),
+        vec![
+            ContentInfoSummary::Data { bags: 2 },
+            ContentInfoSummary::Data { bags: 1 },
+        ]
+    );
+    assert!(pfx.verify_mac("changeit"));
+    // nothing to decrypt for the certificates, any password reads them
+    assert_eq!(
+        pfx.cert_x509_bags("wrong").unwrap(),
+        vec![cert.clone(), ca.clone()]
+    );
+    assert!(pfx.key_bags("wrong").unwrap().is_empty());
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key]);
+    for bag in pfx.bags("wrong").unwrap() {
+        if let SafeBagKind::Pkcs8ShroudedKeyBag(key) = bag.bag {
+            assert!(matches!(
+                key.encryption_algorithm,
+                AlgorithmIdentifier::Pbes2(_)
+            ));
+        }
+    }
+}
+
+#[test]
+fn test_pfx_builder_wrap_pkcs1_keys() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let pkcs1 = yasna::parse_der(&key, |r| {
+        r.read_sequence(|r| {
+            r.next().read_u8()?;
+            r.next().read_der()?;
+            r.next().read_bytes()
         })
+    })
+    .unwrap();
+
+    let p12 = PfxBuilder::new()
+        .wrap_pkcs1_keys(true)
+        .add_identity(&cert, &pkcs1, "look")
+        .build("changeit")
+        .unwrap()
+        .to_der();
+    let pfx = PFX::parse(&p12).unwrap();
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key.clone()]);
+
+    //PKCS#8 keys pass through unchanged
+    let pfx = PfxBuilder::new()
+        .wrap_pkcs1_keys(true)
+        .add_identity(&cert, &key, "look")
+        .build("changeit")
+        .unwrap();
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key]);
+
+    let pfx = PfxBuilder::new()
+        .add_identity(&cert, &pkcs1, "look")
+        .build("changeit")
+        .unwrap();
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![pkcs1]);
+}
+
+#[test]
+fn test_pfx_builder_local_key_id() {
+    use hex_literal::hex;
+
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+
+    // `openssl pkey -pubin -outform der | sha1sum` of the certificate's key
+    let spki_id = hex!("8e55eb4cc02d891c43a86a7f1e96475770daec94").to_vec();
+    for (strategy, id) in [
+        (LocalKeyIdStrategy::default(), sha::<Sha1>(&cert)),
+        (LocalKeyIdStrategy::Sha1OfSpki, spki_id),
+        (LocalKeyIdStrategy::Custom(vec![1, 2, 3]), vec![1, 2, 3]),
+    ] {
+        let pfx = PfxBuilder::new()
+            .local_key_id(strategy)
+            .add_identity(&cert, &key, "look")
+            .build("changeit")
+            .unwrap();
+        let bags = pfx.bags("changeit").unwrap();
+        assert_eq!(bags.len(), 2);
+        for bag in bags {
+            assert_eq!(bag.local_key_id(), Some(id.clone()));
+        }
     }
+    assert!(LocalKeyIdStrategy::Sha1OfSpki
+        .local_key_id(&cert[..20])
+        .is_err());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_pfx_file() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let pfx =
+        PFX::new::<AesCbcDataEncryptor, Pbkdf2>(&cert, &key, None, "changeit", "look").unwrap();
+    let path = std::env::temp_dir().join(format!("p12-test-{}.p12", std::process::id()));
+    pfx.write_to_file(&path).unwrap();
+    let read = PFX::from_file(&path).unwrap().unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(read.to_der(), pfx.to_der());
+    assert_eq!(read.key_bags("changeit").unwrap(), vec![key]);
+
+    assert!(PFX::from_file(&path).is_err());
+    assert!(PFX::from_file("clientcert.der").unwrap().is_err());
+}
+
+#[test]
+fn test_visit_bags() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let ca = fs::read("ca.der").unwrap();
+
+    let pfx = PfxBuilder::new()
+        .add_identity(&cert, &key, "look")
+        .add_named_ca(&ca, "root")
+        .build("changeit")
+        .unwrap();
+    let mut names = vec![];
+    let found = pfx
+        .visit_bags("changeit", |bag| {
+            names.push(bag.friendly_name());
+            ControlFlow::<()>::Continue(())
+        })
+        .unwrap();
+    assert_eq!(found, None);
+    assert_eq!(names.len(), 3);
+
+    let root = pfx
+        .visit_bags("changeit", |bag| match bag.friendly_name().as_deref() {
+            Some("root") => ControlFlow::Break(bag.bag.get_x509_cert()),
+            _ => ControlFlow::Continue(()),
+        })
+        .unwrap();
+    assert_eq!(root, Some(Some(ca)));
+
+    //the content after the first bag is never decrypted
+    let first = SafeBag::cert_x509(&cert).with_friendly_name("look");
+    let rest = EncryptedData::from_safe_bags::<AesCbcDataEncryptor, Pbkdf2>(
+        &[SafeBag::cert_x509(&cert)],
+        b"other",
+    )
+    .unwrap();
+    let pfx = PFX {
+        version: 3,
+        auth_safe: ContentInfo::Data(content_infos_der(&[
+            ContentInfo::Data(safe_contents_der(&[first])),
+            ContentInfo::EncryptedData(rest),
+        ])),
+        mac_data: None,
+    };
+    assert!(pfx.bags("changeit").is_err());
+    let name = pfx
+        .visit_bags("changeit", |bag| ControlFlow::Break(bag.friendly_name()))
+        .unwrap();
+    assert_eq!(name, Some(Some("look".to_owned())));
+}
+
+#[test]
+fn test_new_with_named_cas() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let ca = fs::read("ca.der").unwrap();
+
+    let p12 = PFX::new_with_named_cas::<AesCbcDataEncryptor, Pbkdf2>(
+        &cert,
+        &key,
+        &[(&ca, Some("root ca")), (&ca, None)],
+        "changeit",
+        "look",
+    )
+    .unwrap()
+    .to_der();
+    let pfx = PFX::parse(&p12).unwrap();
+    let certs: Vec<_> = pfx
+        .bags("changeit")
+        .unwrap()
+        .into_iter()
+        .filter(|bag| matches!(bag.bag, SafeBagKind::CertBag(_)))
+        .collect();
+    assert_eq!(certs.len(), 3);
+    assert_eq!(certs[0].friendly_name().as_deref(), Some("look"));
+    assert_eq!(certs[1].friendly_name().as_deref(), Some("root ca"));
+    assert_eq!(
+        certs[1].bag,
+        SafeBagKind::CertBag(CertBag::X509(ca.clone()))
+    );
+    assert_eq!(certs[2].friendly_name(), None);
+    assert!(certs[2].attributes.is_empty());
+}
+
+#[test]
+fn test_pfx_builder_secrets() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+
+    let secret_type = ObjectIdentifier::from_slice(&[1, 3, 6, 1, 4, 1, 99999, 2]);
+    let db = yasna::construct_der(|w| w.write_bytes(b"db password"));
+    let api = yasna::construct_der(|w| w.write_bytes(b"api token"));
+    let p12 = PfxBuilder::new()
+        .add_identity(&cert, &key, "look")
+        .add_secret(secret_type.clone(), &db, "db")
+        .add_secret(secret_type.clone(), &api, "api")
+        .build("changeit")
+        .unwrap()
+        .to_der();
+
+    let pfx = PFX::parse(&p12).unwrap();
+    assert!(pfx.verify_mac("changeit"));
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key]);
+    assert_eq!(pfx.cert_x509_bags("changeit").unwrap(), vec![cert]);
+
+    let secrets = pfx.secret_entries("changeit").unwrap();
+    assert_eq!(secrets.len(), 2);
+    assert_eq!(secrets[0].friendly_name.as_deref(), Some("db"));
+    assert_eq!(secrets[0].secret_type_id, secret_type);
+    assert_eq!(secrets[0].value, db);
+    assert_eq!(secrets[1].friendly_name.as_deref(), Some("api"));
+    assert_eq!(secrets[1].value, api);
+
+    let others = pfx.other_bags("changeit").unwrap();
+    assert_eq!(others.len(), 2);
+    assert_eq!(others[0].bag_id, *OID_SECRET_BAG);
+    let secret = yasna::parse_der(&others[0].bag_value, SecretBag::parse).unwrap();
+    assert_eq!(secret.secret_value, db);
+}
+
+#[test]
+fn test_pfx_builder_other_bag() {
+    let bag_id = ObjectIdentifier::from_slice(&[1, 3, 6, 1, 4, 1, 99999, 3]);
+    // BER with an indefinite length, kept as it is and not re-encoded
+    let value = vec![0x30, 0x80, 0x04, 0x01, 0x2a, 0x00, 0x00];
+    let attributes = vec![
+        PKCS12Attribute::FriendlyName("custom".to_owned()),
+        PKCS12Attribute::LocalKeyId(vec![1, 2, 3]),
+    ];
+    let p12 = PfxBuilder::new()
+        .add_other_bag(bag_id.clone(), value.clone(), attributes.clone())
+        .build("changeit")
+        .unwrap()
+        .to_der();
+
+    let pfx = PFX::parse(&p12).unwrap();
+    assert!(pfx.verify_mac("changeit"));
+    assert_eq!(
+        pfx.other_bags("changeit").unwrap(),
+        vec![OtherBag {
+            bag_id: bag_id.clone(),
+            bag_value: value.clone(),
+        }]
+    );
+    let bags = pfx.bags("changeit").unwrap();
+    assert_eq!(bags.len(), 1);
+    // the attributes are a DER SET OF, sorted when written
+    assert_eq!(bags[0].attributes.len(), attributes.len());
+    assert!(attributes
+        .iter()
+        .all(|attr| bags[0].attributes.contains(attr)));
+}
+
+#[test]
+fn test_encrypted_key_bags() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+
+    let pfx = PfxBuilder::new()
+        .add_identity(&cert, &key, "look")
+        .build("changeit")
+        .unwrap();
+    let keys = pfx.encrypted_key_bags().unwrap();
+    assert_eq!(keys.len(), 1);
+    let info = yasna::parse_der(&keys[0], EncryptedPrivateKeyInfo::parse).unwrap();
+    assert_eq!(info.to_der(), keys[0]);
+    assert_eq!(info.decrypt(b"changeit").unwrap(), key);
+}
+
+#[test]
+fn test_pfx_builder_add_encrypted_key() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+
+    let SafeBagKind::Pkcs8ShroudedKeyBag(epki) = AesCbcDataEncryptor::new()
+        .encrypt_keybag::<Pbkdf2>(&key, b"keypass")
+        .unwrap()
+    else {
+        panic!("expected a shrouded key bag");
+    };
+    let pfx = PfxBuilder::new()
+        .add_ca(&cert)
+        .add_encrypted_key(&epki.to_der(), "look")
+        .unwrap()
+        .build("changeit")
+        .unwrap();
+    assert_eq!(pfx.encrypted_key_bags().unwrap(), vec![epki.to_der()]);
+    assert_eq!(pfx.friendly_names_plaintext(), vec!["look".to_owned()]);
+    let handles = pfx.key_bag_handles("changeit").unwrap();
+    assert_eq!(handles.len(), 1);
+    //the PFX password may still unpad by chance, but never to the key
+    assert_ne!(handles[0].decrypt("changeit").ok(), Some(key.clone()));
+    assert_eq!(epki.decrypt(b"keypass").unwrap(), key);
+
+    assert!(PfxBuilder::new()
+        .add_encrypted_key(&[0x30], "look")
+        .is_err());
+}
+
+#[test]
+fn test_content_infos() {
+    let p12 = fs::read("openssl3_legacy.p12").unwrap();
+    let pfx = PFX::parse(&p12).unwrap();
+    let contents = pfx.content_infos().unwrap();
+    assert_eq!(contents.len(), 2);
+    assert!(matches!(
+        &contents[0],
+        ContentInfoSummary::EncryptedData(alg) if alg.to_string() == "PBE-SHA1-RC2-40"
+    ));
+    assert_eq!(contents[1], ContentInfoSummary::Data { bags: 1 });
+}
+
+#[test]
+fn test_friendly_names_plaintext() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
 
-    pub fn write(&self, w: DERWriter) {
-        w.write_sequence(|w| {
-            w.next().write_u8(self.version);
-            self.auth_safe.write(w.next());
-            if let Some(mac_data) = &self.mac_data {
-                mac_data.write(w.next())
-            }
-        })
-    }
+    let pfx = PfxBuilder::new()
+        .add_identity(&cert, &key, "look")
+        .add_named_ca(&cert, "not in plaintext")
+        .build("changeit")
+        .unwrap();
+    assert_eq!(pfx.friendly_names_plaintext(), vec!["look".to_owned()]);
+}
 
-    pub fn to_der(&self) -> Vec<u8> {
-        yasna::construct_der(|w| self.write(w))
-    }
-    pub fn bags(&self, password: &str) -> Result<Vec<SafeBag>, ASN1Error> {
-        let password = password.as_bytes();
+#[test]
+fn test_key_bag_handles() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
 
-        let data = self
-            .auth_safe
-            .data(password)
-            .ok_or_else(|| ASN1Error::new(ASN1ErrorKind::Invalid))?;
-        let contents = yasna::parse_ber(&data, |r| r.collect_sequence_of(ContentInfo::parse))?;
+    let pfx = PfxBuilder::new()
+        .add_identity(&cert, &key, "first")
+        .add_identity(&cert, &key, "second")
+        .build("changeit")
+        .unwrap();
+    let handles = pfx.key_bag_handles("changeit").unwrap();
+    assert_eq!(handles.len(), 2);
+    let second = handles
+        .iter()
+        .find(|handle| handle.friendly_name() == Some("second"))
+        .unwrap();
+    assert!(second.local_key_id().is_some());
+    assert_eq!(second.decrypt("changeit").unwrap(), key);
+}
 
-        let mut result = vec![];
-        for content in contents.iter() {
-            let data = content
-                .data(password)
-                .ok_or_else(|| ASN1Error::new(ASN1ErrorKind::Invalid))?;
+#[test]
+fn test_pfx_builder_mixed_encryption() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
 
-            let safe_bags = yasna::parse_ber(&data, |r| r.collect_sequence_of(SafeBag::parse))?;
+    let p12 = PfxBuilder::new()
+        .legacy_cert_encryption()
+        .key_encryption::<AesCbcDataEncryptor, Pbkdf2>()
+        .add_identity(&cert, &key, "look")
+        .build("changeit")
+        .unwrap()
+        .to_der();
 
-            for safe_bag in safe_bags.iter() {
-                result.push(safe_bag.to_owned())
-            }
-        }
-        Ok(result)
-    }
-    //DER-encoded X.509 certificate
-    pub fn cert_bags(&self, password: &str) -> Result<Vec<Vec<u8>>, ASN1Error> {
-        self.cert_x509_bags(password)
-    }
-    //DER-encoded X.509 certificate
-    pub fn cert_x509_bags(&self, password: &str) -> Result<Vec<Vec<u8>>, ASN1Error> {
-        let mut result = vec![];
-        for safe_bag in self.bags(password)? {
-            if let Some(cert) = safe_bag.bag.get_x509_cert() {
-                result.push(cert);
-            }
-        }
-        Ok(result)
-    }
-    pub fn cert_sdsi_bags(&self, password: &str) -> Result<Vec<String>, ASN1Error> {
-        let mut result = vec![];
-        for safe_bag in self.bags(password)? {
-            if let Some(cert) = safe_bag.bag.get_sdsi_cert() {
-                result.push(cert);
-            }
-        }
-        Ok(result)
-    }
-    pub fn key_bags(&self, password: &str) -> Result<Vec<Vec<u8>>, ASN1Error> {
-        let bmp_password = password.as_bytes();
-        let mut result = vec![];
-        for safe_bag in self.bags(password)? {
-            if let Some(key) = safe_bag.bag.get_key(bmp_password) {
-                result.push(key);
-            }
-        }
-        Ok(result)
-    }
+    let pfx = PFX::parse(&p12).unwrap();
+    assert!(pfx.verify_mac("changeit"));
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key]);
+    assert_eq!(pfx.cert_x509_bags("changeit").unwrap(), vec![cert]);
 
-    pub fn verify_mac(&self, password: &str) -> bool {
-        let bmp_password = bmp_string(password);
-        if let Some(mac_data) = &self.mac_data {
-            return match self.auth_safe.data(&bmp_password) {
-                Some(data) => mac_data.verify_mac(&data, &bmp_password),
-                None => false,
-            };
+    let ContentInfo::Data(auth_safe) = &pfx.auth_safe else {
+        panic!("expected a data auth_safe");
+    };
+    let contents =
+        yasna::parse_der(auth_safe, |r| r.collect_sequence_of(ContentInfo::parse)).unwrap();
+    let ContentInfo::EncryptedData(certs) = &contents[0] else {
+        panic!("expected encrypted certificates");
+    };
+    assert!(matches!(
+        certs.encrypted_content_info.content_encryption_algorithm,
+        AlgorithmIdentifier::PbewithSHAAnd40BitRC2CBC(_)
+    ));
+    for bag in pfx.bags("changeit").unwrap() {
+        if let SafeBagKind::Pkcs8ShroudedKeyBag(key_bag) = bag.bag {
+            assert!(matches!(
+                key_bag.encryption_algorithm,
+                AlgorithmIdentifier::Pbes2(_)
+            ));
         }
-        true
     }
 }
 
+#[test]
+fn test_pfx_builder_aes_certs_3des_key() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let ca = fs::read("ca.der").unwrap();
+
+    let p12 = PfxBuilder::new()
+        .cert_encryption::<AesCbcDataEncryptor, Pbkdf2>()
+        .legacy_key_encryption()
+        .add_identity(&cert, &key, "look")
+        .add_ca(&ca)
+        .build("changeit")
+        .unwrap()
+        .to_der();
+
+    let pfx = PFX::parse(&p12).unwrap();
+    assert!(pfx.verify_mac("changeit"));
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key]);
+    assert_eq!(pfx.cert_x509_bags("changeit").unwrap(), vec![cert, ca]);
+    let encrypted: Vec<_> = pfx
+        .content_infos()
+        .unwrap()
+        .into_iter()
+        .filter_map(|content| match content {
+            ContentInfoSummary::EncryptedData(alg) => Some(alg),
+            _ => None,
+        })
+        .collect();
+    assert!(matches!(
+        encrypted.as_slice(),
+        [AlgorithmIdentifier::Pbes2(params)]
+            if matches!(*params.encryption_scheme, AlgorithmIdentifier::AesCbcPad(_))
+    ));
+    let shrouded: Vec<_> = pfx
+        .bags("changeit")
+        .unwrap()
+        .into_iter()
+        .filter_map(|bag| match bag.bag {
+            SafeBagKind::Pkcs8ShroudedKeyBag(key) => Some(key.encryption_algorithm),
+            _ => None,
+        })
+        .collect();
+    assert!(matches!(
+        shrouded.as_slice(),
+        [AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(_)]
+    ));
+}
+
+#[test]
+fn test_pfx_builder_3des_certs_aes_key() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+
+    let p12 = PfxBuilder::new()
+        .cert_encryption::<PbeWithShaAnd3KeyTripleDesCbcEncryptor, PbeWithShaAnd3KeyTripleDesCbcEncryptKeyDeriver>()
+        .key_encryption::<AesCbcDataEncryptor, Pbkdf2>()
+        .add_identity(&cert, &key, "look")
+        .build("changeit")
+        .unwrap()
+        .to_der();
+
+    let pfx = PFX::parse(&p12).unwrap();
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key]);
+    assert_eq!(pfx.cert_x509_bags("changeit").unwrap(), vec![cert]);
+    assert!(pfx.content_infos().unwrap().iter().any(|content| matches!(
+        content,
+        ContentInfoSummary::EncryptedData(AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(_))
+    )));
+}
+
+/// The PKCS#12 key derivation function from RFC 7292 Appendix B.2.
+///
+/// `password` is expected to be BMPString encoded, including the two
+/// trailing zero bytes. `id` selects the purpose of the derived bytes:
+/// 1 for an encryption key, 2 for an IV and 3 for a MAC key.
+///
+/// Returns `None` when `out_len` exceeds 1024 bytes or the password or salt
+/// exceed 8192 bytes.
+///
+/// ```
+/// use sha1::Sha1;
+///
+/// let salt = [0x9a, 0xf4, 0x70, 0x29, 0x58, 0xa8, 0xe9, 0x5c];
+/// let key = p12::pkcs12_kdf::<Sha1>(&[0, 0], &salt, 2048, 2, 8).unwrap();
+/// assert_eq!(key, [0x8e, 0x9f, 0x8f, 0xc7, 0x66, 0x43, 0x78, 0xbc]);
+/// ```
+pub fn pkcs12_kdf<D: Digest + BlockSizeUser>(
+    password: &[u8],
+    salt: &[u8],
+    iterations: u64,
+    id: u8,
+    out_len: usize,
+) -> Option<Vec<u8>> {
+    pbepkcs12sha::<D>(password, salt, iterations, id, out_len as u64)
+}
+
+// A_i = H^iterations(D || I), appended to `a`. `iterations` is at least 1
 #[inline(always)]
-fn pbepkcs12shacore<D: Digest>(d: &[u8], i: &[u8], a: &mut Vec<u8>, iterations: u64) -> Vec<u8> {
-    let mut ai: Vec<u8> = d.iter().chain(i.iter()).cloned().collect();
-    for _ in 0..iterations {
-        ai = sha::<D>(&ai);
+fn pbepkcs12shacore<D: Digest>(d: &[u8], i: &[u8], a: &mut Vec<u8>, iterations: u64) -> Output<D> {
+    let mut ai = D::new().chain_update(d).chain_update(i).finalize();
+    for _ in 1..iterations {
+        ai = D::digest(&ai);
     }
-    a.append(&mut ai.clone());
+    a.extend_from_slice(&ai);
     ai
 }
 
+// bounds for the caller controlled sizes, far above anything a real file needs
+const KDF_MAX_OUTPUT_LEN: u64 = 1024;
+const KDF_MAX_INPUT_LEN: usize = 8192;
+
 #[allow(clippy::many_single_char_names)]
-fn pbepkcs12sha<D: Digest>(
+fn pbepkcs12sha<D: Digest + BlockSizeUser>(
     pass: &[u8],
     salt: &[u8],
     iterations: u64,
     id: u8,
     size: u64,
-) -> Vec<u8> {
-    const U: u64 = 160 / 8;
-    const V: u64 = 512 / 8;
+) -> Option<Vec<u8>> {
+    // u and v of RFC 7292 B.2, the digest output and block sizes
+    let u = <D as Digest>::output_size() as u64;
+    let v = D::block_size() as u64;
+    if size > KDF_MAX_OUTPUT_LEN || pass.len() > KDF_MAX_INPUT_LEN || salt.len() > KDF_MAX_INPUT_LEN
+    {
+        return None;
+    }
+    //zero iterations would leave the password unhashed
+    if iterations == 0 {
+        return None;
+    }
     let r: u64 = iterations;
-    let d = [id; V as usize];
-    fn get_len(s: usize) -> usize {
+    let d = vec![id; v as usize];
+    let get_len = |s: usize| -> usize {
         let s = s as u64;
-        (V * ((s + V - 1) / V)) as usize
-    }
+        (v * ((s + v - 1) / v)) as usize
+    };
+    // an empty salt gives an empty S as step 2 of B.2 says. that's weak but
+    // some writers do it, so it's read rather than rejected
     let s = salt.iter().cycle().take(get_len(salt.len()));
     let p = pass.iter().cycle().take(get_len(pass.len()));
     let mut i: Vec<u8> = s.chain(p).cloned().collect();
-    let c = (size + U - 1) / U;
-    let mut a: Vec<u8> = vec![];
+    let c = (size + u - 1) / u;
+    let mut a: Vec<u8> = Vec::with_capacity((c * u) as usize);
     for _ in 1..c {
         let ai = pbepkcs12shacore::<D>(&d, &i, &mut a, r);
 
-        let b: Vec<u8> = ai.iter().cycle().take(V as usize).cloned().collect();
+        let b: Vec<u8> = ai.iter().cycle().take(v as usize).cloned().collect();
 
         let b_iter = b.iter().rev().cycle().take(i.len());
         let i_b_iter = i.iter_mut().rev().zip(b_iter);
         let mut inc = 1u8;
         for (i3, (ii, bi)) in i_b_iter.enumerate() {
-            if ((i3 as u64) % V) == 0 {
+            if ((i3 as u64) % v) == 0 {
                 inc = 1;
             }
             let (ii2, inc2) = ii.overflowing_add(*bi);
@@ -1058,7 +5139,101 @@ fn pbepkcs12sha<D: Digest>(
 
     pbepkcs12shacore::<D>(&d, &i, &mut a, r);
 
-    a.iter().take(size as usize).cloned().collect()
+    Some(a.iter().take(size as usize).cloned().collect())
+}
+
+#[test]
+fn test_pbepkcs12sha_limits() {
+    let salt = [0u8; 8];
+    assert!(pbepkcs12sha::<Sha1>(&[0, 0], &salt, 1, 1, KDF_MAX_OUTPUT_LEN).is_some());
+    assert!(pbepkcs12sha::<Sha1>(&[0, 0], &salt, 1, 1, KDF_MAX_OUTPUT_LEN + 1).is_none());
+    let long = vec![0u8; KDF_MAX_INPUT_LEN + 1];
+    assert!(pbepkcs12sha::<Sha1>(&long, &salt, 1, 1, 8).is_none());
+    assert!(pbepkcs12sha::<Sha1>(&[0, 0], &long, 1, 1, 8).is_none());
+    assert!(pbepkcs12sha::<Sha1>(&[0, 0], &salt, 0, 1, 8).is_none());
+}
+
+#[test]
+fn test_pbepkcs12sha_empty_and_short_salt() {
+    use hex_literal::hex;
+    let password = bmp_string("changeit");
+    assert_eq!(
+        pbepkcs12sha::<Sha1>(&password, &[], 2048, 1, 24).unwrap(),
+        hex!("910935b87a5526a28989b24d5f69aa1ca551e6a731013765")
+    );
+    assert_eq!(
+        pbepkcs12sha::<Sha1>(&password, &[1, 2, 3], 2048, 1, 24).unwrap(),
+        hex!("f1637ab4340fa40e27783ac562c341efeef1436692422135")
+    );
+    let digest = pkcs12_mac::<Sha1>(&password, &[], 2048)
+        .unwrap()
+        .chain_update(b"data")
+        .finalize()
+        .into_bytes()
+        .to_vec();
+    let mac_data = MacData {
+        mac: DigestInfo {
+            digest_algorithm: AlgorithmIdentifier::sha1(),
+            digest,
+        },
+        salt: vec![],
+        iterations: 2048,
+    };
+    assert!(mac_data.verify_mac(b"data", &password));
+    assert!(!mac_data.verify_mac(b"data", &bmp_string("wrong")));
+}
+
+#[test]
+fn test_zero_iterations() {
+    let params = Pbkdf2Params {
+        salt: Pbkdf2Salt::Specified(vec![1; 16]),
+        iteration_count: 0,
+        key_length: None,
+        prf: Box::new(AlgorithmIdentifier::HmacWithSha256(None)),
+    };
+    assert!(Pbkdf2(AlgorithmIdentifier::Pbkdf2(params.clone()))
+        .derive_key(b"changeit")
+        .is_none());
+
+    let algorithm = AlgorithmIdentifier::Pbes2(Pkcs12Pbes2Params {
+        key_derivation_function: Box::new(AlgorithmIdentifier::Pbkdf2(params)),
+        encryption_scheme: Box::new(AlgorithmIdentifier::AesCbcPad(vec![0; 16])),
+    });
+    assert!(matches!(
+        algorithm.decrypt_pbe(&[0; 16], b"changeit"),
+        Err(Pkcs12Error::InvalidKdfParams)
+    ));
+    assert!(matches!(
+        Pkcs12Error::decryption_failed(&algorithm, &[0; 16]),
+        Pkcs12Error::InvalidKdfParams
+    ));
+
+    let mut mac_data =
+        MacData::new_with_algorithm(b"data", b"changeit", AlgorithmIdentifier::sha1(), 1).unwrap();
+    mac_data.iterations = 0;
+    assert!(!mac_data.verify_mac(b"data", &bmp_string("changeit")));
+}
+
+#[test]
+fn test_pbkdf2_iterations_past_u32() {
+    let params = Pbkdf2Params {
+        salt: Pbkdf2Salt::Specified(vec![1; 16]),
+        iteration_count: 1 << 32,
+        key_length: None,
+        prf: Box::new(AlgorithmIdentifier::HmacWithSha256(None)),
+    };
+    assert!(Pbkdf2(AlgorithmIdentifier::Pbkdf2(params.clone()))
+        .derive_key(b"changeit")
+        .is_none());
+
+    let algorithm = AlgorithmIdentifier::Pbes2(Pkcs12Pbes2Params {
+        key_derivation_function: Box::new(AlgorithmIdentifier::Pbkdf2(params)),
+        encryption_scheme: Box::new(AlgorithmIdentifier::AesCbcPad(vec![0; 16])),
+    });
+    assert!(matches!(
+        algorithm.decrypt_pbe(&[0; 16], b"changeit"),
+        Err(Pkcs12Error::InvalidKdfParams)
+    ));
 }
 
 fn pbe_with_sha1_and40_bit_rc2_cbc(
@@ -1071,14 +5246,195 @@ fn pbe_with_sha1_and40_bit_rc2_cbc(
     use rc2::Rc2;
     type Rc2Cbc = Decryptor<Rc2>;
 
-    let dk = pbepkcs12sha::<Sha1>(password, salt, iterations, 1, 5);
-    let iv = pbepkcs12sha::<Sha1>(password, salt, iterations, 2, 8);
+    let dk = pbepkcs12sha::<Sha1>(password, salt, iterations, 1, 5)?;
+    let iv = pbepkcs12sha::<Sha1>(password, salt, iterations, 2, 8)?;
 
     let rc2 = Rc2Cbc::new_from_slices(&dk, &iv).ok()?;
-    rc2.decrypt_padded_vec_mut::<Pkcs7>(data).ok()
+    decrypt_pkcs7(rc2, data)
+}
+
+// RC4 is a stream cipher, there is no IV and no padding
+#[cfg(feature = "legacy-insecure")]
+fn pbe_with_sha1_and_rc4(
+    data: &[u8],
+    password: &[u8],
+    salt: &[u8],
+    iterations: u64,
+    key_len: u64,
+) -> Option<Vec<u8>> {
+    let dk = pbepkcs12sha::<Sha1>(password, salt, iterations, 1, key_len)?;
+    Some(rc4(&dk, data))
+}
+
+#[cfg(feature = "legacy-insecure")]
+fn rc4(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut s: Vec<u8> = (0..=255).collect();
+    let mut j = 0u8;
+    for i in 0..256 {
+        j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+        s.swap(i, j as usize);
+    }
+    let (mut i, mut j) = (0u8, 0u8);
+    data.iter()
+        .map(|byte| {
+            i = i.wrapping_add(1);
+            j = j.wrapping_add(s[i as usize]);
+            s.swap(i as usize, j as usize);
+            byte ^ s[s[i as usize].wrapping_add(s[j as usize]) as usize]
+        })
+        .collect()
+}
+
+#[cfg(feature = "legacy-insecure")]
+#[test]
+fn test_rc4() {
+    use hex_literal::hex;
+    //RFC 6229 keystreams at offsets 0 and 4096, 40 and 128 bit keys
+    for (key, start, end) in [
+        (
+            &hex!("0102030405")[..],
+            hex!("b2396305f03dc027ccc3524a0a1118a8"),
+            hex!("ff25b58995996707e51fbdf08b34d875"),
+        ),
+        (
+            &hex!("0102030405060708090a0b0c0d0e0f10")[..],
+            hex!("9ac7cc9a609d1ef7b2932899cde41b97"),
+            hex!("a36a4c301ae8ac13610ccbc12256cacc"),
+        ),
+    ] {
+        let keystream = rc4(key, &[0; 4112]);
+        assert_eq!(keystream[..16], start);
+        assert_eq!(keystream[4096..], end);
+    }
+}
+
+#[cfg(feature = "legacy-insecure")]
+#[test]
+fn test_read_pbe_rc4() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let p12 = fs::read("pbe_rc4.p12").unwrap();
+
+    let pfx = PFX::parse(&p12).unwrap();
+    assert!(pfx.verify_mac("changeit"));
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key]);
+    assert_eq!(pfx.cert_x509_bags("changeit").unwrap(), vec![cert]);
+}
+
+/// PBKDF1 of RFC 8018 5.1, the key derivation of the PBES1 schemes.
+///
+/// The digest of password || salt is hashed again `iterations` - 1 times and
+/// the first `out_len` bytes are the key. `None` for zero iterations or an
+/// `out_len` longer than the digest.
+pub fn pbkdf1<D: Digest>(
+    password: &[u8],
+    salt: &[u8],
+    iterations: u64,
+    out_len: usize,
+) -> Option<Vec<u8>> {
+    if iterations == 0 || out_len > <D as Digest>::output_size() {
+        return None;
+    }
+    let mut t = D::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .finalize();
+    for _ in 1..iterations {
+        t = D::digest(&t);
+    }
+    Some(t[..out_len].to_vec())
+}
+
+#[test]
+fn test_pbkdf1() {
+    use hex_literal::hex;
+    let salt = hex!("78578e5a5d63cb06");
+    assert_eq!(
+        pbkdf1::<Sha1>(b"password", &salt, 1000, 16).unwrap(),
+        hex!("dc19847e05c64d2faf10ebfb4a3d2a20")
+    );
+    assert_eq!(
+        pbkdf1::<Sha256>(b"password", &salt, 1000, 32).unwrap(),
+        hex!("9697db8e657a3500b33babe789ee7b747ed93d0da7e13245a5e467b82c4a833a")
+    );
+    //a single iteration is the plain digest
+    assert_eq!(
+        pbkdf1::<Sha1>(b"pass", b"word", 1, 20).unwrap(),
+        sha::<Sha1>(b"password")
+    );
+    assert_eq!(pbkdf1::<Sha1>(b"password", &salt, 0, 16), None);
+    assert_eq!(pbkdf1::<Sha1>(b"password", &salt, 1, 21), None);
+}
+
+// PBES1 takes the DES key and the IV from the 16 bytes of PBKDF1 output.
+// the password is used as given, not as a BMPString
+#[cfg(feature = "legacy-insecure")]
+fn pbe_with_sha1_and_des_cbc(
+    data: &[u8],
+    password: &[u8],
+    salt: &[u8],
+    iterations: u64,
+) -> Option<Vec<u8>> {
+    use cbc::Decryptor;
+    use des::Des;
+
+    let dk = pbkdf1::<Sha1>(password, salt, iterations, 16)?;
+    let decryptor = Decryptor::<Des>::new_from_slices(&dk[..8], &dk[8..]).ok()?;
+    decrypt_pkcs7(decryptor, data)
+}
+
+#[cfg(feature = "legacy-insecure")]
+#[test]
+fn test_read_pbe_sha1_des() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    // openssl pkcs12 -export -legacy -keypbe PBE-SHA1-DES -certpbe PBE-SHA1-DES
+    let p12 = fs::read("pbe_sha1_des.p12").unwrap();
+
+    let pfx = PFX::parse(&p12).unwrap();
+    assert!(pfx.verify_mac("changeit"));
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key]);
+    assert_eq!(pfx.cert_x509_bags("changeit").unwrap(), vec![cert]);
+}
+
+#[test]
+fn test_pbe_des_algorithms() {
+    let p12 = fs::read("pbe_sha1_des.p12").unwrap();
+    let pfx = PFX::parse(&p12).unwrap();
+    let contents = pfx.content_infos().unwrap();
+    let ContentInfoSummary::EncryptedData(alg) = &contents[0] else {
+        panic!("{:?}", contents[0]);
+    };
+    assert_eq!(alg.to_string(), "PBE-SHA1-DES");
+    assert_eq!(alg.cipher_block_size(), Some(8));
+    assert_eq!(
+        pfx.to_der(),
+        p12,
+        "the PBES1 parameters are written back as read"
+    );
+
+    let md5 = AlgorithmIdentifier::PbeWithMD5AndDESCBC(Pkcs12PbeParams {
+        salt: vec![1; 8],
+        iterations: 2048,
+    });
+    let der = yasna::construct_der(|w| md5.write(w));
+    assert_eq!(
+        yasna::parse_der(&der, AlgorithmIdentifier::parse).unwrap(),
+        md5
+    );
+    assert!(matches!(
+        md5.decrypt_pbe(&[0; 16], b"changeit"),
+        Err(Pkcs12Error::UnsupportedAlgorithm(alg)) if alg == md5
+    ));
+    assert_eq!(md5.cipher_block_size(), None);
+    #[cfg(not(feature = "legacy-insecure"))]
+    assert!(matches!(
+        pfx.bags("changeit"),
+        Err(Pkcs12Error::UnsupportedAlgorithm(_))
+    ));
 }
 
-fn pbe_with_sha_and40_bit_rc2_cbc_encrypt<D: Digest>(
+fn pbe_with_sha_and40_bit_rc2_cbc_encrypt<D: Digest + BlockSizeUser>(
     data: &[u8],
     password: &[u8],
     salt: &[u8],
@@ -1088,8 +5444,8 @@ fn pbe_with_sha_and40_bit_rc2_cbc_encrypt<D: Digest>(
     use rc2::Rc2;
     type Rc2Cbc = Encryptor<Rc2>;
 
-    let dk = pbepkcs12sha::<D>(password, salt, iterations, 1, 5);
-    let iv = pbepkcs12sha::<D>(password, salt, iterations, 2, 8);
+    let dk = pbepkcs12sha::<D>(password, salt, iterations, 1, 5)?;
+    let iv = pbepkcs12sha::<D>(password, salt, iterations, 2, 8)?;
 
     let rc2 = Rc2Cbc::new_from_slices(&dk, &iv).ok()?;
     Some(rc2.encrypt_padded_vec_mut::<Pkcs7>(data))
@@ -1105,11 +5461,11 @@ fn pbe_with_sha_and3_key_triple_des_cbc(
     use des::TdesEde3;
     type TDesCbc = Decryptor<TdesEde3>;
 
-    let dk = pbepkcs12sha::<Sha1>(password, salt, iterations, 1, 24);
-    let iv = pbepkcs12sha::<Sha1>(password, salt, iterations, 2, 8);
+    let dk = pbepkcs12sha::<Sha1>(password, salt, iterations, 1, 24)?;
+    let iv = pbepkcs12sha::<Sha1>(password, salt, iterations, 2, 8)?;
 
     let tdes = TDesCbc::new_from_slices(&dk, &iv).ok()?;
-    tdes.decrypt_padded_vec_mut::<Pkcs7>(data).ok()
+    decrypt_pkcs7(tdes, data)
 }
 
 fn pbe_with_sha_and3_key_triple_des_cbc_encrypt(
@@ -1122,8 +5478,8 @@ fn pbe_with_sha_and3_key_triple_des_cbc_encrypt(
     use des::TdesEde3;
     type TDesCbc = Encryptor<TdesEde3>;
 
-    let dk = pbepkcs12sha::<Sha1>(password, salt, iterations, 1, 24);
-    let iv = pbepkcs12sha::<Sha1>(password, salt, iterations, 2, 8);
+    let dk = pbepkcs12sha::<Sha1>(password, salt, iterations, 1, 24)?;
+    let iv = pbepkcs12sha::<Sha1>(password, salt, iterations, 2, 8)?;
 
     let tdes = TDesCbc::new_from_slices(&dk, &iv).ok()?;
     Some(tdes.encrypt_padded_vec_mut::<Pkcs7>(data))
@@ -1142,13 +5498,44 @@ fn bmp_string(s: &str) -> Vec<u8> {
     bytes
 }
 
-#[derive(Debug, Clone)]
+// the encodings tried for `password`, an empty password is also tried as no
+// bytes at all instead of a lone BMP NUL. the fallback is speculative, no
+// file from a tool keyed that way (e.g. a macOS Keychain export) was at hand
+// to confirm it, and reading such exports isn't claimed
+fn pkcs12_passwords(password: &str) -> Vec<Vec<u8>> {
+    if password.is_empty() {
+        vec![bmp_string(password), Vec::new()]
+    } else {
+        vec![bmp_string(password)]
+    }
+}
+
+/// An SDSI certificate, only built from an IA5 (ASCII) string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SdsiCertificate(String);
+
+impl SdsiCertificate {
+    pub fn new(sdsi: &str) -> Result<Self, Pkcs12Error> {
+        if !sdsi.is_ascii() {
+            return Err(Pkcs12Error::NotIa5String);
+        }
+        Ok(SdsiCertificate(sdsi.to_owned()))
+    }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum CertBag {
     X509(Vec<u8>),
-    SDSI(String),
+    SDSI(SdsiCertificate),
 }
 
 impl CertBag {
+    pub fn sdsi(sdsi: &str) -> Result<Self, Pkcs12Error> {
+        Ok(CertBag::SDSI(SdsiCertificate::new(sdsi)?))
+    }
     pub fn parse(r: BERReader) -> Result<Self, ASN1Error> {
         r.read_sequence(|r| {
             let oid = r.next().read_oid()?;
@@ -1160,7 +5547,7 @@ impl CertBag {
                 let sdsi = r
                     .next()
                     .read_tagged(Tag::context(0), |r| r.read_ia5_string())?;
-                return Ok(CertBag::SDSI(sdsi));
+                return Ok(CertBag::SDSI(SdsiCertificate(sdsi)));
             }
             Err(ASN1Error::new(ASN1ErrorKind::Invalid))
         })
@@ -1175,13 +5562,241 @@ impl CertBag {
             CertBag::SDSI(sdsi) => {
                 w.next().write_oid(&OID_CERT_TYPE_SDSI_CERTIFICATE);
                 w.next()
-                    .write_tagged(Tag::context(0), |w| w.write_ia5_string(sdsi));
+                    .write_tagged(Tag::context(0), |w| w.write_ia5_string(sdsi.as_str()));
             }
         })
     }
+    pub fn to_der(&self) -> Vec<u8> {
+        yasna::construct_der(|w| self.write(w))
+    }
+}
+
+//certValue is [0] EXPLICIT OCTET STRING for x509Certificate (RFC 7292 4.2.3),
+//checked against the encoding OpenSSL writes
+#[test]
+fn test_cert_bag_x509_encoding() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let p12 = fs::read("pbes2_rc2.p12").unwrap();
+
+    let ours = yasna::construct_der(|w| CertBag::X509(cert.clone()).write(w));
+    let value = yasna::parse_der(&ours, |r| {
+        r.read_sequence(|r| {
+            assert_eq!(r.next().read_oid()?, *OID_CERT_TYPE_X509_CERTIFICATE);
+            r.next().read_tagged(Tag::context(0), |r| r.read_bytes())
+        })
+    })
+    .unwrap();
+    assert_eq!(value, cert);
+
+    let pfx = PFX::parse(&p12).unwrap();
+    let auth_safe = pfx.auth_safe_der("changeit").unwrap();
+    let contents =
+        yasna::parse_der(&auth_safe, |r| r.collect_sequence_of(ContentInfo::parse)).unwrap();
+    let mut cert_bags = vec![];
+    for content in contents {
+        let data = content.data(b"changeit").unwrap();
+        yasna::parse_der(&data, |r| {
+            r.read_sequence_of(|r| {
+                r.read_sequence(|r| {
+                    let oid = r.next().read_oid()?;
+                    let value = r.next().read_tagged(Tag::context(0), |r| r.read_der())?;
+                    r.read_optional(|r| r.read_der())?;
+                    if oid == *OID_CERT_BAG {
+                        cert_bags.push(value);
+                    }
+                    Ok(())
+                })
+            })
+        })
+        .unwrap();
+    }
+    assert_eq!(cert_bags[0], ours);
+}
+
+// just enough X.509 to get at subject and issuer names and the public key
+mod x509 {
+    use super::*;
+    use yasna::BERReaderSeq;
+
+    fn skip_rest(r: &mut BERReaderSeq) -> Result<(), ASN1Error> {
+        while r.read_optional(|r| r.read_der())?.is_some() {}
+        Ok(())
+    }
+
+    // DER of the subject Name of a certificate
+    #[cfg(feature = "x509")]
+    pub(crate) fn cert_subject(cert_der: &[u8]) -> Result<Vec<u8>, ASN1Error> {
+        yasna::parse_der(cert_der, |r| {
+            r.read_sequence(|r| {
+                let subject = r.next().read_sequence(|r| {
+                    //version [0] EXPLICIT DEFAULT v1
+                    r.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_der()))?;
+                    let _serial_number = r.next().read_der()?;
+                    let _signature = r.next().read_der()?;
+                    let _issuer = r.next().read_der()?;
+                    let _validity = r.next().read_der()?;
+                    let subject = r.next().read_der()?;
+                    skip_rest(r)?;
+                    Ok(subject)
+                })?;
+                skip_rest(r)?;
+                Ok(subject)
+            })
+        })
+    }
+
+    // DER of the SubjectPublicKeyInfo of a certificate
+    pub(crate) fn cert_spki(cert_der: &[u8]) -> Result<Vec<u8>, ASN1Error> {
+        yasna::parse_der(cert_der, |r| {
+            r.read_sequence(|r| {
+                let spki = r.next().read_sequence(|r| {
+                    r.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_der()))?;
+                    let _serial_number = r.next().read_der()?;
+                    let _signature = r.next().read_der()?;
+                    let _issuer = r.next().read_der()?;
+                    let _validity = r.next().read_der()?;
+                    let _subject = r.next().read_der()?;
+                    let spki = r.next().read_der()?;
+                    skip_rest(r)?;
+                    Ok(spki)
+                })?;
+                skip_rest(r)?;
+                Ok(spki)
+            })
+        })
+    }
+
+    // DER of the issuer Name of a CRL
+    #[cfg(feature = "x509")]
+    pub(crate) fn crl_issuer(crl_der: &[u8]) -> Result<Vec<u8>, ASN1Error> {
+        yasna::parse_der(crl_der, |r| {
+            r.read_sequence(|r| {
+                let issuer = r.next().read_sequence(|r| {
+                    //version INTEGER OPTIONAL, only present for v2
+                    r.read_optional(|r| r.read_tagged_implicit(TAG_INTEGER, |r| r.read_bytes()))?;
+                    let _signature = r.next().read_der()?;
+                    let issuer = r.next().read_der()?;
+                    skip_rest(r)?;
+                    Ok(issuer)
+                })?;
+                skip_rest(r)?;
+                Ok(issuer)
+            })
+        })
+    }
+
+    // the X.509 CRL of a CRLBag, `None` for other CRL types
+    #[cfg(feature = "x509")]
+    pub(crate) fn crl_bag_x509_crl(bag_value: &[u8]) -> Result<Option<Vec<u8>>, ASN1Error> {
+        yasna::parse_der(bag_value, |r| {
+            r.read_sequence(|r| {
+                let crl_id = r.next().read_oid()?;
+                let crl = r.next().read_tagged(Tag::context(0), |r| r.read_bytes())?;
+                if crl_id != *OID_CRL_TYPE_X509_CRL {
+                    return Ok(None);
+                }
+                Ok(Some(crl))
+            })
+        })
+    }
+}
+
+#[cfg(feature = "x509")]
+#[test]
+fn test_crl_covers() {
+    let cert = fs::read("clientcert.der").unwrap();
+
+    let crl_bag = |issuer: &[u8]| {
+        let crl = yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_sequence(|w| {
+                    w.next().write_u8(1);
+                    AlgorithmIdentifier::sha256().write(w.next());
+                    w.next().write_der(issuer);
+                    //thisUpdate
+                    w.next()
+                        .write_tagged_implicit(yasna::tags::TAG_UTCTIME, |w| {
+                            w.write_bytes(b"260101000000Z")
+                        });
+                });
+                AlgorithmIdentifier::sha256().write(w.next());
+                w.next().write_bitvec_bytes(&[0; 8], 64);
+            })
+        });
+        let bag_value = yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_oid(&OID_CRL_TYPE_X509_CRL);
+                w.next()
+                    .write_tagged(Tag::context(0), |w| w.write_bytes(&crl));
+            })
+        });
+        SafeBag {
+            bag: SafeBagKind::OtherBagKind(OtherBag {
+                bag_id: OID_CRL_BAG.clone(),
+                bag_value,
+            }),
+            attributes: vec![],
+            raw_der: None,
+        }
+    };
+    let pfx_with = |bags: &[SafeBag]| PFX {
+        version: 3,
+        auth_safe: ContentInfo::Data(content_infos_der(&[ContentInfo::Data(safe_contents_der(
+            bags,
+        ))])),
+        mac_data: None,
+    };
+
+    let cert_bag = SafeBag {
+        bag: SafeBagKind::CertBag(CertBag::X509(cert.clone())),
+        attributes: vec![],
+        raw_der: None,
+    };
+    let subject = x509::cert_subject(&cert).unwrap();
+    let pfx = pfx_with(&[cert_bag.clone(), crl_bag(&subject)]);
+    assert!(pfx.crl_covers("").unwrap());
+
+    let other_name = yasna::construct_der(|w| w.write_sequence(|_| {}));
+    let pfx = pfx_with(&[cert_bag, crl_bag(&other_name)]);
+    assert!(!pfx.crl_covers("").unwrap());
+    //no certificate with that subject in the file
+    assert!(!pfx_with(&[crl_bag(&subject)]).crl_covers("").unwrap());
+}
+
+#[test]
+fn test_cert_bag_sdsi() {
+    let bag = CertBag::sdsi("(certificate (issuer alice) (subject bob))").unwrap();
+    let der = yasna::construct_der(|w| bag.write(w));
+    assert_eq!(yasna::parse_der(&der, CertBag::parse).unwrap(), bag);
+
+    assert!(matches!(
+        CertBag::sdsi("(subject b\u{f6}b)"),
+        Err(Pkcs12Error::NotIa5String)
+    ));
+}
+
+#[test]
+fn test_cert_bag_sdsi_non_ascii() {
+    // a non-ASCII SDSI bag can't be built, so writing one can't panic
+    let non_ascii = "(subject b\u{f6}b)";
+    assert!(SdsiCertificate::new(non_ascii).is_err());
+    let bags = [
+        CertBag::sdsi(non_ascii),
+        SdsiCertificate::new(non_ascii).map(CertBag::SDSI),
+    ];
+    for bag in bags {
+        assert!(matches!(bag, Err(Pkcs12Error::NotIa5String)));
+    }
+
+    let sdsi = SdsiCertificate::new("(subject bob)").unwrap();
+    let der = yasna::construct_der(|w| CertBag::SDSI(sdsi.clone()).write(w));
+    let CertBag::SDSI(parsed) = yasna::parse_der(&der, CertBag::parse).unwrap() else {
+        panic!("expected an SDSI bag");
+    };
+    assert_eq!(parsed.as_str(), "(subject bob)");
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct EncryptedPrivateKeyInfo {
     pub encryption_algorithm: AlgorithmIdentifier,
     pub encrypted_data: Vec<u8>,
@@ -1206,16 +5821,155 @@ impl EncryptedPrivateKeyInfo {
             w.next().write_bytes(&self.encrypted_data);
         })
     }
-    pub fn decrypt(&self, password: &[u8]) -> Option<Vec<u8>> {
+    pub fn to_der(&self) -> Vec<u8> {
+        yasna::construct_der(|w| self.write(w))
+    }
+    pub fn decrypt(&self, password: &[u8]) -> Result<Vec<u8>, Pkcs12Error> {
         self.encryption_algorithm
             .decrypt_pbe(&self.encrypted_data, password)
     }
+    pub fn kdf_info(&self) -> Option<KdfInfo> {
+        self.encryption_algorithm.kdf_info()
+    }
+    /// Decrypts with `old_password` and shrouds the key again under `new_password`.
+    pub fn reencrypt(
+        &self,
+        old_password: &[u8],
+        new_password: &[u8],
+        encryptor: &impl DataEncryptor,
+        key_deriver: &impl KeyDeriver,
+    ) -> Option<EncryptedPrivateKeyInfo> {
+        let key = self.decrypt(old_password).ok()?;
+        match encryptor.encrypt_keybag_key_deriver(&key, new_password, key_deriver)? {
+            SafeBagKind::Pkcs8ShroudedKeyBag(epki) => Some(epki),
+            _ => None,
+        }
+    }
+}
+
+/// The algorithm OID of a DER encoded PKCS#8 PrivateKeyInfo, e.g.
+/// 1.2.840.113549.1.1.1 for RSA, 1.2.840.10045.2.1 for EC or 1.3.101.112
+/// for Ed25519. `None` when it doesn't parse.
+pub fn private_key_algorithm(pkcs8_der: &[u8]) -> Option<ObjectIdentifier> {
+    yasna::parse_ber(pkcs8_der, |r| {
+        r.read_sequence(|r| {
+            let _version = r.next().read_u8()?;
+            let algorithm = r.next().read_sequence(|r| {
+                let oid = r.next().read_oid()?;
+                r.read_optional(|r| r.read_der())?;
+                Ok(oid)
+            })?;
+            let _private_key = r.next().read_bytes()?;
+            //attributes [0] and publicKey [1] of OneAsymmetricKey
+            r.read_optional(|r| r.read_der())?;
+            r.read_optional(|r| r.read_der())?;
+            Ok(algorithm)
+        })
+    })
+    .ok()
+}
+
+/// Wraps a PKCS#1 RSAPrivateKey, the body of a `BEGIN RSA PRIVATE KEY` PEM,
+/// into the PKCS#8 PrivateKeyInfo key bags have to hold.
+///
+/// The input isn't checked, see `is_pkcs1_rsa_key`.
+pub fn wrap_pkcs1_rsa_as_pkcs8(pkcs1_der: &[u8]) -> Vec<u8> {
+    yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next().write_u8(0);
+            w.next().write_sequence(|w| {
+                w.next().write_oid(&OID_RSA_ENCRYPTION);
+                w.next().write_null();
+            });
+            w.next().write_bytes(pkcs1_der);
+        })
+    })
+}
+
+/// Whether `der` is a PKCS#1 RSAPrivateKey rather than a PKCS#8 PrivateKeyInfo.
+pub fn is_pkcs1_rsa_key(der: &[u8]) -> bool {
+    yasna::parse_der(der, |r| {
+        r.read_sequence(|r| {
+            let version = r.next().read_u8()?;
+            if version > 1 {
+                return Err(ASN1Error::new(ASN1ErrorKind::Invalid));
+            }
+            //modulus, exponents, primes and the CRT coefficient
+            for _ in 0..8 {
+                r.next().read_bigint_bytes()?;
+            }
+            //otherPrimeInfos of a multi-prime key
+            r.read_optional(|r| r.read_der())?;
+            Ok(())
+        })
+    })
+    .is_ok()
+}
+
+#[test]
+fn test_wrap_pkcs1_rsa_as_pkcs8() {
+    let key = fs::read("clientkey.der").unwrap();
+    let pkcs1 = yasna::parse_der(&key, |r| {
+        r.read_sequence(|r| {
+            r.next().read_u8()?;
+            r.next().read_der()?;
+            r.next().read_bytes()
+        })
+    })
+    .unwrap();
+
+    assert!(is_pkcs1_rsa_key(&pkcs1));
+    assert!(!is_pkcs1_rsa_key(&key));
+    assert_eq!(wrap_pkcs1_rsa_as_pkcs8(&pkcs1), key);
+}
+
+#[test]
+fn test_private_key_algorithm() {
+    let key = fs::read("clientkey.der").unwrap();
+    assert_eq!(
+        private_key_algorithm(&key),
+        Some(as_oid(&[1, 2, 840, 113_549, 1, 1, 1]))
+    );
+
+    //RFC 8410 Ed25519 example
+    let ed25519 = hex::decode(
+        "302e020100300506032b657004220420d4ee72dbf913584ad5b6d8f1f769f8ad3afe7c28cbf1d4fbe097a88f44755842",
+    )
+    .unwrap();
+    assert_eq!(
+        private_key_algorithm(&ed25519),
+        Some(as_oid(&[1, 3, 101, 112]))
+    );
+    assert_eq!(private_key_algorithm(&key[..key.len() - 1]), None);
+}
+
+#[test]
+fn test_encrypted_private_key_info_reencrypt() {
+    let SafeBagKind::Pkcs8ShroudedKeyBag(epki) = AesCbcDataEncryptor::new()
+        .encrypt_keybag::<Pbkdf2>(b"key", b"old")
+        .unwrap()
+    else {
+        panic!("expected a shrouded key bag");
+    };
+    let reencrypted = epki
+        .reencrypt(
+            b"old",
+            b"new",
+            &PbeWithShaAnd40BitRc2CbcEncryptor,
+            &PbeWithShaAnd40BitRc2CbcEncryptKeyDeriver::default(),
+        )
+        .unwrap();
+    assert!(matches!(
+        reencrypted.encryption_algorithm,
+        AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(_)
+    ));
+    assert_eq!(reencrypted.decrypt(b"new").unwrap(), b"key");
 }
 
 #[test]
 fn test_encrypted_private_key_info() {
     let epki = EncryptedPrivateKeyInfo {
-        encryption_algorithm: AlgorithmIdentifier::Sha1,
+        encryption_algorithm: AlgorithmIdentifier::sha1(),
         encrypted_data: b"foo".to_vec(),
     };
     let der = yasna::construct_der(|w| {
@@ -1225,19 +5979,229 @@ fn test_encrypted_private_key_info() {
     assert_eq!(epki2, epki);
 }
 
+#[test]
+fn test_encrypted_private_key_info_kdf_info() {
+    let pbes1 = EncryptedPrivateKeyInfo {
+        encryption_algorithm: AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(Pkcs12PbeParams {
+            salt: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            iterations: 2048,
+        }),
+        encrypted_data: vec![],
+    };
+    let info = pbes1.kdf_info().unwrap();
+    assert_eq!(info.salt, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+    assert_eq!(info.iterations, 2048);
+    assert_eq!(info.prf, None);
+
+    let SafeBagKind::Pkcs8ShroudedKeyBag(pbes2) = AesCbcDataEncryptor::new()
+        .encrypt_keybag::<Pbkdf2>(b"key", b"changeit")
+        .unwrap()
+    else {
+        panic!("expected a shrouded key bag");
+    };
+    let der = yasna::construct_der(|w| pbes2.write(w));
+    let pbes2 = yasna::parse_ber(&der, EncryptedPrivateKeyInfo::parse).unwrap();
+    let info = pbes2.kdf_info().unwrap();
+    assert_eq!(info.salt.len(), 16);
+    assert_eq!(info.iterations, 2048);
+    assert_eq!(info.prf, Some(AlgorithmIdentifier::HmacWithSha256(None)));
+}
+
+#[test]
+fn test_strength() {
+    let pbes2 = |prf, iteration_count, encryption_scheme| {
+        AlgorithmIdentifier::Pbes2(Pkcs12Pbes2Params {
+            key_derivation_function: Box::new(AlgorithmIdentifier::Pbkdf2(Pbkdf2Params {
+                salt: Pbkdf2Salt::Specified(vec![1; 16]),
+                iteration_count,
+                key_length: None,
+                prf: Box::new(prf),
+            })),
+            encryption_scheme: Box::new(encryption_scheme),
+        })
+    };
+    let aes = AlgorithmIdentifier::AesCbcPad(vec![0; 16]);
+    let tdes = AlgorithmIdentifier::DesEde3Cbc(vec![0; 8]);
+    let sha256 = AlgorithmIdentifier::HmacWithSha256(None);
+    let params = Pkcs12PbeParams {
+        salt: vec![1; 8],
+        iterations: 2048,
+    };
+    for (alg, strength) in [
+        (
+            pbes2(sha256.clone(), 600_000, aes.clone()),
+            Strength::Secure,
+        ),
+        (pbes2(sha256.clone(), 2048, aes.clone()), Strength::Weak),
+        (pbes2(sha256.clone(), 1000, aes.clone()), Strength::Weak),
+        (pbes2(sha256.clone(), 999, aes.clone()), Strength::Broken),
+        (
+            pbes2(
+                AlgorithmIdentifier::OtherAlg(OtherAlgorithmIdentifier {
+                    algorithm_type: as_oid(&[1, 2, 3, 4]),
+                    params: None,
+                }),
+                600_000,
+                aes.clone(),
+            ),
+            Strength::Broken,
+        ),
+        (
+            pbes2(AlgorithmIdentifier::HmacWithSha1(None), 2048, aes),
+            Strength::Weak,
+        ),
+        (pbes2(sha256, 2048, tdes), Strength::Weak),
+        (
+            AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(params.clone()),
+            Strength::Weak,
+        ),
+        (
+            AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(Pkcs12PbeParams {
+                salt: vec![1; 8],
+                iterations: 1,
+            }),
+            Strength::Broken,
+        ),
+        (
+            AlgorithmIdentifier::PbewithSHAAnd40BitRC2CBC(params.clone()),
+            Strength::Broken,
+        ),
+        (
+            AlgorithmIdentifier::PbeWithSHA1And128BitRC4(params.clone()),
+            Strength::Broken,
+        ),
+        (
+            AlgorithmIdentifier::PbeWithSHA1AndDESCBC(params),
+            Strength::Broken,
+        ),
+        (AlgorithmIdentifier::sha1(), Strength::Broken),
+        (AlgorithmIdentifier::sha256(), Strength::Secure),
+    ] {
+        assert_eq!(alg.strength(), strength, "{alg}");
+    }
+    assert!(Strength::Broken < Strength::Weak && Strength::Weak < Strength::Secure);
+    // what `PfxBuilder` writes by default, AES-256 with 2048 PBKDF2 iterations
+    let pfx = PfxBuilder::new()
+        .add_identity(
+            &fs::read("clientcert.der").unwrap(),
+            &fs::read("clientkey.der").unwrap(),
+            "look",
+        )
+        .build("changeit")
+        .unwrap();
+    for content in pfx.content_infos().unwrap() {
+        if let ContentInfoSummary::EncryptedData(alg) = content {
+            assert_eq!(alg.strength(), Strength::Weak);
+        }
+    }
+}
+
+#[test]
+fn test_iteration_count() {
+    let pbes1 = AlgorithmIdentifier::PbewithSHAAnd40BitRC2CBC(Pkcs12PbeParams {
+        salt: vec![1; 8],
+        iterations: 2048,
+    });
+    assert_eq!(pbes1.iteration_count(), Some(2048));
+
+    let pbkdf2 = |salt| {
+        AlgorithmIdentifier::Pbes2(Pkcs12Pbes2Params {
+            key_derivation_function: Box::new(AlgorithmIdentifier::Pbkdf2(Pbkdf2Params {
+                salt,
+                iteration_count: 600_000,
+                key_length: None,
+                prf: Box::new(AlgorithmIdentifier::HmacWithSha256(None)),
+            })),
+            encryption_scheme: Box::new(AlgorithmIdentifier::AesCbcPad(vec![0; 16])),
+        })
+    };
+    assert_eq!(
+        pbkdf2(Pbkdf2Salt::Specified(vec![1; 16])).iteration_count(),
+        Some(600_000)
+    );
+    let other_source = pbkdf2(Pbkdf2Salt::OtherSource(Box::new(
+        AlgorithmIdentifier::sha1(),
+    )));
+    assert_eq!(other_source.kdf_info(), None);
+    assert_eq!(other_source.iteration_count(), Some(600_000));
+
+    assert_eq!(AlgorithmIdentifier::sha256().iteration_count(), None);
+    assert_eq!(
+        AlgorithmIdentifier::AesCbcPad(vec![0; 16]).iteration_count(),
+        None
+    );
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecretBag {
+    pub secret_type_id: ObjectIdentifier,
+    /// DER-encoded secret value.
+    pub secret_value: Vec<u8>,
+}
+
+impl SecretBag {
+    pub fn parse(r: BERReader) -> Result<Self, ASN1Error> {
+        r.read_sequence(|r| {
+            let secret_type_id = r.next().read_oid()?;
+            let secret_value = r.next().read_tagged(Tag::context(0), |r| r.read_der())?;
+            Ok(SecretBag {
+                secret_type_id,
+                secret_value,
+            })
+        })
+    }
+    pub fn write(&self, w: DERWriter) {
+        w.write_sequence(|w| {
+            w.next().write_oid(&self.secret_type_id);
+            w.next()
+                .write_tagged(Tag::context(0), |w| w.write_der(&self.secret_value));
+        })
+    }
+}
+
+/// A secret bag together with its friendly name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecretEntry {
+    pub friendly_name: Option<String>,
+    pub secret_type_id: ObjectIdentifier,
+    /// DER-encoded secret value.
+    pub value: Vec<u8>,
+}
+
 #[derive(Debug, Clone)]
+pub struct KeyHandle {
+    friendly_name: Option<String>,
+    local_key_id: Option<Vec<u8>>,
+    key: EncryptedPrivateKeyInfo,
+}
+
+impl KeyHandle {
+    pub fn friendly_name(&self) -> Option<&str> {
+        self.friendly_name.as_deref()
+    }
+    pub fn local_key_id(&self) -> Option<&[u8]> {
+        self.local_key_id.as_deref()
+    }
+    /// DER-encoded PKCS#8 private key.
+    pub fn decrypt(&self, password: &str) -> Result<Vec<u8>, Pkcs12Error> {
+        self.key.decrypt(password.as_bytes())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OtherBag {
     pub bag_id: ObjectIdentifier,
     pub bag_value: Vec<u8>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SafeBagKind {
-    //KeyBag(),
+    // an unencrypted PKCS#8 PrivateKeyInfo
+    KeyBag(Vec<u8>),
     Pkcs8ShroudedKeyBag(EncryptedPrivateKeyInfo),
     CertBag(CertBag),
     //CRLBag(),
-    //SecretBag(),
+    SecretBag(SecretBag),
     //SafeContents(Vec<SafeBag>),
     OtherBagKind(OtherBag),
 }
@@ -1247,6 +6211,12 @@ impl SafeBagKind {
         if bag_id == *OID_CERT_BAG {
             return Ok(SafeBagKind::CertBag(CertBag::parse(r)?));
         }
+        if bag_id == *OID_SECRET_BAG {
+            return Ok(SafeBagKind::SecretBag(SecretBag::parse(r)?));
+        }
+        if bag_id == *OID_KEY_BAG {
+            return Ok(SafeBagKind::KeyBag(r.read_der()?));
+        }
         if bag_id == *OID_PKCS8_SHROUDED_KEY_BAG {
             return Ok(SafeBagKind::Pkcs8ShroudedKeyBag(
                 EncryptedPrivateKeyInfo::parse(r)?,
@@ -1257,15 +6227,23 @@ impl SafeBagKind {
     }
     pub fn write(&self, w: DERWriter) {
         match self {
+            SafeBagKind::KeyBag(key) => w.write_der(key),
             SafeBagKind::Pkcs8ShroudedKeyBag(epk) => epk.write(w),
             SafeBagKind::CertBag(cb) => cb.write(w),
+            SafeBagKind::SecretBag(sb) => sb.write(w),
             SafeBagKind::OtherBagKind(other) => w.write_der(&other.bag_value),
         }
     }
+    /// The bagValue alone, without the bag id and the `[0]` tag around it.
+    pub fn to_der(&self) -> Vec<u8> {
+        yasna::construct_der(|w| self.write(w))
+    }
     pub fn oid(&self) -> ObjectIdentifier {
         match self {
+            SafeBagKind::KeyBag(_) => OID_KEY_BAG.clone(),
             SafeBagKind::Pkcs8ShroudedKeyBag(_) => OID_PKCS8_SHROUDED_KEY_BAG.clone(),
             SafeBagKind::CertBag(_) => OID_CERT_BAG.clone(),
+            SafeBagKind::SecretBag(_) => OID_SECRET_BAG.clone(),
             SafeBagKind::OtherBagKind(other) => other.bag_id.clone(),
         }
     }
@@ -1278,50 +6256,60 @@ impl SafeBagKind {
 
     pub fn get_sdsi_cert(&self) -> Option<String> {
         if let SafeBagKind::CertBag(CertBag::SDSI(sdsi)) = self {
-            return Some(sdsi.to_owned());
+            return Some(sdsi.as_str().to_owned());
         }
         None
     }
 
+    /// The key of a shrouded key bag, or of a plain key bag regardless of the password.
     pub fn get_key(&self, password: &[u8]) -> Option<Vec<u8>> {
-        if let SafeBagKind::Pkcs8ShroudedKeyBag(kb) = self {
-            return kb.decrypt(password);
+        match self {
+            SafeBagKind::KeyBag(key) => Some(key.clone()),
+            SafeBagKind::Pkcs8ShroudedKeyBag(kb) => kb.decrypt(password).ok(),
+            _ => None,
         }
-        None
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OtherAttribute {
     pub oid: ObjectIdentifier,
     pub data: Vec<Vec<u8>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PKCS12Attribute {
     FriendlyName(String),
     LocalKeyId(Vec<u8>),
     Other(OtherAttribute),
 }
 
+fn single_value<T>(mut values: Vec<T>) -> Result<T, ASN1Error> {
+    if values.len() != 1 {
+        return Err(ASN1Error::new(ASN1ErrorKind::Invalid));
+    }
+    Ok(values.remove(0))
+}
+
 impl PKCS12Attribute {
+    /// friendlyName and localKeyId are SINGLE VALUE attributes (RFC 2985), a
+    /// value set that doesn't hold exactly one element is rejected. Values of
+    /// other attributes are all kept.
     pub fn parse(r: BERReader) -> Result<Self, ASN1Error> {
         r.read_sequence(|r| {
             let oid = r.next().read_oid()?;
             if oid == *OID_FRIENDLY_NAME {
-                let name = r
-                    .next()
-                    .collect_set_of(|s| s.read_bmp_string())?
-                    .pop()
-                    .ok_or_else(|| ASN1Error::new(ASN1ErrorKind::Invalid))?;
+                let name = single_value(r.next().collect_set_of(|s| {
+                    if s.lookahead_tag()? == TAG_UTF8STRING {
+                        s.read_utf8string()
+                    } else {
+                        s.read_bmp_string()
+                    }
+                })?)?;
                 return Ok(PKCS12Attribute::FriendlyName(name));
             }
             if oid == *OID_LOCAL_KEY_ID {
-                let local_key_id = r
-                    .next()
-                    .collect_set_of(|s| s.read_bytes())?
-                    .pop()
-                    .ok_or_else(|| ASN1Error::new(ASN1ErrorKind::Invalid))?;
+                let local_key_id = single_value(r.next().collect_set_of(|s| s.read_bytes())?)?;
                 return Ok(PKCS12Attribute::LocalKeyId(local_key_id));
             }
 
@@ -1353,14 +6341,272 @@ impl PKCS12Attribute {
         })
     }
 }
+#[test]
+fn test_read_friendly_name_encodings() {
+    let key = fs::read("clientkey.der").unwrap();
+    let bmp = yasna::construct_der(|w| w.write_bmp_string("l\u{f6}ok"));
+    let utf8 = yasna::construct_der(|w| w.write_utf8_string("l\u{f6}ok"));
+    let contains =
+        |der: &[u8], encoded: &[u8]| der.windows(encoded.len()).any(|window| window == encoded);
+    // friendly_name_bmp.p12 is from `openssl pkcs12 -export -name`,
+    // friendly_name_utf8.p12 from `PfxBuilder::friendly_name_utf8(true)`
+    for (file, encoded) in [
+        ("friendly_name_bmp.p12", &bmp),
+        ("friendly_name_utf8.p12", &utf8),
+    ] {
+        let pfx = PFX::parse(&fs::read(file).unwrap()).unwrap();
+        assert!(pfx.verify_mac("changeit"));
+        assert!(contains(&pfx.auth_safe_der("changeit").unwrap(), encoded));
+        assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key.clone()]);
+        let bags = pfx.bags("changeit").unwrap();
+        assert_eq!(bags.len(), 2);
+        for mut bag in bags {
+            assert_eq!(bag.friendly_name().as_deref(), Some("l\u{f6}ok"));
+            //an unchanged bag is written back as it was read
+            assert!(contains(&bag.to_der(), encoded));
+            //encoded afresh as a BMPString whichever way it was read
+            bag.raw_der = None;
+            let der = bag.to_der();
+            assert!(contains(&der, &bmp));
+            assert!(!contains(&der, &utf8));
+            assert_eq!(yasna::parse_der(&der, SafeBag::parse).unwrap(), bag);
+        }
+    }
+}
+
+#[test]
+fn test_read_empty_password_zero_length() {
+    // written by this crate, not a macOS export: RC2-40 certs, a 3DES shrouded
+    // key and a SHA-1 MAC, all keyed with a zero-length password
+    let pfx = PFX::parse(&fs::read("empty_password_zero_length.p12").unwrap()).unwrap();
+    assert!(pfx.verify_mac(""));
+    assert!(!pfx.verify_mac("changeit"));
+    assert_eq!(
+        pfx.key_bags("").unwrap(),
+        vec![fs::read("clientkey.der").unwrap()]
+    );
+    assert_eq!(
+        pfx.cert_x509_bags("").unwrap(),
+        vec![fs::read("clientcert.der").unwrap()]
+    );
+}
+
+#[test]
+fn test_read_combined_safe_contents() {
+    // the shrouded key and both certificates in one encrypted SafeContents.
+    // not OpenSSL output, `openssl pkcs12 -export` always writes the key in a
+    // plain data content. assembled with this crate's EncryptedData and
+    // shrouded key bag encryption: AES-256-CBC with PBKDF2 and a SHA-256 MAC
+    let pfx = PFX::parse(&fs::read("combined_safe_contents.p12").unwrap()).unwrap();
+    assert!(matches!(
+        pfx.content_infos().unwrap().as_slice(),
+        [ContentInfoSummary::EncryptedData(_)]
+    ));
+    assert!(pfx.verify_mac("changeit"));
+    let cert = fs::read("clientcert.der").unwrap();
+    assert_eq!(
+        pfx.key_bags("changeit").unwrap(),
+        vec![fs::read("clientkey.der").unwrap()]
+    );
+    assert_eq!(
+        pfx.cert_bags("changeit").unwrap(),
+        vec![cert.clone(), fs::read("ca.der").unwrap()]
+    );
+    assert_eq!(pfx.leaf_cert("changeit").unwrap(), Some(cert));
+}
+
+#[test]
+fn test_leaf_cert() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let ca = fs::read("ca.der").unwrap();
+    let pfx = PfxBuilder::new()
+        .add_ca(&ca)
+        .add_identity(&cert, &key, "look")
+        .build("changeit")
+        .unwrap();
+    assert_eq!(pfx.cert_x509_bags("changeit").unwrap()[0], ca);
+    assert_eq!(pfx.leaf_cert("changeit").unwrap(), Some(cert));
+    // no localKeyId links, the first certificate
+    let pfx = PfxBuilder::new().add_ca(&ca).build("changeit").unwrap();
+    assert_eq!(pfx.leaf_cert("changeit").unwrap(), Some(ca));
+    let pfx = PfxBuilder::new().build("changeit").unwrap();
+    assert_eq!(pfx.leaf_cert("changeit").unwrap(), None);
+}
+
+#[test]
+fn test_extra_padding_block() {
+    use cbc::Encryptor;
+    use cipher::block_padding::NoPadding;
+    use des::TdesEde3;
+
+    let key = fs::read("clientkey.der").unwrap();
+    let (password, salt) = (bmp_string("changeit"), [7; 8]);
+    let dk = pbepkcs12sha::<Sha1>(&password, &salt, 2048, 1, 24).unwrap();
+    let iv = pbepkcs12sha::<Sha1>(&password, &salt, 2048, 2, 8).unwrap();
+    let encrypt = |plain: &[u8]| {
+        let pad = 8 - plain.len() % 8;
+        let mut padded = plain.to_vec();
+        padded.extend(vec![pad as u8; pad]);
+        padded.extend([8; 8]);
+        Encryptor::<TdesEde3>::new_from_slices(&dk, &iv)
+            .unwrap()
+            .encrypt_padded_vec_mut::<NoPadding>(&padded)
+    };
+    let decrypted = pbe_with_sha_and3_key_triple_des_cbc(&encrypt(&key), &password, &salt, 2048);
+    if cfg!(feature = "lenient-padding") {
+        assert_eq!(decrypted, Some(key));
+        // the extra block stays unless stripping it leaves a SEQUENCE
+        let decrypted =
+            pbe_with_sha_and3_key_triple_des_cbc(&encrypt(b"changeit"), &password, &salt, 2048);
+        assert_eq!(
+            decrypted.unwrap(),
+            b"changeit\x08\x08\x08\x08\x08\x08\x08\x08"
+        );
+    } else {
+        // strict, the extra block is taken as part of the plaintext
+        assert_ne!(decrypted, Some(key));
+    }
+}
+
+#[test]
+fn test_salts_and_ivs_not_reused() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let ca = fs::read("ca.der").unwrap();
+    // the salts and IVs of the cert content, the shrouded key and the MAC
+    fn randoms(pfx: &PFX) -> Vec<Vec<u8>> {
+        let mut algs = vec![];
+        for content in pfx.content_infos().unwrap() {
+            if let ContentInfoSummary::EncryptedData(alg) = content {
+                algs.push(alg);
+            }
+        }
+        for bag in pfx.bags("changeit").unwrap() {
+            if let SafeBagKind::Pkcs8ShroudedKeyBag(key) = bag.bag {
+                algs.push(key.encryption_algorithm);
+            }
+        }
+        let mut result = vec![pfx.mac_data.as_ref().unwrap().salt.clone()];
+        for alg in algs {
+            match alg {
+                AlgorithmIdentifier::Pbes2(params) => {
+                    let AlgorithmIdentifier::Pbkdf2(Pbkdf2Params {
+                        salt: Pbkdf2Salt::Specified(salt),
+                        ..
+                    }) = *params.key_derivation_function
+                    else {
+                        panic!("not PBKDF2");
+                    };
+                    let AlgorithmIdentifier::AesCbcPad(iv) = *params.encryption_scheme else {
+                        panic!("not AES-CBC");
+                    };
+                    result.extend([salt, iv]);
+                }
+                AlgorithmIdentifier::PbewithSHAAnd40BitRC2CBC(params)
+                | AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(params) => {
+                    result.push(params.salt)
+                }
+                alg => panic!("unexpected {}", alg),
+            }
+        }
+        result
+    }
+    let modern = || {
+        PFX::new_with_cas::<AesCbcDataEncryptor, Pbkdf2>(&cert, &key, &[&ca], "changeit", "look")
+            .unwrap()
+    };
+    let legacy = || {
+        PfxBuilder::new()
+            .legacy_encryption()
+            .add_identity(&cert, &key, "look")
+            .build("changeit")
+            .unwrap()
+    };
+    for (build, count) in [(&modern as &dyn Fn() -> PFX, 5), (&legacy, 3)] {
+        let mut all = randoms(&build());
+        all.extend(randoms(&build()));
+        assert_eq!(all.len(), count * 2);
+        for (i, a) in all.iter().enumerate() {
+            assert!(a.len() >= 8);
+            assert!(all[i + 1..].iter().all(|b| a != b));
+        }
+    }
+}
+
+#[test]
+fn test_pkcs12_attribute_multiple_values() {
+    let single = yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next().write_oid(&OID_FRIENDLY_NAME);
+            w.next().write_set_of(|w| w.next().write_bmp_string("look"));
+        })
+    });
+    let attr = yasna::parse_der(&single, PKCS12Attribute::parse).unwrap();
+    assert!(matches!(attr, PKCS12Attribute::FriendlyName(name) if name == "look"));
+
+    let multiple = yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next().write_oid(&OID_FRIENDLY_NAME);
+            w.next().write_set_of(|w| {
+                w.next().write_bmp_string("look");
+                w.next().write_bmp_string("other");
+            });
+        })
+    });
+    assert!(yasna::parse_der(&multiple, PKCS12Attribute::parse).is_err());
+
+    let empty = yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next().write_oid(&OID_LOCAL_KEY_ID);
+            w.next().write_set_of(|_| {});
+        })
+    });
+    assert!(yasna::parse_der(&empty, PKCS12Attribute::parse).is_err());
+
+    let other = yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next()
+                .write_oid(&ObjectIdentifier::from_slice(&[1, 2, 3, 4]));
+            w.next().write_set_of(|w| {
+                w.next().write_u8(1);
+                w.next().write_u8(2);
+            });
+        })
+    });
+    let attr = yasna::parse_der(&other, PKCS12Attribute::parse).unwrap();
+    assert!(matches!(attr, PKCS12Attribute::Other(other) if other.data.len() == 2));
+}
+
 #[derive(Debug, Clone)]
 pub struct SafeBag {
     pub bag: SafeBagKind,
     pub attributes: Vec<PKCS12Attribute>,
+    /// The DER the bag was parsed from, `None` for bags built in code.
+    ///
+    /// `write` gives these bytes back unchanged as long as they are DER and
+    /// `bag` and `attributes` still match them, so a parsed bag keeps an
+    /// empty attribute SET or the attribute order of its writer.
+    pub raw_der: Option<Vec<u8>>,
+}
+
+// `raw_der` only keeps an encoding, bags are equal by their content
+impl PartialEq for SafeBag {
+    fn eq(&self, other: &Self) -> bool {
+        self.bag == other.bag && self.attributes == other.attributes
+    }
 }
 
+impl Eq for SafeBag {}
+
 impl SafeBag {
     pub fn parse(r: BERReader) -> Result<Self, ASN1Error> {
+        let raw_der = r.read_der()?;
+        let mut bag = yasna::parse_ber(&raw_der, SafeBag::parse_content)?;
+        bag.raw_der = Some(raw_der);
+        Ok(bag)
+    }
+    fn parse_content(r: BERReader) -> Result<Self, ASN1Error> {
         r.read_sequence(|r| {
             let oid = r.next().read_oid()?;
 
@@ -1368,19 +6614,32 @@ impl SafeBag {
                 .next()
                 .read_tagged(Tag::context(0), |r| SafeBagKind::parse(r, oid))?;
 
+            //an absent and an empty attribute SET both give no attributes
             let attributes = r
                 .read_optional(|r| r.collect_set_of(PKCS12Attribute::parse))?
                 .unwrap_or_else(Vec::new);
 
-            Ok(SafeBag { bag, attributes })
+            Ok(SafeBag {
+                bag,
+                attributes,
+                raw_der: None,
+            })
         })
     }
     pub fn write(&self, w: DERWriter) {
+        if let Some(raw_der) = &self.raw_der {
+            //BER or a bag changed since parsing is encoded afresh
+            if yasna::parse_der(raw_der, SafeBag::parse_content).map_or(false, |bag| bag == *self) {
+                w.write_der(raw_der);
+                return;
+            }
+        }
         w.write_sequence(|w| {
             w.next().write_oid(&self.bag.oid());
             w.next()
                 .write_tagged(Tag::context(0), |w| self.bag.write(w));
             if !self.attributes.is_empty() {
+                //write_set_of sorts the encoded elements as DER requires
                 w.next().write_set_of(|w| {
                     for attr in &self.attributes {
                         attr.write(w.next());
@@ -1389,6 +6648,9 @@ impl SafeBag {
             }
         })
     }
+    pub fn to_der(&self) -> Vec<u8> {
+        yasna::construct_der(|w| self.write(w))
+    }
     pub fn friendly_name(&self) -> Option<String> {
         for attr in self.attributes.iter() {
             if let PKCS12Attribute::FriendlyName(name) = attr {
@@ -1405,21 +6667,225 @@ impl SafeBag {
         }
         None
     }
+    /// Whether the localKeyId is the SHA-1 thumbprint of `cert_der`, false without a localKeyId.
+    pub fn matches_cert(&self, cert_der: &[u8]) -> bool {
+        match self.local_key_id() {
+            Some(id) => id == sha::<Sha1>(cert_der),
+            None => false,
+        }
+    }
+
+    /// A bag without attributes, add them with `with_friendly_name` and `with_local_key_id`.
+    pub fn new(bag: SafeBagKind) -> Self {
+        SafeBag {
+            bag,
+            attributes: vec![],
+            raw_der: None,
+        }
+    }
+    pub fn cert_x509(cert_der: &[u8]) -> Self {
+        Self::new(SafeBagKind::CertBag(CertBag::X509(cert_der.to_owned())))
+    }
+    pub fn shrouded_key(key: EncryptedPrivateKeyInfo) -> Self {
+        Self::new(SafeBagKind::Pkcs8ShroudedKeyBag(key))
+    }
+    /// Replaces an existing friendly name.
+    pub fn with_friendly_name(mut self, name: &str) -> Self {
+        self.attributes
+            .retain(|attr| !matches!(attr, PKCS12Attribute::FriendlyName(_)));
+        self.attributes
+            .push(PKCS12Attribute::FriendlyName(name.to_owned()));
+        self
+    }
+    /// Replaces an existing localKeyId.
+    pub fn with_local_key_id(mut self, id: &[u8]) -> Self {
+        self.attributes
+            .retain(|attr| !matches!(attr, PKCS12Attribute::LocalKeyId(_)));
+        self.attributes
+            .push(PKCS12Attribute::LocalKeyId(id.to_owned()));
+        self
+    }
+}
+
+#[test]
+fn test_safe_bag_builder() {
+    let bag = SafeBag::cert_x509(&[1, 2, 3])
+        .with_friendly_name("old")
+        .with_local_key_id(&[4])
+        .with_friendly_name("look");
+    assert_eq!(bag.bag, SafeBagKind::CertBag(CertBag::X509(vec![1, 2, 3])));
+    assert_eq!(
+        bag.attributes,
+        vec![
+            PKCS12Attribute::LocalKeyId(vec![4]),
+            PKCS12Attribute::FriendlyName("look".to_owned()),
+        ]
+    );
+    assert_eq!(
+        yasna::parse_der(&bag.to_der(), SafeBag::parse).unwrap(),
+        bag
+    );
+
+    let key = EncryptedPrivateKeyInfo {
+        encryption_algorithm: AlgorithmIdentifier::PbeWithSHAAnd3KeyTripleDESCBC(Pkcs12PbeParams {
+            salt: vec![1; 8],
+            iterations: 2048,
+        }),
+        encrypted_data: vec![0; 16],
+    };
+    let bag = SafeBag::shrouded_key(key.clone()).with_local_key_id(&[4]);
+    assert_eq!(bag.bag, SafeBagKind::Pkcs8ShroudedKeyBag(key));
+    assert_eq!(bag.local_key_id(), Some(vec![4]));
+    assert_eq!(bag.friendly_name(), None);
+}
+
+#[test]
+fn test_safe_bag_to_der() {
+    let cert_bag = CertBag::X509(vec![1, 2, 3]);
+    let kind = SafeBagKind::CertBag(cert_bag.clone());
+    assert_eq!(kind.to_der(), cert_bag.to_der());
+    assert_eq!(
+        yasna::parse_der(&cert_bag.to_der(), CertBag::parse).unwrap(),
+        cert_bag
+    );
+
+    let bag = SafeBag::new(kind).with_friendly_name("look");
+    let der = bag.to_der();
+    assert_eq!(der, yasna::construct_der(|w| bag.write(w)));
+    // the bag value sits inside the [0] after the bag id
+    let value = yasna::parse_der(&der, |r| {
+        r.read_sequence(|r| {
+            r.next().read_oid()?;
+            let value = r.next().read_tagged(Tag::context(0), |r| r.read_der())?;
+            r.next().read_der()?;
+            Ok(value)
+        })
+    })
+    .unwrap();
+    assert_eq!(value, bag.bag.to_der());
+}
+
+#[test]
+fn test_safe_bag_matches_cert() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let ca = fs::read("ca.der").unwrap();
+
+    let pfx = PFX::new::<AesCbcDataEncryptor, Pbkdf2>(&cert, &key, Some(&ca), "changeit", "look")
+        .unwrap();
+    for bag in pfx.bags("changeit").unwrap() {
+        assert!(!bag.matches_cert(&ca));
+        match bag.bag {
+            SafeBagKind::CertBag(CertBag::X509(ref der)) if *der == ca => {
+                assert!(!bag.matches_cert(&cert))
+            }
+            _ => assert!(bag.matches_cert(&cert)),
+        }
+    }
+}
+
+#[test]
+fn test_safe_bag_empty_attributes() {
+    let bag_value = yasna::construct_der(|w| w.write_null());
+    let safe_bag_der = |attributes: &[Vec<u8>]| {
+        yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_oid(&as_oid(&[1, 2, 3, 4]));
+                w.next()
+                    .write_tagged(Tag::context(0), |w| w.write_der(&bag_value));
+                w.next().write_set_of(|w| {
+                    for attribute in attributes {
+                        w.next().write_der(attribute);
+                    }
+                });
+            })
+        })
+    };
+
+    let bag = yasna::parse_der(&safe_bag_der(&[]), SafeBag::parse).unwrap();
+    assert!(bag.attributes.is_empty());
+    assert_eq!(bag.friendly_name(), None);
+
+    let other = yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next().write_oid(&as_oid(&[2, 5, 4, 3]));
+            w.next()
+                .write_set_of(|w| w.next().write_utf8_string("other"));
+        })
+    });
+    let bag = yasna::parse_der(&safe_bag_der(&[other]), SafeBag::parse).unwrap();
+    assert_eq!(bag.attributes.len(), 1);
+    assert_eq!(bag.friendly_name(), None);
+    assert_eq!(bag.local_key_id(), None);
+    assert!(!bag.matches_cert(&bag_value));
+}
+
+#[test]
+fn test_safe_bag_attributes_der_order() {
+    let bag = SafeBag {
+        bag: SafeBagKind::OtherBagKind(OtherBag {
+            bag_id: as_oid(&[1, 2, 3, 4]),
+            bag_value: yasna::construct_der(|w| w.write_null()),
+        }),
+        attributes: vec![
+            PKCS12Attribute::Other(OtherAttribute {
+                oid: as_oid(&[2, 5, 4, 3]),
+                data: vec![
+                    yasna::construct_der(|w| w.write_u8(2)),
+                    yasna::construct_der(|w| w.write_u8(1)),
+                ],
+            }),
+            PKCS12Attribute::LocalKeyId(vec![0xff; 4]),
+            PKCS12Attribute::FriendlyName("z".to_owned()),
+        ],
+        raw_der: None,
+    };
+    let der = yasna::construct_der(|w| bag.write(w));
+
+    let attributes = yasna::parse_der(&der, |r| {
+        r.read_sequence(|r| {
+            r.next().read_oid()?;
+            r.next().read_der()?;
+            let mut elements = Vec::new();
+            r.next().read_set_of(|r| {
+                elements.push(r.read_der()?);
+                Ok(())
+            })?;
+            Ok(elements)
+        })
+    })
+    .unwrap();
+    assert_eq!(attributes.len(), 3);
+    assert!(attributes.windows(2).all(|pair| pair[0] <= pair[1]));
+
+    for attribute in &attributes {
+        let values = yasna::parse_der(attribute, |r| {
+            r.read_sequence(|r| {
+                r.next().read_oid()?;
+                let mut values = Vec::new();
+                r.next().read_set_of(|r| {
+                    values.push(r.read_der()?);
+                    Ok(())
+                })?;
+                Ok(values)
+            })
+        })
+        .unwrap();
+        assert!(values.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    let parsed = yasna::parse_der(&der, SafeBag::parse).unwrap();
+    assert_eq!(parsed.friendly_name(), Some("z".to_owned()));
+    assert_eq!(parsed.local_key_id(), Some(vec![0xff; 4]));
 }
 
 #[test]
 fn test_create_p12_pbes2() {
     use std::fs::File;
-    use std::io::{Read, Write};
-    let mut cafile = File::open("ca.der").unwrap();
-    let mut ca = vec![];
-    cafile.read_to_end(&mut ca).unwrap();
-    let mut fcert = File::open("clientcert.der").unwrap();
-    let mut fkey = File::open("clientkey.der").unwrap();
-    let mut cert = vec![];
-    fcert.read_to_end(&mut cert).unwrap();
-    let mut key = vec![];
-    fkey.read_to_end(&mut key).unwrap();
+    use std::io::Write;
+    let ca = fs::read("ca.der").unwrap();
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
     let p12 = PFX::new::<AesCbcDataEncryptor, Pbkdf2>(&cert, &key, Some(&ca), "changeit", "look")
         .unwrap()
         .to_der();
@@ -1434,20 +6900,32 @@ fn test_create_p12_pbes2() {
     assert_eq!(certs[1], ca);
     assert!(pfx.verify_mac("changeit"));
 
+    let auth_safe = pfx.auth_safe_der("changeit").unwrap();
+    let mac_data = pfx.mac_data.as_ref().unwrap();
+    assert!(mac_data.verify_mac(&auth_safe, &bmp_string("changeit")));
+
+    let cert_lens = pfx
+        .with_bags("changeit", |bags| {
+            bags.iter()
+                .filter_map(|bag| match &bag.bag {
+                    SafeBagKind::CertBag(CertBag::X509(x509)) => Some(x509.len()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap();
+    assert_eq!(cert_lens, vec![cert.len(), ca.len()]);
+
     let mut fp12 = File::create("test.p12").unwrap();
     fp12.write_all(&p12).unwrap();
 }
 #[test]
 fn test_create_p12_pbes2_without_password() {
     use std::fs::File;
-    use std::io::{Read, Write};
-    let mut cafile = File::open("ca.der").unwrap();
-    let mut ca = vec![];
-    cafile.read_to_end(&mut ca).unwrap();
-    let mut fcert = File::open("clientcert.der").unwrap();
+    use std::io::Write;
+    let ca = fs::read("ca.der").unwrap();
 
-    let mut cert = vec![];
-    fcert.read_to_end(&mut cert).unwrap();
+    let cert = fs::read("clientcert.der").unwrap();
 
     let p12 = PFX::new::<AesCbcDataEncryptor, Pbkdf2>(&cert, &[], Some(&ca), "", "look")
         .expect("failed to generate")
@@ -1464,19 +6942,332 @@ fn test_create_p12_pbes2_without_password() {
     fp12.write_all(&p12).unwrap();
 }
 
+#[test]
+fn test_safe_bag_raw_der() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let bag = SafeBag::cert_x509(&cert);
+    // the bag with an empty attribute SET, which `write` leaves out
+    let der = yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next().write_oid(&OID_CERT_BAG);
+            w.next().write_tagged(Tag::context(0), |w| bag.bag.write(w));
+            w.next().write_set(|_| {});
+        })
+    });
+    let parsed = yasna::parse_der(&der, SafeBag::parse).unwrap();
+    assert_eq!(parsed.raw_der.as_deref(), Some(&der[..]));
+    assert_eq!(parsed, bag);
+    assert_eq!(parsed.to_der(), der);
+    assert_ne!(bag.to_der(), der);
+
+    let renamed = parsed.with_friendly_name("look");
+    assert_eq!(renamed.to_der(), bag.with_friendly_name("look").to_der());
+
+    // BER is written as DER
+    let header = 2 + (der[1] & 0x7f) as usize;
+    let mut ber = vec![0x30, 0x80];
+    ber.extend_from_slice(&der[header..]);
+    ber.extend_from_slice(&[0, 0]);
+    let parsed = yasna::parse_ber(&ber, SafeBag::parse).unwrap();
+    assert_eq!(parsed.raw_der.as_deref(), Some(&ber[..]));
+    assert_eq!(parsed.to_der(), SafeBag::cert_x509(&cert).to_der());
+}
+
+#[test]
+fn test_der_round_trip() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let p12 = PFX::new::<AesCbcDataEncryptor, Pbkdf2>(&cert, &key, None, "changeit", "look")
+        .unwrap()
+        .to_der();
+
+    let pfx = PFX::parse(&p12).unwrap();
+    assert_eq!(pfx.to_der(), p12);
+    assert_eq!(PFX::parse(&pfx.to_der()).unwrap(), pfx);
+    assert_eq!(pfx.integrity_mode(), IntegrityMode::Password);
+
+    let ContentInfo::Data(auth_safe) = &pfx.auth_safe else {
+        panic!("expected a data auth_safe");
+    };
+    let contents =
+        yasna::parse_der(auth_safe, |r| r.collect_sequence_of(ContentInfo::parse)).unwrap();
+    for content in contents {
+        let der = content.to_der();
+        assert_eq!(ContentInfo::from_der(&der).unwrap().to_der(), der);
+        let data = content.data(b"changeit").unwrap();
+        let bags = yasna::parse_der(&data, |r| r.collect_sequence_of(SafeBag::parse)).unwrap();
+        let reencoded = yasna::construct_der(|w| {
+            w.write_sequence_of(|w| {
+                for bag in &bags {
+                    bag.write(w.next());
+                }
+            })
+        });
+        assert_eq!(reencoded, data);
+        assert_eq!(
+            yasna::parse_ber(&reencoded, |r| r.collect_sequence_of(SafeBag::parse)).unwrap(),
+            bags
+        );
+    }
+
+    let params = Pbkdf2Params {
+        salt: Pbkdf2Salt::Specified(vec![0; 8]),
+        iteration_count: 2048,
+        key_length: None,
+        prf: Box::new(AlgorithmIdentifier::HmacWithSha1(None)),
+    };
+    let der = yasna::construct_der(|w| params.write(w));
+    assert_eq!(yasna::parse_der(&der, Pbkdf2Params::parse).unwrap(), params);
+}
+
+#[test]
+fn test_parse_trailing_data() {
+    let mut p12 = fs::read("openssl_nomaciter.p12").unwrap();
+    assert!(PFX::parse(&p12).is_ok());
+    p12.extend_from_slice(b"junk");
+    assert!(matches!(
+        PFX::parse(&p12),
+        Err(Pkcs12Error::TrailingData(4))
+    ));
+    assert!(matches!(
+        PFX::parse_with_limits(&p12, &Limits::default()),
+        Err(Pkcs12Error::TrailingData(4))
+    ));
+}
+
+#[test]
+fn test_parse_version() {
+    let p12 = fs::read("pfx_version0.p12").unwrap();
+    let pfx = PFX::parse(&p12).unwrap();
+    assert_eq!(pfx.version, 0);
+    assert!(pfx.verify_mac("changeit"));
+    assert_eq!(pfx.to_der(), p12);
+
+    let with_version = |version: Option<u64>| {
+        yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                if let Some(version) = version {
+                    w.next().write_u64(version);
+                }
+                pfx.auth_safe.write(w.next());
+            })
+        })
+    };
+    assert_eq!(PFX::parse(&with_version(None)).unwrap().version, 3);
+    assert_eq!(PFX::parse(&with_version(Some(2))).unwrap().version, 2);
+    let err = PFX::parse(&with_version(Some(300))).unwrap_err();
+    assert!(matches!(err, Pkcs12Error::UnsupportedVersion(300)));
+    assert_eq!(err.to_string(), "unsupported PFX version 300");
+}
+
+#[test]
+fn test_parse_with_limits() {
+    let ca = fs::read("ca.der").unwrap();
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let p12 = PFX::new::<AesCbcDataEncryptor, Pbkdf2>(&cert, &key, Some(&ca), "changeit", "look")
+        .unwrap()
+        .to_der();
+
+    let limits = Limits::default();
+    let pfx = PFX::parse_with_limits(&p12, &limits).unwrap();
+    assert_eq!(pfx.bags_with_limits("changeit", &limits).unwrap().len(), 3);
+
+    let small = Limits {
+        max_total_len: p12.len() - 1,
+        ..limits
+    };
+    assert!(matches!(
+        PFX::parse_with_limits(&p12, &small),
+        Err(Pkcs12Error::LimitExceeded)
+    ));
+    let shallow = Limits {
+        max_depth: 2,
+        ..limits
+    };
+    assert!(matches!(
+        PFX::parse_with_limits(&p12, &shallow),
+        Err(Pkcs12Error::LimitExceeded)
+    ));
+    let few_bags = Limits {
+        max_bags: 2,
+        ..limits
+    };
+    assert!(matches!(
+        pfx.bags_with_limits("changeit", &few_bags),
+        Err(Pkcs12Error::LimitExceeded)
+    ));
+    let small_decrypted = Limits {
+        max_total_len: p12.len(),
+        ..limits
+    };
+    assert!(matches!(
+        pfx.bags_with_limits("changeit", &small_decrypted),
+        Err(Pkcs12Error::LimitExceeded)
+    ));
+    let few_iterations = Limits {
+        max_iterations: 1000,
+        ..limits
+    };
+    assert!(matches!(
+        PFX::parse_with_limits(&p12, &few_iterations),
+        Err(Pkcs12Error::LimitExceeded)
+    ));
+    assert!(matches!(
+        pfx.bags_with_limits("changeit", &few_iterations),
+        Err(Pkcs12Error::LimitExceeded)
+    ));
+
+    // the MAC iterations are checked before anything is derived
+    let p12 = fs::read("mac_iter_600000.p12").unwrap();
+    assert!(PFX::parse_with_limits(&p12, &limits).is_ok());
+    let mac_limits = Limits {
+        max_iterations: 599_999,
+        ..limits
+    };
+    assert!(matches!(
+        PFX::parse_with_limits(&p12, &mac_limits),
+        Err(Pkcs12Error::LimitExceeded)
+    ));
+}
+
+#[test]
+fn test_read_pbes2_des_ede3_cbc() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let p12 = fs::read("pbes2_3des.p12").unwrap();
+
+    let pfx = PFX::parse(&p12).unwrap();
+    assert!(pfx.verify_mac("changeit"));
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key]);
+    assert_eq!(pfx.cert_x509_bags("changeit").unwrap(), vec![cert]);
+    let key = yasna::parse_der(
+        &pfx.encrypted_key_bags().unwrap()[0],
+        EncryptedPrivateKeyInfo::parse,
+    )
+    .unwrap();
+    assert_eq!(
+        key.encryption_algorithm.to_string(),
+        "PBES2 PBKDF2-HMAC-SHA256, DES-EDE3-CBC"
+    );
+    assert_eq!(key.encryption_algorithm.cipher_block_size(), Some(8));
+}
+
+#[test]
+fn test_des_ede3_cbc_padding() {
+    use cbc::Encryptor;
+    use des::TdesEde3;
+    let key = [7u8; 24];
+    let iv = [9u8; 8];
+    for len in [0, 3, 8, 13] {
+        let data = vec![1u8; len];
+        let cipher_text = Encryptor::<TdesEde3>::new_from_slices(&key, &iv)
+            .unwrap()
+            .encrypt_padded_vec_mut::<Pkcs7>(&data);
+        //PKCS#5 pads to 8 bytes, 1 to 8 bytes of padding
+        assert_eq!(cipher_text.len(), (len / 8 + 1) * 8);
+        assert_eq!(des_ede3_cbc_decrypt(&key, &iv, &cipher_text).unwrap(), data);
+    }
+    //16 bytes of 0x10 padding isn't valid for an 8 byte block
+    let padded_16 = [0x10u8; 16];
+    let cipher_text = Encryptor::<TdesEde3>::new_from_slices(&key, &iv)
+        .unwrap()
+        .encrypt_padded_vec_mut::<cipher::block_padding::NoPadding>(&padded_16);
+    assert_eq!(des_ede3_cbc_decrypt(&key, &iv, &cipher_text), None);
+}
+
+#[test]
+fn test_read_pbes2_rc2_cbc() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let p12 = fs::read("pbes2_rc2.p12").unwrap();
+
+    let pfx = PFX::parse(&p12).unwrap();
+    assert!(pfx.verify_mac("changeit"));
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key]);
+    assert_eq!(pfx.cert_x509_bags("changeit").unwrap()[0], cert);
+}
+
+#[test]
+fn test_rc2_effective_key_bits() {
+    let bits = |version| {
+        Rc2CbcParams {
+            version,
+            iv: vec![0; 8],
+        }
+        .effective_key_bits()
+    };
+    assert_eq!(bits(None), Some(32));
+    assert_eq!(bits(Some(160)), Some(40));
+    assert_eq!(bits(Some(52)), Some(56));
+    assert_eq!(bits(Some(120)), Some(64));
+    assert_eq!(bits(Some(58)), Some(128));
+    assert_eq!(bits(Some(256)), Some(256));
+    assert_eq!(bits(Some(1024)), Some(1024));
+    assert_eq!(bits(Some(5000)), None);
+    assert_eq!(bits(Some(7)), None);
+}
+
+#[test]
+fn test_pbes2_rc2_hostile_params() {
+    let alg = |key_length, version| {
+        AlgorithmIdentifier::Pbes2(Pkcs12Pbes2Params {
+            key_derivation_function: Box::new(AlgorithmIdentifier::Pbkdf2(Pbkdf2Params {
+                salt: Pbkdf2Salt::Specified(vec![7; 8]),
+                iteration_count: 1,
+                key_length,
+                prf: Box::new(AlgorithmIdentifier::HmacWithSha1(None)),
+            })),
+            encryption_scheme: Box::new(AlgorithmIdentifier::Rc2Cbc(Rc2CbcParams {
+                version,
+                iv: vec![0; 8],
+            })),
+        })
+    };
+    for (key_length, version) in [
+        (Some(0), Some(58)),
+        (Some(200), Some(58)),
+        (Some(u64::MAX), Some(58)),
+        (Some(16), Some(5000)),
+    ] {
+        assert!(alg(key_length, version)
+            .decrypt_pbe(&[0; 16], b"changeit")
+            .is_err());
+    }
+    assert!(matches!(
+        alg(Some(200), Some(58)).decrypt_pbe(&[0; 16], b"changeit"),
+        Err(Pkcs12Error::InvalidKdfParams)
+    ));
+    assert!(matches!(
+        alg(Some(16), Some(5000)).decrypt_pbe(&[0; 16], b"changeit"),
+        Err(Pkcs12Error::DecryptionFailed { .. })
+    ));
+}
+
+#[test]
+fn test_create_p12_without_mac() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let p12 =
+        PFX::new_without_mac::<AesCbcDataEncryptor, Pbkdf2>(&cert, &key, None, "changeit", "look")
+            .unwrap()
+            .to_der();
+
+    let pfx = PFX::parse(&p12).unwrap();
+    assert!(pfx.mac_data.is_none());
+    assert_eq!(pfx.integrity_mode(), IntegrityMode::None);
+    assert!(pfx.verify_mac("changeit"));
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key]);
+    assert_eq!(pfx.cert_x509_bags("changeit").unwrap(), vec![cert]);
+}
+
 #[test]
 fn test_create_p12_legacy() {
     use std::fs::File;
-    use std::io::{Read, Write};
-    let mut cafile = File::open("ca.der").unwrap();
-    let mut ca = vec![];
-    cafile.read_to_end(&mut ca).unwrap();
-    let mut fcert = File::open("clientcert.der").unwrap();
-    let mut fkey = File::open("clientkey.der").unwrap();
-    let mut cert = vec![];
-    fcert.read_to_end(&mut cert).unwrap();
-    let mut key = vec![];
-    fkey.read_to_end(&mut key).unwrap();
+    use std::io::Write;
+    let ca = fs::read("ca.der").unwrap();
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
     let p12 = PFX::new::<
         PbeWithShaAnd40BitRc2CbcEncryptor,
         PbeWithShaAnd40BitRc2CbcEncryptKeyDeriver,
@@ -1497,17 +7288,63 @@ fn test_create_p12_legacy() {
     let mut fp12 = File::create("test.p12").unwrap();
     fp12.write_all(&p12).unwrap();
 }
+#[test]
+fn test_pfx_builder_legacy_encryption_openssl3() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    //written by OpenSSL 3.5 `openssl pkcs12 -export -legacy`, which picks the
+    //pre 3.0 defaults. not compared against OpenSSL 1.1 output, none was at hand
+    let p12 = fs::read("openssl3_legacy.p12").unwrap();
+
+    let algorithms = |pfx: &PFX| {
+        let data = pfx.auth_safe_der("changeit").unwrap();
+        let contents =
+            yasna::parse_ber(&data, |r| r.collect_sequence_of(ContentInfo::parse)).unwrap();
+        let mut result = vec![];
+        for content in contents {
+            match content {
+                ContentInfo::EncryptedData(encrypted) => result.push(
+                    encrypted
+                        .encrypted_content_info
+                        .content_encryption_algorithm
+                        .to_string(),
+                ),
+                ContentInfo::Data(data) => {
+                    let bags =
+                        yasna::parse_ber(&data, |r| r.collect_sequence_of(SafeBag::parse)).unwrap();
+                    for bag in bags {
+                        if let SafeBagKind::Pkcs8ShroudedKeyBag(key) = bag.bag {
+                            result.push(key.encryption_algorithm.to_string());
+                        }
+                    }
+                }
+                ContentInfo::OtherContext(_) => panic!("unexpected content"),
+            }
+        }
+        result
+    };
+    let openssl = PFX::parse(&p12).unwrap();
+    assert_eq!(algorithms(&openssl), ["PBE-SHA1-RC2-40", "PBE-SHA1-3DES"]);
+    assert_eq!(openssl.key_bags("changeit").unwrap(), vec![key.clone()]);
+
+    let pfx = PfxBuilder::new()
+        .legacy_encryption()
+        .add_identity(&cert, &key, "look")
+        .build("changeit")
+        .unwrap();
+    assert_eq!(algorithms(&pfx), algorithms(&openssl));
+    assert_eq!(pfx.mac_algorithm(), openssl.mac_algorithm());
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key]);
+    assert_eq!(pfx.cert_x509_bags("changeit").unwrap()[0], cert);
+}
+
 #[test]
 fn test_create_p12_legacy_without_password() {
     use std::fs::File;
-    use std::io::{Read, Write};
-    let mut cafile = File::open("ca.der").unwrap();
-    let mut ca = vec![];
-    cafile.read_to_end(&mut ca).unwrap();
-    let mut fcert = File::open("clientcert.der").unwrap();
+    use std::io::Write;
+    let ca = fs::read("ca.der").unwrap();
 
-    let mut cert = vec![];
-    fcert.read_to_end(&mut cert).unwrap();
+    let cert = fs::read("clientcert.der").unwrap();
 
     let p12 = PFX::new::<
         PbeWithShaAnd40BitRc2CbcEncryptor,
@@ -1527,6 +7364,318 @@ fn test_create_p12_legacy_without_password() {
     fp12.write_all(&p12).unwrap();
 }
 
+#[test]
+fn test_read_key_bag() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    // openssl pkcs12 -export -keypbe NONE
+    let p12 = fs::read("keybag.p12").unwrap();
+    let pfx = PFX::parse(&p12).unwrap();
+    assert!(pfx.verify_mac("changeit"));
+
+    let bags = pfx.bags("changeit").unwrap();
+    let key_bag = bags
+        .iter()
+        .find(|bag| bag.bag == SafeBagKind::KeyBag(key.clone()))
+        .unwrap();
+    assert_eq!(key_bag.friendly_name().as_deref(), Some("look"));
+    assert!(key_bag.matches_cert(&cert));
+    assert_eq!(pfx.key_bags("changeit").unwrap(), vec![key]);
+    assert_eq!(pfx.to_der(), p12);
+
+    assert_eq!(
+        pfx.warnings("changeit").unwrap(),
+        vec![SecurityWarning::UnencryptedPrivateKey]
+    );
+    let description = pfx.describe("changeit").unwrap();
+    assert!(description.contains("  unencrypted private key \"look\""));
+    assert!(description.ends_with("warning: unencrypted private key present"));
+}
+
+#[test]
+fn test_semantically_eq() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let ca = fs::read("ca.der").unwrap();
+    let pfx = PfxBuilder::new()
+        .add_identity(&cert, &key, "look")
+        .add_named_ca(&ca, "root")
+        .build("changeit")
+        .unwrap();
+    let same = PfxBuilder::new()
+        .legacy_encryption()
+        .add_named_ca(&ca, "root")
+        .add_identity(&cert, &key, "look")
+        .build("changeit")
+        .unwrap();
+    assert!(pfx.semantically_eq(&same, "changeit"));
+    assert!(same.semantically_eq(&pfx, "changeit"));
+    assert!(!pfx.semantically_eq(
+        &PFX::parse(&fs::read("keybag.p12").unwrap()).unwrap(),
+        "changeit"
+    ));
+    assert!(!pfx.semantically_eq(&same, "wrong"));
+
+    let renamed = PfxBuilder::new()
+        .add_identity(&cert, &key, "look")
+        .add_named_ca(&ca, "other")
+        .build("changeit")
+        .unwrap();
+    assert!(!pfx.semantically_eq(&renamed, "changeit"));
+    let without_ca = PfxBuilder::new()
+        .add_identity(&cert, &key, "look")
+        .build("changeit")
+        .unwrap();
+    assert!(!pfx.semantically_eq(&without_ca, "changeit"));
+    // keybag.p12 holds the same identity under the same name, the key unencrypted
+    assert!(without_ca.semantically_eq(
+        &PFX::parse(&fs::read("keybag.p12").unwrap()).unwrap(),
+        "changeit"
+    ));
+}
+
+#[test]
+fn test_rename_entry() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let ca = fs::read("ca.der").unwrap();
+    let pfx = PfxBuilder::new()
+        .add_identity(&cert, &key, "look")
+        .add_named_ca(&ca, "root")
+        .build("changeit")
+        .unwrap();
+    let names = |pfx: &PFX| -> Vec<Option<String>> {
+        pfx.bags("changeit")
+            .unwrap()
+            .iter()
+            .map(SafeBag::friendly_name)
+            .collect()
+    };
+    assert_eq!(
+        names(&pfx),
+        vec![
+            Some("look".to_owned()),
+            Some("root".to_owned()),
+            Some("look".to_owned())
+        ]
+    );
+
+    let renamed = pfx.rename_entry("changeit", None, "server").unwrap();
+    assert!(renamed.verify_mac("changeit"));
+    assert_eq!(
+        names(&renamed),
+        vec![
+            Some("server".to_owned()),
+            Some("root".to_owned()),
+            Some("server".to_owned())
+        ]
+    );
+    assert_eq!(renamed.key_bags("changeit").unwrap(), vec![key.clone()]);
+
+    //only the certificates are encrypted again, the key content is untouched
+    let renamed = pfx.rename_entry("changeit", Some("root"), "ca").unwrap();
+    assert_eq!(
+        names(&renamed),
+        vec![
+            Some("look".to_owned()),
+            Some("ca".to_owned()),
+            Some("look".to_owned())
+        ]
+    );
+    let contents = |pfx: &PFX| {
+        yasna::parse_der(&pfx.auth_safe_der("changeit").unwrap(), |r| {
+            r.collect_sequence_of(ContentInfo::parse)
+        })
+        .unwrap()
+    };
+    assert_eq!(contents(&renamed)[1], contents(&pfx)[1]);
+    assert_ne!(contents(&renamed)[0], contents(&pfx)[0]);
+
+    assert!(matches!(
+        pfx.rename_entry("changeit", Some("missing"), "ca"),
+        Err(Pkcs12Error::MissingIdentity)
+    ));
+    assert!(matches!(
+        pfx.rename_entry("wrong", None, "ca"),
+        Err(Pkcs12Error::InvalidMac)
+    ));
+    let two_keys = PfxBuilder::new()
+        .add_identity(&cert, &key, "first")
+        .add_identity(&cert, &key, "second")
+        .build("changeit")
+        .unwrap();
+    assert!(matches!(
+        two_keys.rename_entry("changeit", None, "ca"),
+        Err(Pkcs12Error::MissingIdentity)
+    ));
+}
+
+#[test]
+fn test_describe() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let pfx = PfxBuilder::new()
+        .add_identity(&cert, &key, "look")
+        .build("changeit")
+        .unwrap();
+    assert!(pfx.warnings("changeit").unwrap().is_empty());
+    assert_eq!(
+        pfx.describe("changeit").unwrap(),
+        [
+            "MAC: SHA-1, 2048 iterations",
+            "encrypted data: PBES2 PBKDF2-HMAC-SHA256, AES-256-CBC",
+            "data: 1 bags",
+            "  X.509 certificate \"look\"",
+            "  shrouded private key: PBES2 PBKDF2-HMAC-SHA256, AES-256-CBC \"look\"",
+        ]
+        .join("\n")
+    );
+
+    let mut pfx = pfx;
+    pfx.remove_mac();
+    assert_eq!(
+        pfx.warnings("changeit").unwrap(),
+        vec![SecurityWarning::NoMac]
+    );
+}
+
+#[test]
+fn test_other_bags() {
+    let bag_id = ObjectIdentifier::from_slice(&[1, 3, 6, 1, 4, 1, 99999, 1]);
+    let bag_value = yasna::construct_der(|w| w.write_utf8_string("custom"));
+    let safe_bag = SafeBag {
+        bag: SafeBagKind::OtherBagKind(OtherBag {
+            bag_id: bag_id.clone(),
+            bag_value: bag_value.clone(),
+        }),
+        attributes: vec![],
+        raw_der: None,
+    };
+    let contents = yasna::construct_der(|w| {
+        w.write_sequence_of(|w| {
+            ContentInfo::Data(yasna::construct_der(|w| {
+                w.write_sequence_of(|w| safe_bag.write(w.next()))
+            }))
+            .write(w.next());
+        })
+    });
+    let pfx = PFX {
+        version: 3,
+        auth_safe: ContentInfo::Data(contents),
+        mac_data: None,
+    };
+    let pfx = PFX::parse(&pfx.to_der()).unwrap();
+
+    let others = pfx.other_bags("").unwrap();
+    assert_eq!(others.len(), 1);
+    assert_eq!(others[0].bag_id, bag_id);
+    assert_eq!(others[0].bag_value, bag_value);
+    assert!(pfx.cert_bags("").unwrap().is_empty());
+}
+
+#[test]
+fn test_bags_decryption_failed() {
+    let secret_type = as_oid(&[1, 3, 6, 1, 4, 1, 99999, 2]);
+    let value = yasna::construct_der(|w| w.write_bytes(b"secret"));
+    let pfx = PfxBuilder::new()
+        .add_secret(secret_type, &value, "secret")
+        .build("changeit")
+        .unwrap();
+    // a built file has random salts and may unpad under a wrong password by
+    // chance, the fixed bytes of a fixture give the same error every run
+    let fixture = PFX::parse(&fs::read("pbes2_aes128_192.p12").unwrap()).unwrap();
+    assert!(matches!(
+        fixture.bags("wrong"),
+        Err(Pkcs12Error::DecryptionFailed { truncated: false })
+    ));
+
+    let ContentInfo::Data(auth_safe) = &pfx.auth_safe else {
+        panic!("expected a data auth_safe");
+    };
+    let mut contents =
+        yasna::parse_der(auth_safe, |r| r.collect_sequence_of(ContentInfo::parse)).unwrap();
+    let ContentInfo::EncryptedData(encrypted) = &mut contents[0] else {
+        panic!("expected an encrypted content info");
+    };
+    encrypted.encrypted_content_info.encrypted_content.pop();
+    let truncated = PFX {
+        version: 3,
+        auth_safe: ContentInfo::Data(yasna::construct_der(|w| {
+            w.write_sequence_of(|w| {
+                for content in &contents {
+                    content.write(w.next());
+                }
+            })
+        })),
+        mac_data: None,
+    };
+    assert!(matches!(
+        truncated.bags("changeit"),
+        Err(Pkcs12Error::DecryptionFailed { truncated: true })
+    ));
+}
+
+#[test]
+fn test_signed_data_econtent() {
+    let cert = fs::read("clientcert.der").unwrap();
+    let auth_safe =
+        content_infos_der(&[ContentInfo::Data(safe_contents_der(&[SafeBag::cert_x509(
+            &cert,
+        )]))]);
+    let signed_data = yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next().write_u8(1);
+            w.next()
+                .write_set(|w| AlgorithmIdentifier::sha256().write(w.next()));
+            w.next().write_sequence(|w| {
+                w.next().write_oid(&OID_DATA_CONTENT_TYPE);
+                w.next()
+                    .write_tagged(Tag::context(0), |w| w.write_bytes(&auth_safe));
+            });
+            // signerInfos, not looked at
+            w.next().write_set(|_| {});
+        })
+    });
+    let content = ContentInfo::OtherContext(OtherContext {
+        content_type: OID_SIGNED_DATA_CONTENT_TYPE.clone(),
+        content: signed_data,
+    });
+    let content = yasna::parse_der(&content.to_der(), ContentInfo::parse).unwrap();
+    let econtent = content.signed_data_econtent().unwrap();
+    assert_eq!(econtent, auth_safe);
+    let contents =
+        yasna::parse_ber(&econtent, |r| r.collect_sequence_of(ContentInfo::parse)).unwrap();
+    let data = contents[0].data(&[]).unwrap();
+    let bags = yasna::parse_ber(&data, |r| r.collect_sequence_of(SafeBag::parse)).unwrap();
+    assert_eq!(bags[0].bag.get_x509_cert(), Some(cert));
+
+    assert_eq!(ContentInfo::Data(auth_safe).signed_data_econtent(), None);
+    let truncated = ContentInfo::OtherContext(OtherContext {
+        content_type: OID_SIGNED_DATA_CONTENT_TYPE.clone(),
+        content: yasna::construct_der(|w| w.write_sequence(|w| w.next().write_u8(1))),
+    });
+    assert_eq!(truncated.signed_data_econtent(), None);
+}
+
+#[test]
+fn test_bags_unsupported_auth_safe() {
+    let signed_data = OID_SIGNED_DATA_CONTENT_TYPE.clone();
+    let pfx = PFX {
+        version: 3,
+        auth_safe: ContentInfo::OtherContext(OtherContext {
+            content_type: signed_data.clone(),
+            content: yasna::construct_der(|w| w.write_sequence(|w| w.next().write_u8(1))),
+        }),
+        mac_data: None,
+    };
+    let pfx = PFX::parse(&pfx.to_der()).unwrap();
+    assert_eq!(pfx.integrity_mode(), IntegrityMode::PublicKey);
+    match pfx.bags("") {
+        Err(Pkcs12Error::UnsupportedAuthSafe(oid)) => assert_eq!(oid, signed_data),
+        other => panic!("unexpected result {other:?}"),
+    }
+}
+
 #[test]
 fn test_bmp_string() {
     let value = bmp_string("Beavis");
@@ -1545,7 +7694,7 @@ fn test_pbepkcs12sha1() {
     let iterations = 2048;
     let id = 1;
     let size = 24;
-    let result = pbepkcs12sha::<Sha1>(&pass, &salt, iterations, id, size);
+    let result = pbepkcs12sha::<Sha1>(&pass, &salt, iterations, id, size).unwrap();
     let res = hex!("c2294aa6d02930eb5ce9c329eccb9aee1cb136baea746557");
     assert_eq!(result, res);
 }
@@ -1559,7 +7708,436 @@ fn test_pbepkcs12sha1_2() {
     let iterations = 2048;
     let id = 2;
     let size = 8;
-    let result = pbepkcs12sha::<Sha1>(&pass, &salt, iterations, id, size);
+    let result = pbepkcs12sha::<Sha1>(&pass, &salt, iterations, id, size).unwrap();
     let res = hex!("8e9f8fc7664378bc");
     assert_eq!(result, res);
 }
+
+#[test]
+fn test_pbepkcs12sha256() {
+    use hex_literal::hex;
+    // known answers from OpenSSL's PKCS12KDF with digest SHA256
+    let pass = bmp_string("changeit");
+    let salt = hex!("9af4702958a8e95c");
+    let key = pbepkcs12sha::<Sha256>(&pass, &salt, 2048, 1, 32).unwrap();
+    assert_eq!(
+        key,
+        hex!("9a29460cc4f4a87bb536678b1df5a309a56a0fe9ad19a492a871b1e2a52ec6f5")
+    );
+    let iv = pbepkcs12sha::<Sha256>(&pass, &salt, 2048, 2, 16).unwrap();
+    assert_eq!(iv, hex!("e5b23f28ba09934ecc8bec9ad10d10ef"));
+    let mac_key = pbepkcs12sha::<Sha256>(&pass, &salt, 2048, 3, 32).unwrap();
+    assert_eq!(
+        mac_key,
+        hex!("4e46a6703d9f284878b8a174aeb59801e127f2f0f2b9fb643f51adad39aaf568")
+    );
+
+    let empty = pbepkcs12sha::<Sha256>(&bmp_string(""), &salt, 2048, 1, 24).unwrap();
+    assert_eq!(
+        empty,
+        hex!("aba88739a6857d62093c4284f43a79a753b6337079f99dba")
+    );
+}
+
+#[test]
+fn test_pbepkcs12sha256_multi_block() {
+    use hex_literal::hex;
+    //more than one digest output, so I is adjusted between the rounds
+    let pass = bmp_string("changeit");
+    let salt = hex!("0102030405060708");
+    let result = pbepkcs12sha::<Sha256>(&pass, &salt, 1, 1, 100).unwrap();
+    let res = hex!(
+        "cc6056c4c34aa6846b0216d3d8606ae5d5d582ce554c79f533772a1cd491df4d"
+        "de8e7f5eb0565c01d2e2e730f81b27dedf12deb92bed327e95b4e017f86970c2"
+        "f54e19acdb7a9158250070043afda6daec39d2d12ff27e6bce7bd540fbcdc8e8"
+        "c57d839e"
+    );
+    assert_eq!(result, res);
+}
+
+/// C API for parsing and building PFX files.
+///
+/// Functions return `P12_OK` or one of the `P12_ERR_*` codes and hand out
+/// results through pointer arguments. A parsed PFX is an opaque `P12Handle`
+/// released with `p12_free`, byte buffers are released with `p12_bytes_free`.
+/// Passwords are NUL terminated UTF-8 strings. The declarations for C are
+/// in `include/p12.h`.
+#[cfg(feature = "capi")]
+pub mod capi {
+    use super::*;
+    use std::ffi::CStr;
+    use std::os::raw::{c_char, c_int};
+
+    pub const P12_OK: c_int = 0;
+    pub const P12_ERR_NULL_POINTER: c_int = 1;
+    pub const P12_ERR_INVALID_UTF8: c_int = 2;
+    /// No key, or no certificate at the index.
+    pub const P12_ERR_NOT_FOUND: c_int = 3;
+    pub const P12_ERR_ASN1: c_int = 4;
+    pub const P12_ERR_UNSUPPORTED_AUTH_SAFE: c_int = 5;
+    pub const P12_ERR_LIMIT_EXCEEDED: c_int = 6;
+    pub const P12_ERR_TRAILING_DATA: c_int = 7;
+    pub const P12_ERR_UNSUPPORTED_VERSION: c_int = 8;
+    pub const P12_ERR_INVALID_MAC: c_int = 9;
+    pub const P12_ERR_MISSING_IDENTITY: c_int = 10;
+    pub const P12_ERR_DECRYPTION_FAILED: c_int = 11;
+    pub const P12_ERR_INVALID_KDF_PARAMS: c_int = 12;
+    pub const P12_ERR_NOT_IA5_STRING: c_int = 13;
+    pub const P12_ERR_ENCRYPTION_FAILED: c_int = 14;
+    pub const P12_ERR_UNSUPPORTED_ALGORITHM: c_int = 15;
+    pub const P12_ERR_UNSUPPORTED_SALT_SOURCE: c_int = 16;
+    pub const P12_ERR_NESTED_ENCRYPTION: c_int = 17;
+    pub const P12_ERR_UNSUPPORTED_MAC_ALGORITHM: c_int = 18;
+    /// The file has no MacData to verify.
+    pub const P12_ERR_NO_MAC: c_int = 19;
+    /// A bug in this crate, the panic was caught at the C boundary.
+    pub const P12_ERR_PANIC: c_int = 20;
+
+    pub fn error_code(err: &Pkcs12Error) -> c_int {
+        match err {
+            Pkcs12Error::Asn1(_) => P12_ERR_ASN1,
+            Pkcs12Error::UnsupportedAuthSafe(_) => P12_ERR_UNSUPPORTED_AUTH_SAFE,
+            Pkcs12Error::LimitExceeded => P12_ERR_LIMIT_EXCEEDED,
+            Pkcs12Error::TrailingData(_) => P12_ERR_TRAILING_DATA,
+            Pkcs12Error::UnsupportedVersion(_) => P12_ERR_UNSUPPORTED_VERSION,
+            Pkcs12Error::InvalidMac => P12_ERR_INVALID_MAC,
+            Pkcs12Error::MissingIdentity => P12_ERR_MISSING_IDENTITY,
+            Pkcs12Error::DecryptionFailed { .. } => P12_ERR_DECRYPTION_FAILED,
+            Pkcs12Error::InvalidKdfParams => P12_ERR_INVALID_KDF_PARAMS,
+            Pkcs12Error::NotIa5String => P12_ERR_NOT_IA5_STRING,
+            Pkcs12Error::EncryptionFailed => P12_ERR_ENCRYPTION_FAILED,
+            Pkcs12Error::UnsupportedAlgorithm(_) => P12_ERR_UNSUPPORTED_ALGORITHM,
+            Pkcs12Error::UnsupportedSaltSource(_) => P12_ERR_UNSUPPORTED_SALT_SOURCE,
+            Pkcs12Error::NestedEncryption => P12_ERR_NESTED_ENCRYPTION,
+            Pkcs12Error::UnsupportedMacAlgorithm(_) => P12_ERR_UNSUPPORTED_MAC_ALGORITHM,
+        }
+    }
+
+    /// Opaque to C.
+    pub struct P12Handle {
+        pfx: PFX,
+    }
+
+    // unwinding into C is undefined behavior, every extern fn runs in here
+    pub(crate) fn catch_panic(f: impl FnOnce() -> c_int) -> c_int {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or(P12_ERR_PANIC)
+    }
+
+    unsafe fn password<'a>(password: *const c_char) -> Result<&'a str, c_int> {
+        if password.is_null() {
+            return Err(P12_ERR_NULL_POINTER);
+        }
+        CStr::from_ptr(password)
+            .to_str()
+            .map_err(|_| P12_ERR_INVALID_UTF8)
+    }
+
+    unsafe fn bytes<'a>(data: *const u8, len: usize) -> Result<&'a [u8], c_int> {
+        if data.is_null() {
+            return Err(P12_ERR_NULL_POINTER);
+        }
+        Ok(std::slice::from_raw_parts(data, len))
+    }
+
+    unsafe fn hand_out(data: Vec<u8>, out: *mut *mut u8, out_len: *mut usize) -> c_int {
+        let data = data.into_boxed_slice();
+        *out_len = data.len();
+        *out = Box::into_raw(data) as *mut u8;
+        P12_OK
+    }
+
+    /// Parses `len` bytes of DER at `der` into a handle written to `out`.
+    ///
+    /// # Safety
+    ///
+    /// `der` must point to `len` readable bytes and `out` must be writable.
+    #[no_mangle]
+    pub unsafe extern "C" fn p12_parse(
+        der: *const u8,
+        len: usize,
+        out: *mut *mut P12Handle,
+    ) -> c_int {
+        catch_panic(|| {
+            if out.is_null() {
+                return P12_ERR_NULL_POINTER;
+            }
+            let der = match bytes(der, len) {
+                Ok(der) => der,
+                Err(code) => return code,
+            };
+            match PFX::parse(der) {
+                Ok(pfx) => {
+                    *out = Box::into_raw(Box::new(P12Handle { pfx }));
+                    P12_OK
+                }
+                Err(err) => error_code(&err),
+            }
+        })
+    }
+
+    /// `P12_OK` if the MAC verifies, `P12_ERR_INVALID_MAC` if it doesn't and
+    /// `P12_ERR_NO_MAC` if the file has none.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must come from `p12_parse` and `password` must be a NUL terminated string.
+    #[no_mangle]
+    pub unsafe extern "C" fn p12_verify_mac(
+        handle: *const P12Handle,
+        password: *const c_char,
+    ) -> c_int {
+        catch_panic(|| {
+            let password = match self::password(password) {
+                Ok(password) => password,
+                Err(code) => return code,
+            };
+            let Some(handle) = handle.as_ref() else {
+                return P12_ERR_NULL_POINTER;
+            };
+            match handle.pfx.mac_status(password) {
+                MacStatus::Verified => P12_OK,
+                MacStatus::NoMac => P12_ERR_NO_MAC,
+                MacStatus::Failed => P12_ERR_INVALID_MAC,
+            }
+        })
+    }
+
+    /// Decrypts the first private key, a PKCS#8 PrivateKeyInfo, into a buffer
+    /// of `out_len` bytes at `out`.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must come from `p12_parse`, `password` must be a NUL
+    /// terminated string and `out` and `out_len` must be writable.
+    #[no_mangle]
+    pub unsafe extern "C" fn p12_get_key(
+        handle: *const P12Handle,
+        password: *const c_char,
+        out: *mut *mut u8,
+        out_len: *mut usize,
+    ) -> c_int {
+        catch_panic(|| {
+            let password = match self::password(password) {
+                Ok(password) => password,
+                Err(code) => return code,
+            };
+            let handle = match handle.as_ref() {
+                Some(handle) if !out.is_null() && !out_len.is_null() => handle,
+                _ => return P12_ERR_NULL_POINTER,
+            };
+            match handle.pfx.key_bags(password) {
+                Ok(keys) => match keys.into_iter().next() {
+                    Some(key) => hand_out(key, out, out_len),
+                    None => P12_ERR_NOT_FOUND,
+                },
+                Err(err) => error_code(&err),
+            }
+        })
+    }
+
+    /// Decrypts the X.509 certificate at `index` into a buffer of `out_len`
+    /// bytes at `out`, `P12_ERR_NOT_FOUND` past the last one.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must come from `p12_parse`, `password` must be a NUL
+    /// terminated string and `out` and `out_len` must be writable.
+    #[no_mangle]
+    pub unsafe extern "C" fn p12_get_certs(
+        handle: *const P12Handle,
+        password: *const c_char,
+        index: usize,
+        out: *mut *mut u8,
+        out_len: *mut usize,
+    ) -> c_int {
+        catch_panic(|| {
+            let password = match self::password(password) {
+                Ok(password) => password,
+                Err(code) => return code,
+            };
+            let handle = match handle.as_ref() {
+                Some(handle) if !out.is_null() && !out_len.is_null() => handle,
+                _ => return P12_ERR_NULL_POINTER,
+            };
+            match handle.pfx.cert_x509_bags(password) {
+                Ok(certs) => match certs.into_iter().nth(index) {
+                    Some(cert) => hand_out(cert, out, out_len),
+                    None => P12_ERR_NOT_FOUND,
+                },
+                Err(err) => error_code(&err),
+            }
+        })
+    }
+
+    /// Builds the DER of a PFX holding a certificate, its PKCS#8 key and an
+    /// optional CA certificate (`ca` may be NULL), encrypted with AES-256-CBC
+    /// and PBKDF2.
+    ///
+    /// # Safety
+    ///
+    /// The byte pointers must point to as many readable bytes as their lengths
+    /// say, `password` and `name` must be NUL terminated strings and `out`
+    /// and `out_len` must be writable.
+    #[no_mangle]
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe extern "C" fn p12_build(
+        cert: *const u8,
+        cert_len: usize,
+        key: *const u8,
+        key_len: usize,
+        ca: *const u8,
+        ca_len: usize,
+        password: *const c_char,
+        name: *const c_char,
+        out: *mut *mut u8,
+        out_len: *mut usize,
+    ) -> c_int {
+        catch_panic(|| {
+            if out.is_null() || out_len.is_null() {
+                return P12_ERR_NULL_POINTER;
+            }
+            let args = (|| {
+                let ca = if ca.is_null() {
+                    None
+                } else {
+                    Some(bytes(ca, ca_len)?)
+                };
+                Ok((
+                    bytes(cert, cert_len)?,
+                    bytes(key, key_len)?,
+                    ca,
+                    self::password(password)?,
+                    self::password(name)?,
+                ))
+            })();
+            let (cert, key, ca, password, name) = match args {
+                Ok(args) => args,
+                Err(code) => return code,
+            };
+            match PFX::new::<AesCbcDataEncryptor, Pbkdf2>(cert, key, ca, password, name) {
+                Some(pfx) => hand_out(pfx.to_der(), out, out_len),
+                None => P12_ERR_ENCRYPTION_FAILED,
+            }
+        })
+    }
+
+    /// Releases a handle from `p12_parse`, NULL is ignored.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must come from `p12_parse` and not be used afterwards.
+    #[no_mangle]
+    pub unsafe extern "C" fn p12_free(handle: *mut P12Handle) {
+        catch_panic(|| {
+            if !handle.is_null() {
+                drop(Box::from_raw(handle));
+            }
+            P12_OK
+        });
+    }
+
+    /// Releases a buffer handed out by this API, NULL is ignored.
+    ///
+    /// # Safety
+    ///
+    /// `data` and `len` must be a buffer and its length as handed out, and
+    /// the buffer must not be used afterwards.
+    #[no_mangle]
+    pub unsafe extern "C" fn p12_bytes_free(data: *mut u8, len: usize) {
+        catch_panic(|| {
+            if !data.is_null() {
+                drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(data, len)));
+            }
+            P12_OK
+        });
+    }
+}
+
+#[cfg(feature = "capi")]
+#[test]
+fn test_capi_round_trip() {
+    use capi::*;
+
+    use std::ptr;
+    let cert = fs::read("clientcert.der").unwrap();
+    let key = fs::read("clientkey.der").unwrap();
+    let ca = fs::read("ca.der").unwrap();
+    let password = b"changeit\0".as_ptr() as *const std::os::raw::c_char;
+    let wrong = b"wrong\0".as_ptr() as *const std::os::raw::c_char;
+    let name = b"look\0".as_ptr() as *const std::os::raw::c_char;
+
+    unsafe {
+        let (mut der, mut der_len) = (ptr::null_mut(), 0);
+        let code = p12_build(
+            cert.as_ptr(),
+            cert.len(),
+            key.as_ptr(),
+            key.len(),
+            ca.as_ptr(),
+            ca.len(),
+            password,
+            name,
+            &mut der,
+            &mut der_len,
+        );
+        assert_eq!(code, P12_OK);
+
+        let mut handle = ptr::null_mut();
+        assert_eq!(p12_parse(der, der_len, &mut handle), P12_OK);
+        p12_bytes_free(der, der_len);
+        assert_eq!(p12_verify_mac(handle, password), P12_OK);
+        assert_eq!(p12_verify_mac(handle, wrong), P12_ERR_INVALID_MAC);
+
+        let (mut out, mut out_len) = (ptr::null_mut(), 0);
+        assert_eq!(
+            p12_get_key(handle, password, &mut out, &mut out_len),
+            P12_OK
+        );
+        assert_eq!(std::slice::from_raw_parts(out, out_len), &key[..]);
+        p12_bytes_free(out, out_len);
+
+        for (index, expected) in [&cert, &ca].iter().enumerate() {
+            let code = p12_get_certs(handle, password, index, &mut out, &mut out_len);
+            assert_eq!(code, P12_OK);
+            assert_eq!(std::slice::from_raw_parts(out, out_len), &expected[..]);
+            p12_bytes_free(out, out_len);
+        }
+        let code = p12_get_certs(handle, password, 2, &mut out, &mut out_len);
+        assert_eq!(code, P12_ERR_NOT_FOUND);
+        p12_free(handle);
+
+        let mut handle = ptr::null_mut();
+        assert_eq!(p12_parse(cert.as_ptr(), 4, &mut handle), P12_ERR_ASN1);
+        assert!(handle.is_null());
+        assert_eq!(p12_parse(ptr::null(), 0, &mut handle), P12_ERR_NULL_POINTER);
+    }
+}
+
+#[cfg(feature = "capi")]
+#[test]
+fn test_capi_catch_panic() {
+    use capi::*;
+    assert_eq!(catch_panic(|| P12_ERR_NOT_FOUND), P12_ERR_NOT_FOUND);
+    assert_eq!(catch_panic(|| panic!("bug")), P12_ERR_PANIC);
+}
+
+#[cfg(feature = "capi")]
+#[test]
+fn test_capi_verify_mac_without_mac() {
+    use capi::*;
+    use std::ptr;
+    let mut pfx = PfxBuilder::new()
+        .add_ca(&fs::read("ca.der").unwrap())
+        .build("changeit")
+        .unwrap();
+    pfx.remove_mac();
+    let der = pfx.to_der();
+    let password = b"changeit\0".as_ptr() as *const std::os::raw::c_char;
+
+    unsafe {
+        let mut handle = ptr::null_mut();
+        assert_eq!(p12_parse(der.as_ptr(), der.len(), &mut handle), P12_OK);
+        assert_eq!(p12_verify_mac(handle, password), P12_ERR_NO_MAC);
+        p12_free(handle);
+    }
+}